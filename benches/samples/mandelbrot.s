@@ -0,0 +1,63 @@
+#############################################################
+##                                                         ##
+##  Counts the iterations of the Mandelbrot set in a       ##
+##  160x120 grid, with at most 64 iterations per point.    ##
+##  The total ends up in s0                                ##
+##                                                         ##
+#############################################################
+
+.data
+X0: .float -2.0
+Y0: .float -1.2
+STEP: .float 0.02
+FOUR: .float 4.0
+
+.text
+    li s0 0        # total iterations
+    li s1 120      # rows
+    li s2 160      # columns
+    li s3 64       # max iterations
+    la t0 X0
+    flw fs0 0(t0)
+    flw fs1 4(t0)  # Y0
+    flw fs2 8(t0)  # STEP
+    flw fs3 12(t0) # FOUR
+
+    li t0 0        # y
+row:
+    fcvt.s.w ft0 t0
+    fmul.s ft0 ft0 fs2
+    fadd.s ft0 ft0 fs1 # ci
+
+    li t1 0        # x
+column:
+    fcvt.s.w ft1 t1
+    fmul.s ft1 ft1 fs2
+    fadd.s ft1 ft1 fs0 # cr
+
+    fmv.s.x ft2 zero # zr
+    fmv.s.x ft3 zero # zi
+    li t2 0          # iteration
+iterate:
+    fmul.s ft4 ft2 ft2 # zr²
+    fmul.s ft5 ft3 ft3 # zi²
+    fadd.s ft6 ft4 ft5
+    flt.s t3 fs3 ft6
+    bnez t3 escaped
+
+    fmul.s ft6 ft2 ft3
+    fadd.s ft6 ft6 ft6
+    fadd.s ft3 ft6 ft0 # zi = 2 zr zi + ci
+    fsub.s ft6 ft4 ft5
+    fadd.s ft2 ft6 ft1 # zr = zr² - zi² + cr
+
+    addi t2 t2 1
+    blt t2 s3 iterate
+escaped:
+    add s0 s0 t2
+
+    addi t1 t1 1
+    blt t1 s2 column
+
+    addi t0 t0 1
+    blt t0 s1 row
@@ -1,21 +1,94 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use fpgrars::simulator::{self, Simulator};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use fpgrars::{
+    parser,
+    simulator::{self, Simulator},
+};
 
-const TESTCASES: &[&str] = &["add.s", "memory.s", "memory2.s", "sort.s", "video.s"];
+const TESTCASES: &[&str] = &[
+    "add.s",
+    "memory.s",
+    "memory2.s",
+    "sort.s",
+    "video.s",
+    "mandelbrot.s",
+];
 
 fn criterion_benchmark(c: &mut Criterion) {
     for testcase in TESTCASES {
-        c.bench_function(testcase, |b| {
+        // `run` consumes the code, so every iteration needs a freshly loaded simulator
+        let load = || {
             let memory = simulator::memory::Memory::new();
             let mut simulator = Simulator::default().with_memory(memory);
             simulator
                 .load_file(&format!("./benches/samples/{testcase}"))
                 .unwrap_or_else(|e| panic!("Couldn't parse {testcase}: {e}"));
+            simulator
+        };
 
-            b.iter(|| simulator.run())
+        c.bench_function(testcase, |b| {
+            b.iter_batched_ref(
+                load,
+                |simulator| simulator.run().unwrap(),
+                BatchSize::LargeInput,
+            )
         });
     }
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// Loads a full 320x240 24-bit image into the video memory, like the read file ecall would
+fn set_reader_benchmark(c: &mut Criterion) {
+    let image: Vec<u8> = (0..320 * 240 * 4).map(|i| (i % 199) as u8).collect();
+    let mut memory = simulator::memory::Memory::new();
+
+    c.bench_function("set_reader 320x240", |b| {
+        b.iter(|| memory.set_reader(&mut &image[..], simulator::memory::VIDEO_START, image.len()))
+    });
+}
+
+/// Clears, copies and prints a whole frame of video memory, which takes the MMIO lock only once
+fn full_frame_benchmark(c: &mut Criterion) {
+    use simulator::memory::{FRAME_SIZE, VIDEO_START};
+
+    let mut memory = simulator::memory::Memory::new();
+    let frame_1 = VIDEO_START + FRAME_SIZE;
+
+    c.bench_function("clear frame", |b| {
+        b.iter(|| memory.fill_pattern(VIDEO_START, &0x00123456u32.to_le_bytes(), FRAME_SIZE))
+    });
+
+    c.bench_function("memcpy frame", |b| {
+        b.iter(|| memory.copy(frame_1, VIDEO_START, FRAME_SIZE))
+    });
+
+    memory.fill(frame_1, b'a', FRAME_SIZE - 1);
+    memory.fill(frame_1 + FRAME_SIZE - 1, 0, 1);
+    c.bench_function("print string frame", |b| {
+        b.iter(|| memory.read_c_string(frame_1))
+    });
+}
+
+/// Parses a large generated program, with a bit of every kind of operand
+fn parse_benchmark(c: &mut Criterion) {
+    let code: String = (0..10_000)
+        .map(|i| {
+            format!(
+                "label{i}:\n    addi t0, t1, {}\n    lw a0, 8(sp)\n    fadd.s ft0, ft1, ft2\n    \
+                 bne t0, zero, label{i}\n",
+                i % 2048
+            )
+        })
+        .collect();
+
+    c.bench_function("parse 40k instructions", |b| {
+        b.iter(|| parser::parse_str(code.clone(), 0x100).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    set_reader_benchmark,
+    full_frame_benchmark,
+    parse_benchmark
+);
 criterion_main!(benches);
@@ -1,9 +1,12 @@
+use crate::renderer::FrameLayout;
+use crate::simulator::memory::{MMIO_SIZE, MMIO_START, TRANSPARENT_BYTE};
 use clap::Parser;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 #[derive(Parser, Deserialize, Debug, Default)]
 #[command(author, version, about)]
 #[clap(disable_help_flag = true)]
+#[serde(default)]
 pub struct OptionalConfig {
     #[clap(long, action = clap::ArgAction::HelpLong)]
     help: Option<bool>,
@@ -28,6 +31,37 @@ pub struct OptionalConfig {
     #[arg(short, long)]
     pub port: Option<usize>,
 
+    /// Uses the first MIDI device whose name contains this text, instead of --port
+    #[arg(long)]
+    pub midi_device: Option<String>,
+
+    /// Number of layers of the bitmap display. With 2, the overlay frame at 0xFF220000 is drawn on
+    /// top of the selected frame. Defaults to 1
+    #[arg(long)]
+    pub layers: Option<usize>,
+
+    /// Address of frame 0 of the bitmap display. Frame 1 starts 0x100000 bytes after it. Defaults
+    /// to 0xFF000000
+    #[arg(long, value_name = "ADDR")]
+    #[serde(deserialize_with = "number_or_string")]
+    pub frame_base: Option<String>,
+
+    /// Bytes from the start of a row of the bitmap display to the start of the next one. Defaults
+    /// to the width times the bytes per pixel
+    #[arg(long, value_name = "BYTES")]
+    pub stride: Option<usize>,
+
+    /// Bytes from the start of a pixel of the bitmap display to the start of the next one.
+    /// Defaults to 4, or 1 with the `unb` feature
+    #[arg(long, value_name = "BYTES")]
+    pub bytes_per_pixel: Option<usize>,
+
+    /// Bytes with this value are not drawn to the bitmap display. Defaults to 0xC7. Use `none` to
+    /// disable transparency
+    #[arg(long)]
+    #[serde(deserialize_with = "number_or_string")]
+    pub transparent: Option<String>,
+
     /// Prints the instructions in the FPGRARS format
     #[arg(long)]
     pub print_instructions: bool,
@@ -36,6 +70,90 @@ pub struct OptionalConfig {
     #[arg(long)]
     pub print_state: bool,
 
+    /// Writes the output of the print ecalls to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    pub stdout: Option<String>,
+
+    /// Writes a line to this file for each instruction executed, with the registers it changed
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<String>,
+
+    /// Writes the final state of the registers to a JSON file after execution
+    #[arg(long)]
+    pub dump_state: Option<String>,
+
+    /// Saves the last frame of the bitmap display to a PNG file after execution
+    #[arg(long)]
+    pub screenshot: Option<String>,
+
+    /// Feeds the keyboard events in this file to the program. Useful with --no-video
+    #[arg(long)]
+    pub input_script: Option<String>,
+
+    /// A TOML file mapping the scancodes of your keyboard to the ones your program expects
+    #[arg(long)]
+    pub keymap: Option<String>,
+
+    /// The initial value of the stack pointer (sp). Defaults to the end of the data segment
+    #[arg(long)]
+    #[serde(deserialize_with = "number_or_string")]
+    pub sp: Option<String>,
+
+    /// The initial value of the global pointer (gp). Defaults to 0x10008000
+    #[arg(long)]
+    #[serde(deserialize_with = "number_or_string")]
+    pub gp: Option<String>,
+
+    /// Allows `sbrk` to be called with negative increments, shrinking the heap
+    #[arg(long)]
+    pub allow_sbrk_shrink: bool,
+
+    /// Warns the first time an instruction writes to the `zero` register
+    #[arg(long)]
+    pub warn_zero_write: bool,
+
+    /// Dividing by zero jumps to the trap handler (ucause = 24) or stops with an error, instead of
+    /// returning -1 like the RISC-V spec says
+    #[arg(long)]
+    pub trap_div_zero: bool,
+
+    /// Signed overflow in `add` and `sub` jumps to the trap handler (ucause = 25) or stops with an
+    /// error, instead of wrapping around
+    #[arg(long)]
+    pub trap_overflow: bool,
+
+    /// Accepts immediates that don't fit in their instruction, like `addi t0, t0, 5000`
+    #[arg(long)]
+    pub no_imm_range_check: bool,
+
+    /// Warns about branches and jumps that are too far for the real instructions, which reach
+    /// ±4KiB and ±1MiB
+    #[arg(long)]
+    pub hw_range_check: bool,
+
+    /// Runs at most N instructions per second, to match slower hardware. Unlimited by default
+    #[arg(long, value_name = "N")]
+    pub max_ips: Option<u64>,
+
+    /// Warns when N instructions run without any ecall or memory write, which usually means the
+    /// program is stuck in a loop
+    #[arg(long, value_name = "N")]
+    pub watchdog: Option<u64>,
+
+    /// Stops the program when the --watchdog fires, instead of only warning
+    #[arg(long)]
+    pub watchdog_exit: bool,
+
+    /// Warns when a load reads a byte of the data or heap memory that the program never wrote.
+    /// Makes the simulation slower
+    #[arg(long)]
+    pub sanitize_uninit: bool,
+
+    /// Loads the contents of FILE into memory at ADDR before running. Can be used multiple times
+    #[arg(long, num_args = 2, value_names = ["ADDR", "FILE"])]
+    #[serde(default)]
+    pub load_image: Vec<String>,
+
     /// The RISC-V file to execute
     pub file: Option<String>,
 }
@@ -60,35 +178,178 @@ impl OptionalConfig {
             height: self.height.or(rhs.height),
             scale: self.scale.or(rhs.scale),
             port: self.port.or(rhs.port),
+            midi_device: self.midi_device.or(rhs.midi_device),
+            layers: self.layers.or(rhs.layers),
+            frame_base: self.frame_base.or(rhs.frame_base),
+            stride: self.stride.or(rhs.stride),
+            bytes_per_pixel: self.bytes_per_pixel.or(rhs.bytes_per_pixel),
+            transparent: self.transparent.or(rhs.transparent),
             print_instructions: self.print_instructions || rhs.print_instructions,
             print_state: self.print_state || rhs.print_state,
+            stdout: self.stdout.or(rhs.stdout),
+            trace: self.trace.or(rhs.trace),
+            dump_state: self.dump_state.or(rhs.dump_state),
+            screenshot: self.screenshot.or(rhs.screenshot),
+            input_script: self.input_script.or(rhs.input_script),
+            keymap: self.keymap.or(rhs.keymap),
+            sp: self.sp.or(rhs.sp),
+            gp: self.gp.or(rhs.gp),
+            allow_sbrk_shrink: self.allow_sbrk_shrink || rhs.allow_sbrk_shrink,
+            warn_zero_write: self.warn_zero_write || rhs.warn_zero_write,
+            trap_div_zero: self.trap_div_zero || rhs.trap_div_zero,
+            trap_overflow: self.trap_overflow || rhs.trap_overflow,
+            no_imm_range_check: self.no_imm_range_check || rhs.no_imm_range_check,
+            hw_range_check: self.hw_range_check || rhs.hw_range_check,
+            max_ips: self.max_ips.or(rhs.max_ips),
+            watchdog: self.watchdog.or(rhs.watchdog),
+            watchdog_exit: self.watchdog_exit || rhs.watchdog_exit,
+            sanitize_uninit: self.sanitize_uninit || rhs.sanitize_uninit,
+            load_image: [self.load_image, rhs.load_image].concat(),
             file: self.file.or(rhs.file),
         }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Config {
     pub no_video: bool,
     pub width: usize,
     pub height: usize,
     pub scale: usize,
     pub port: Option<usize>,
+    pub midi_device: Option<String>,
+    pub layers: usize,
+    /// Where the pixels of the bitmap display are in the MMIO
+    pub frame_layout: FrameLayout,
+    pub transparent: Option<u8>,
     pub print_instructions: bool,
     pub print_state: bool,
+    pub stdout: Option<String>,
+    pub trace: Option<String>,
+    pub dump_state: Option<String>,
+    pub screenshot: Option<String>,
+    pub input_script: Option<String>,
+    pub keymap: Option<String>,
+    pub sp: Option<u32>,
+    pub gp: Option<u32>,
+    pub allow_sbrk_shrink: bool,
+    pub warn_zero_write: bool,
+    pub trap_div_zero: bool,
+    pub trap_overflow: bool,
+    pub no_imm_range_check: bool,
+    pub hw_range_check: bool,
+    /// `None` means unlimited
+    pub max_ips: Option<u64>,
+    pub watchdog: Option<u64>,
+    pub watchdog_exit: bool,
+    pub sanitize_uninit: bool,
+    /// (address, file) pairs to be loaded into memory before running
+    pub load_image: Vec<(u32, String)>,
     pub file: String,
 }
 
+/// Builds the layout of the bitmap display from `--frame-base`, `--stride` and
+/// `--bytes-per-pixel`, exiting if a custom layout wouldn't fit in the MMIO
+fn frame_layout(config: &OptionalConfig) -> FrameLayout {
+    let width = config.width.unwrap_or(320);
+    let height = config.height.unwrap_or(240);
+    let default = FrameLayout::new(width);
+    if config.frame_base.is_none() && config.stride.is_none() && config.bytes_per_pixel.is_none() {
+        return default;
+    }
+
+    let base = match &config.frame_base {
+        Some(base) => {
+            let address = parse_address("--frame-base", base) as usize;
+            address.checked_sub(MMIO_START).unwrap_or_else(|| {
+                eprintln!(
+                    "Invalid address for --frame-base: {}. It should be in the MMIO, at {:#x} or after",
+                    base, MMIO_START
+                );
+                std::process::exit(1);
+            })
+        }
+        None => default.base,
+    };
+    let bytes_per_pixel = config.bytes_per_pixel.unwrap_or(default.bytes_per_pixel);
+    let layout = FrameLayout {
+        base,
+        stride: config.stride.unwrap_or(width * bytes_per_pixel),
+        bytes_per_pixel,
+    };
+
+    if !layout.fits(width, height, config.layers.unwrap_or(1), MMIO_SIZE) {
+        eprintln!(
+            "Invalid display layout: a {}x{} display at {:#x}, with a stride of {} bytes and {} bytes per pixel, doesn't fit in the MMIO, runs into the registers at 0xff200000 or has overlapping pixels",
+            width,
+            height,
+            MMIO_START + layout.base,
+            layout.stride,
+            layout.bytes_per_pixel
+        );
+        std::process::exit(1);
+    }
+    layout
+}
+
 impl From<OptionalConfig> for Config {
     fn from(config: OptionalConfig) -> Self {
+        let frame_layout = frame_layout(&config);
         Self {
             no_video: config.no_video,
             width: config.width.unwrap_or(320),
             height: config.height.unwrap_or(240),
             scale: config.scale.unwrap_or(2),
             port: config.port,
+            midi_device: config.midi_device,
+            layers: match config.layers.unwrap_or(1) {
+                layers @ 1..=2 => layers,
+                layers => {
+                    eprintln!("Invalid number of layers: {}. It should be 1 or 2", layers);
+                    std::process::exit(1);
+                }
+            },
+            frame_layout,
+            transparent: match config.transparent.as_deref() {
+                None => Some(TRANSPARENT_BYTE),
+                Some("none") => None,
+                Some(byte) => Some(
+                    parse_number(byte)
+                        .and_then(|x| u8::try_from(x).ok())
+                        .unwrap_or_else(|| {
+                            eprintln!("Invalid transparent byte: {}", byte);
+                            std::process::exit(1);
+                        }),
+                ),
+            },
             print_instructions: config.print_instructions,
             print_state: config.print_state,
+            stdout: config.stdout,
+            trace: config.trace,
+            dump_state: config.dump_state,
+            screenshot: config.screenshot,
+            input_script: config.input_script,
+            keymap: config.keymap,
+            sp: config.sp.map(|sp| parse_address("--sp", &sp)),
+            gp: config.gp.map(|gp| parse_address("--gp", &gp)),
+            allow_sbrk_shrink: config.allow_sbrk_shrink,
+            warn_zero_write: config.warn_zero_write,
+            trap_div_zero: config.trap_div_zero,
+            trap_overflow: config.trap_overflow,
+            no_imm_range_check: config.no_imm_range_check,
+            hw_range_check: config.hw_range_check,
+            max_ips: config.max_ips.filter(|&ips| ips > 0),
+            watchdog: config.watchdog.filter(|&n| n > 0),
+            watchdog_exit: config.watchdog_exit,
+            sanitize_uninit: config.sanitize_uninit,
+            load_image: config
+                .load_image
+                .chunks(2)
+                .map(|pair| {
+                    let addr = parse_address("--load-image", &pair[0]);
+                    (addr, pair[1].clone())
+                })
+                .collect(),
             file: config.file.unwrap_or_else(|| {
                 eprintln!("No file specified");
                 std::process::exit(1);
@@ -97,6 +358,44 @@ impl From<OptionalConfig> for Config {
     }
 }
 
+/// Parses a decimal or 0x-prefixed hexadecimal number
+pub(crate) fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Deserializes an option that can be written in the TOML file either as a string or as an
+/// integer, such as `transparent = 0xC7` or `transparent = "none"`
+fn number_or_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(String),
+    }
+
+    Ok(
+        Option::<NumberOrString>::deserialize(deserializer)?.map(|x| match x {
+            NumberOrString::Number(n) => n.to_string(),
+            NumberOrString::String(s) => s,
+        }),
+    )
+}
+
+/// Parses the address given to `option`, exiting if it's invalid
+fn parse_address(option: &str, s: &str) -> u32 {
+    parse_number(s)
+        .and_then(|x| u32::try_from(x).ok())
+        .unwrap_or_else(|| {
+            eprintln!("Invalid address for {}: {}", option, s);
+            std::process::exit(1);
+        })
+}
+
 impl Config {
     pub fn get() -> Self {
         OptionalConfig::get_toml()
@@ -104,3 +403,38 @@ impl Config {
             .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml_numbers() {
+        let config: OptionalConfig = toml::from_str(
+            r#"
+            transparent = 0xC7
+            frame_base = "0xff000000"
+            file = "main.s"
+            sp = 4096
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.transparent.as_deref(), Some("199"));
+        assert_eq!(config.frame_base.as_deref(), Some("0xff000000"));
+        assert_eq!(config.sp.as_deref(), Some("4096"));
+        assert_eq!(config.gp, None);
+
+        let config: Config = config.into();
+        assert_eq!(config.transparent, Some(0xC7));
+        assert_eq!(config.sp, Some(4096));
+
+        let config: OptionalConfig = toml::from_str(
+            r#"
+            transparent = "none"
+            file = "main.s"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Config::from(config).transparent, None);
+    }
+}
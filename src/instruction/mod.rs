@@ -1,3 +1,45 @@
+/// Rounding mode of a floating point instruction, like the `rtz` in `fcvt.w.s t0, ft0, rtz`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even. Also used for `dyn`, since we don't have an `frm` register
+    #[default]
+    Rne,
+    /// Round towards zero
+    Rtz,
+    /// Round down (towards −∞)
+    Rdn,
+    /// Round up (towards +∞)
+    Rup,
+    /// Round to nearest, ties to max magnitude
+    Rmm,
+}
+
+impl RoundingMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        use RoundingMode::*;
+        match name {
+            "rne" | "dyn" => Some(Rne),
+            "rtz" => Some(Rtz),
+            "rdn" => Some(Rdn),
+            "rup" => Some(Rup),
+            "rmm" => Some(Rmm),
+            _ => None,
+        }
+    }
+
+    /// Rounds `x` to an integral value
+    pub fn round(self, x: f32) -> f32 {
+        use RoundingMode::*;
+        match self {
+            Rne => x.round_ties_even(),
+            Rtz => x.trunc(),
+            Rdn => x.floor(),
+            Rup => x.ceil(),
+            Rmm => x.round(),
+        }
+    }
+}
+
 /// Floating point instructions.
 /// In a separate enum because maybe someday I'll have a cargo feature to disable
 /// floating point instructions.
@@ -23,9 +65,11 @@ pub enum FloatInstruction {
     Class(u8, u8),
     CvtSW(u8, u8),  // fcvt.s.w
     CvtSWu(u8, u8), // fcvt.s.wu
-    CvtWS(u8, u8),  // fcvt.w.s
-    CvtWuS(u8, u8), // fcvw.wu.s
-    MvSX(u8, u8),   // fmv.s.x
+    /// rd, rs1, rounding mode
+    CvtWS(u8, u8, RoundingMode), // fcvt.w.s
+    CvtWuS(u8, u8, RoundingMode), // fcvw.wu.s
+    /// rd, rs1
+    MvSX(u8, u8), // fmv.s.x
     MvXS(u8, u8),   // fmv.x.s
     Sqrt(u8, u8),
 
@@ -33,6 +77,25 @@ pub enum FloatInstruction {
     Sw(u8, u32, u8),
 }
 
+/// Atomic memory instructions, from the A extension.
+/// FPGRARS only runs a single hart, so these are just loads and stores in disguise.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AtomicInstruction {
+    /// rd, rs1
+    LrW(u8, u8),
+    /// rd, rs2, rs1
+    ScW(u8, u8, u8),
+    AmoSwapW(u8, u8, u8),
+    AmoAddW(u8, u8, u8),
+    AmoXorW(u8, u8, u8),
+    AmoAndW(u8, u8, u8),
+    AmoOrW(u8, u8, u8),
+    AmoMinW(u8, u8, u8),
+    AmoMaxW(u8, u8, u8),
+    AmoMinuW(u8, u8, u8),
+    AmoMaxuW(u8, u8, u8),
+}
+
 /// Giant enum that represents a single RISC-V instruction and its arguments
 #[derive(Debug, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -116,9 +179,108 @@ pub enum Instruction {
     // Floating point
     Float(FloatInstruction),
 
+    // Atomics
+    Atomic(AtomicInstruction),
+
     // Some pseudoinstructions
     /// rd, imm
     Li(u8, u32),
     /// rd, rs1
     Mv(u8, u8),
 }
+
+impl Instruction {
+    /// Whether this instruction computes a value for `x0`, which is then discarded. Jumps,
+    /// CSR accesses, atomics and `nop` are left out, since they write to `x0` on purpose.
+    pub fn writes_to_zero(&self) -> bool {
+        use FloatInstruction as F;
+        use Instruction::*;
+        match *self {
+            Addi(0, 0, 0) | Li(0, 0) | Mv(0, 0) => false,
+
+            Add(rd, ..)
+            | Sub(rd, ..)
+            | Sll(rd, ..)
+            | Slt(rd, ..)
+            | Sltu(rd, ..)
+            | Xor(rd, ..)
+            | Srl(rd, ..)
+            | Sra(rd, ..)
+            | Or(rd, ..)
+            | And(rd, ..)
+            | Mul(rd, ..)
+            | Mulh(rd, ..)
+            | Mulhsu(rd, ..)
+            | Mulhu(rd, ..)
+            | Div(rd, ..)
+            | Divu(rd, ..)
+            | Rem(rd, ..)
+            | Remu(rd, ..)
+            | Lb(rd, ..)
+            | Lh(rd, ..)
+            | Lw(rd, ..)
+            | Lbu(rd, ..)
+            | Lhu(rd, ..)
+            | Addi(rd, ..)
+            | Slti(rd, ..)
+            | Sltiu(rd, ..)
+            | Slli(rd, ..)
+            | Srli(rd, ..)
+            | Srai(rd, ..)
+            | Ori(rd, ..)
+            | Andi(rd, ..)
+            | Xori(rd, ..)
+            | Lui(rd, ..)
+            | AuiPc(rd, ..)
+            | Li(rd, ..)
+            | Mv(rd, ..) => rd == 0,
+
+            Float(
+                F::Equ(rd, ..)
+                | F::Le(rd, ..)
+                | F::Lt(rd, ..)
+                | F::Class(rd, ..)
+                | F::CvtWS(rd, ..)
+                | F::CvtWuS(rd, ..)
+                | F::MvXS(rd, ..),
+            ) => rd == 0,
+
+            _ => false,
+        }
+    }
+
+    /// Whether this instruction stores something in memory
+    pub fn writes_memory(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            Sb(..) | Sh(..) | Sw(..) | Float(FloatInstruction::Sw(..))
+        ) || matches!(self, Atomic(a) if !matches!(a, AtomicInstruction::LrW(..)))
+    }
+
+    /// If this instruction loads something from memory, returns the register with the base
+    /// address, the offset added to it and how many bytes are read
+    pub fn memory_read(&self) -> Option<(u8, u32, usize)> {
+        use AtomicInstruction as A;
+        use Instruction::*;
+        match *self {
+            Lb(_, imm, rs1) | Lbu(_, imm, rs1) => Some((rs1, imm, 1)),
+            Lh(_, imm, rs1) | Lhu(_, imm, rs1) => Some((rs1, imm, 2)),
+            Lw(_, imm, rs1) | Float(FloatInstruction::Lw(_, imm, rs1)) => Some((rs1, imm, 4)),
+            Atomic(A::ScW(..)) => None,
+            Atomic(A::LrW(_, rs1)) => Some((rs1, 0, 4)),
+            Atomic(
+                A::AmoSwapW(_, _, rs1)
+                | A::AmoAddW(_, _, rs1)
+                | A::AmoXorW(_, _, rs1)
+                | A::AmoAndW(_, _, rs1)
+                | A::AmoOrW(_, _, rs1)
+                | A::AmoMinW(_, _, rs1)
+                | A::AmoMaxW(_, _, rs1)
+                | A::AmoMinuW(_, _, rs1)
+                | A::AmoMaxuW(_, _, rs1),
+            ) => Some((rs1, 0, 4)),
+            _ => None,
+        }
+    }
+}
@@ -19,3 +19,18 @@ pub mod parser;
 pub mod renderer;
 pub mod simulator;
 pub(crate) mod utf8_lossy_lines;
+
+/// A path in the temporary directory that no other test uses, even in a concurrent run of the
+/// tests
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "fpgrars_test_{}_{}_{}",
+        std::process::id(),
+        id,
+        name
+    ))
+}
@@ -1,34 +1,125 @@
+use fpgrars::renderer::input_script;
+use fpgrars::renderer::keymap::Keymap;
 use fpgrars::simulator::Simulator;
 use owo_colors::OwoColorize;
 use std::error::Error;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::thread;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let config = fpgrars::config::Config::get();
 
-    let memory = fpgrars::simulator::memory::Memory::new();
+    let memory = fpgrars::simulator::memory::Memory::new()
+        .with_transparent(config.transparent)
+        .with_display(
+            config.width,
+            config.height,
+            config.frame_layout.bytes_per_pixel,
+        )
+        .with_video(config.frame_layout.video(config.width, config.height));
     let mmio = memory.mmio.clone();
+    let sim_config = config.clone();
+    let paused = Arc::new(AtomicBool::new(false));
+    let sim_paused = paused.clone();
+    let input_signal = fpgrars::renderer::InputSignal::default();
+    let sim_input_signal = input_signal.clone();
+
+    if let Some(path) = &config.input_script {
+        let events = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|script| input_script::parse(&script).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                eprintln!("   {}: {}: {}\n", "[error]".bright_red().bold(), path, e);
+                std::process::exit(1);
+            });
+        input_script::spawn(mmio.clone(), events, input_signal.clone());
+    }
 
     let sim_thread = thread::Builder::new()
         .name("FPGRARS Simulator".into())
         .spawn(move || {
-            let mut sim = Simulator::default()
-                .with_memory(memory)
-                .with_midi_port(config.port);
+            let sim = Simulator::default().with_memory(memory);
+            let sim = match &sim_config.midi_device {
+                Some(device) => sim.with_midi_device(device),
+                None => sim.with_midi_port(sim_config.port),
+            };
+            let mut sim = sim.with_config(sim_config.clone());
+            if let Some(path) = &sim_config.stdout {
+                match std::fs::File::create(path) {
+                    Ok(file) => sim = sim.with_output(io::LineWriter::new(file)),
+                    Err(e) => {
+                        eprintln!("   {}: {}: {}\n", "[error]".bright_red().bold(), path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Some(path) = &sim_config.trace {
+                match std::fs::File::create(path) {
+                    Ok(file) => sim = sim.with_trace(io::BufWriter::new(file)),
+                    Err(e) => {
+                        eprintln!("   {}: {}: {}\n", "[error]".bright_red().bold(), path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if !sim_config.no_video {
+                sim = sim.with_pause_flag(sim_paused);
+            }
+            if !sim_config.no_video || sim_config.input_script.is_some() {
+                sim = sim.with_input_signal(sim_input_signal);
+            }
 
-            if let Err(e) = sim.load_file(&config.file) {
+            if let Err(e) = sim.load_file(&sim_config.file) {
                 eprintln!("   {}: {}\n", "[error]".bright_red().bold(), e);
                 std::process::exit(1);
             };
 
+            for (addr, file) in &sim_config.load_image {
+                if let Err(e) = sim.load_image(*addr, file) {
+                    eprintln!(
+                        "   {}: couldn't load {} at {:#x}: {}\n",
+                        "[error]".bright_red().bold(),
+                        file,
+                        addr,
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+
             let start_time = std::time::Instant::now();
-            let exit_code = sim.run();
-            eprintln!("Finished in {}ms", start_time.elapsed().as_millis());
-            std::process::exit(exit_code);
+            match sim.run() {
+                Ok(exit_code) => {
+                    eprintln!("Finished in {}ms", start_time.elapsed().as_millis());
+                    std::process::exit(exit_code);
+                }
+                Err(e) => {
+                    eprintln!("   {} {}", "[error]".bright_red(), e);
+                    std::process::exit(1);
+                }
+            }
         })?;
 
     if !config.no_video {
-        let state = fpgrars::renderer::State::new(mmio, config.width, config.height, config.scale);
+        let keymap = match &config.keymap {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|keymap| Keymap::from_toml(&keymap).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    eprintln!("   {}: {}: {}\n", "[error]".bright_red().bold(), path, e);
+                    std::process::exit(1);
+                }),
+            None => Keymap::default(),
+        };
+
+        let state = fpgrars::renderer::State::new(mmio, config.width, config.height, config.scale)
+            .with_keymap(keymap)
+            .with_layers(config.layers, config.transparent)
+            .with_layout(config.frame_layout)
+            .with_pause_flag(paused)
+            .with_input_signal(input_signal);
         fpgrars::renderer::init(state);
     }
 
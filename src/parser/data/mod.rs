@@ -4,11 +4,12 @@ pub use unlabel::unlabel;
 use crate::parser::error::Contextualize;
 use crate::parser::LabelUseType;
 
-use super::error::{Error, ParserError};
-use super::token::Token;
+use super::error::{Error, LexerError, ParserError};
+use super::token::{self, Token};
 use super::ParserContext;
 
 use byteorder::{ByteOrder, LittleEndian};
+use owo_colors::OwoColorize;
 use std::str::FromStr;
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -56,25 +57,41 @@ impl FromStr for Type {
 /// alignment 0 = byte, 1 = half, 2 = word, 3 = double.
 fn align(data: &mut Vec<u8>, alignment: u32) {
     let multiple = 1 << alignment;
-    let blocks = (data.len() + multiple - 1) / multiple; // ceil(len / multiple)
-    let len = blocks * multiple;
+    let len = data.len().div_ceil(multiple) * multiple;
     data.resize(len, 0);
 }
 
+/// Aligns the data to the boundary of the current data type, and commits the labels before it to
+/// the aligned position
+fn align_and_commit(ctx: &mut ParserContext) {
+    use Type::*;
+    match ctx.data_type {
+        Half => align(&mut ctx.data, 1),
+        Word | Float => align(&mut ctx.data, 2),
+        Align | Byte | Ascii | Asciz | Space => {}
+    }
+    ctx.commit_data_label_backlog();
+}
+
+/// Appends raw bytes, like the contents of an `.incbin` file, to the data vector. They're aligned
+/// to a word boundary, so the data can be read with `lw` from the label before it.
+pub fn push_bytes(ctx: &mut ParserContext, bytes: &[u8]) {
+    align(&mut ctx.data, 2);
+    ctx.commit_data_label_backlog();
+    ctx.data.extend_from_slice(bytes);
+}
+
 /// Stores a numerical token with value `value` in the data vector.
 fn store_numerical(ctx: &mut ParserContext, value: u32) -> Result<(), Error> {
     use Type::*;
 
     // Align the data to the correct boundary
-    match ctx.data_type {
-        Half => align(&mut ctx.data, 1),
-        Word | Float => align(&mut ctx.data, 2),
-        Align => align(&mut ctx.data, value),
-        Byte | Ascii | Asciz | Space => {}
+    if ctx.data_type == Align {
+        align(&mut ctx.data, value);
     }
 
     // Commit the current data position to the label
-    ctx.commit_data_label_backlog();
+    align_and_commit(ctx);
 
     // Push data into the data vector
     match ctx.data_type {
@@ -103,16 +120,54 @@ fn store_numerical(ctx: &mut ParserContext, value: u32) -> Result<(), Error> {
     Ok(())
 }
 
+/// Whether narrowing `f` to `narrowed` overflowed, underflowed, or rounded by more than the usual
+/// half an ulp (which happens for subnormals)
+fn loses_precision(f: f64, narrowed: f32) -> bool {
+    let error = (narrowed as f64 - f).abs();
+    f.is_finite() && error > f.abs() * f32::EPSILON as f64
+}
+
+fn warn_lossy_float(ctx: &mut ParserContext, f: f64, narrowed: f32, token_ctx: &token::Context) {
+    ctx.lossy_floats += 1;
+    eprintln!(
+        "   {} {} doesn't fit in a 32-bit float, it's stored as {}\n{}",
+        "[warning]".bright_yellow(),
+        f.bright_yellow(),
+        narrowed.bright_blue(),
+        token_ctx
+    );
+}
+
 /// Pushes a data token onto the data vector.
 pub fn push_data(token: Token, ctx: &mut ParserContext) -> Result<(), Error> {
     use super::token::Data::*;
     match token.data {
         Identifier(label) => {
+            // The label may be the one being defined, like in `x: .word x`, so it has to be
+            // committed before it's used
+            align_and_commit(ctx);
+            let pos = ctx.use_label(&label, LabelUseType::Data, token.ctx.clone());
+            store_numerical(ctx, pos)?;
+        }
+        NumericLabelRef { number, forward } => {
+            align_and_commit(ctx);
+            let label = ctx.numeric_label_ref(number, forward, &token.ctx)?;
             let pos = ctx.use_label(&label, LabelUseType::Data, token.ctx.clone());
             store_numerical(ctx, pos)?;
         }
         Integer(i) => store_numerical(ctx, i as u32)?,
-        Float(f) => store_numerical(ctx, f.to_bits())?,
+        Float(_) if ctx.data_type != Type::Float && token.data.is_oversized_integer() => {
+            return Err(
+                LexerError::IntegerOutOfRange(token.data.to_string()).with_context(token.ctx)
+            )
+        }
+        Float(f) => {
+            let narrowed = f as f32;
+            if loses_precision(f, narrowed) {
+                warn_lossy_float(ctx, f, narrowed, &token.ctx);
+            }
+            store_numerical(ctx, narrowed.to_bits())?
+        }
         CharLiteral(c) => store_numerical(ctx, c as u32)?,
         StringLiteral(s) => {
             ctx.commit_data_label_backlog();
@@ -165,7 +220,7 @@ mod tests {
             ..Default::default()
         };
         assert!(store_numerical(&mut ctx, 2).is_ok());
-        assert_eq!(&ctx.data, &[]);
+        assert_eq!(&ctx.data, &[0u8; 0]);
     }
 
     #[test]
@@ -221,6 +276,26 @@ mod tests {
         assert_eq!(&ctx.data, b"Hello world!\0");
     }
 
+    #[test]
+    fn test_lossy_float() {
+        let mut ctx = ParserContext {
+            data_type: Type::Float,
+            ..Default::default()
+        };
+
+        assert!(push_data(Token::new(Data::Float(1.5)), &mut ctx).is_ok());
+        assert_eq!(ctx.lossy_floats, 0);
+        assert!(push_data(Token::new(Data::Float(1e40)), &mut ctx).is_ok());
+        assert_eq!(ctx.lossy_floats, 1);
+
+        assert_eq!(LittleEndian::read_f32(&ctx.data[0..4]), 1.5);
+        assert_eq!(LittleEndian::read_f32(&ctx.data[4..8]), f32::INFINITY);
+
+        // Rounding to the nearest f32 is expected, flushing to zero isn't
+        assert!(!loses_precision(0.1, 0.1));
+        assert!(loses_precision(1e-50, 0.0));
+    }
+
     #[test]
     fn test_label_alignment() {
         let input = ".data
@@ -239,7 +314,8 @@ mod tests {
             la x0 End";
 
         let tokens = Lexer::from_content(String::from(input), "test_label_alignment").peekable();
-        let Parsed { code, .. } = parse_tokens(tokens, DATA_SIZE).unwrap();
+        let Parsed { code, .. } =
+            parse_tokens(tokens, vec![0; DATA_SIZE], Default::default()).unwrap();
 
         use crate::instruction::Instruction::{Ecall, Li};
         assert_eq!(
@@ -255,4 +331,32 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn test_comma_separated_values() {
+        let input = ".data\n .word 1,2,,3,\n .byte 4";
+        let tokens = Lexer::from_content(String::from(input), "test_comma_separated_values");
+        let Parsed { data, .. } =
+            parse_tokens(tokens.peekable(), vec![0; 16], Default::default()).unwrap();
+        assert_eq!(&data[..13], &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn test_empty_data_directive() {
+        let empty = |input: &str| {
+            let tokens = Lexer::from_content(String::from(input), "test_empty_data_directive");
+            match parse_tokens(tokens.peekable(), vec![0; 16], Default::default()) {
+                Err(Error::WithContext { err, .. }) => {
+                    matches!(*err, Error::Parser(ParserError::EmptyDataDirective(_)))
+                }
+                _ => false,
+            }
+        };
+
+        assert!(empty(".data\n .word"));
+        assert!(empty(".data\n .word\n .byte 1"));
+        assert!(empty(".data\n x: .word\n .text\n nop"));
+        assert!(!empty(".data\n .word\n 1"));
+        assert!(!empty(".data\n .word 1\n .text"));
+    }
 }
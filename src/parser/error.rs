@@ -7,6 +7,15 @@ use owo_colors::OwoColorize;
 use std::{borrow::Cow, io, path::Path};
 use thiserror::Error;
 
+/// Smallest and largest values of an immediate field with `bits` bits
+pub(super) fn imm_bounds(bits: u32, signed: bool) -> (i64, i64) {
+    if signed {
+        (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+    } else {
+        (0, (1i64 << bits) - 1)
+    }
+}
+
 fn some_or_eof<T: fmt::Display>(s: &Option<T>) -> Cow<'static, str> {
     match s {
         Some(s) => s.to_string().into(),
@@ -27,12 +36,50 @@ pub enum ParserError {
     #[error("Value '{}' cannot be stored in data type '{1:?}'", .0.bright_blue())]
     InvalidDataType(token::Data, data::Type),
 
+    #[error("The directive '{}{}' has no values. Write them after it, like in `{}`", ".".bright_yellow(), .0.bright_yellow(), ".word 1, 2, 3".bright_blue())]
+    EmptyDataDirective(String),
+
     #[error("Unknown directive '{}{}'", ".".bright_yellow(), .0.bright_yellow())]
     UnknownDirective(String),
 
+    #[error("Unknown section '{}'. Code sections (like `{}`) and data sections (like `{}`, `{}` or `{}`) are supported", .0.bright_yellow(), ".text".bright_blue(), ".data".bright_blue(), ".rodata".bright_blue(), ".bss".bright_blue())]
+    UnknownSection(String),
+
+    #[error("The code can't continue at {}. The address after `{}` should be a multiple of 4, at least {} (where the code is now) and less than {} (the end of the text segment, which starts at 0 in FPGRARS, or at 0x400000 like in RARS)", format!("{:#x}", .address).bright_yellow(), ".text".bright_blue(), format!("{:#x}", .min).bright_blue(), format!("{:#x}", .max).bright_blue())]
+    InvalidTextAddress {
+        address: u32,
+        min: usize,
+        max: usize,
+    },
+
+    #[error("The data can't continue at {}. The address after `{}` should be between {} (where the data is now) and {} (the end of the data segment, which starts at 0 in FPGRARS, or at 0x10010000 like in RARS)", format!("{:#x}", .address).bright_yellow(), ".data".bright_blue(), format!("{:#x}", .min).bright_blue(), format!("{:#x}", .max).bright_blue())]
+    InvalidDataAddress {
+        address: u32,
+        min: usize,
+        max: usize,
+    },
+
     #[error("Unknown instruction '{}'", .0.bright_yellow())]
     UnknownInstruction(String),
 
+    #[error("'{}' is not supported, because FPGRARS doesn't implement {feature}", .instr.bright_yellow())]
+    UnsupportedInstruction {
+        instr: String,
+        feature: &'static str,
+    },
+
+    #[error("'{}' is an instruction, but it's in the data segment. Did you forget a `{}` before it?", .0.bright_yellow(), ".text".bright_blue())]
+    InstructionInDataSegment(String),
+
+    #[error("'{}{}' declares data, but it's in the text segment. Did you forget a `{}` before it?", ".".bright_yellow(), .0.bright_yellow(), ".data".bright_blue())]
+    DataInTextSegment(String),
+
+    #[error("Expected a file name after `{}`, but found '{}'", ".incbin".bright_blue(), some_or_eof(.0).bright_yellow())]
+    ExpectedIncbinPath(Option<token::Data>),
+
+    #[error("Couldn't read '{}': {err}", Path::new(.path).normalize().display().bright_blue())]
+    IncbinFailed { path: String, err: io::Error },
+
     #[error("Expected a register name, but found '{}'", some_or_eof(.0).bright_yellow())]
     ExpectedRegister(Option<String>),
 
@@ -51,6 +98,12 @@ pub enum ParserError {
     #[error("Did not expect token '{}' here.", some_or_eof(.0).bright_yellow())]
     UnexpectedToken(Option<token::Data>),
 
+    #[error("Jump target {} is not the address of an instruction, it should be a multiple of 4 between {} and {}", format!("{:#x}", .0).bright_yellow(), "0".bright_blue(), format!("{:#x}", .1).bright_blue())]
+    InvalidJumpTarget(usize, usize),
+
+    #[error("Immediate {} doesn't fit in a {bits}-bit {} field, it should be between {} and {}", .value.bright_yellow(), if *.signed { "signed" } else { "unsigned" }, imm_bounds(*.bits, *.signed).0.bright_blue(), imm_bounds(*.bits, *.signed).1.bright_blue())]
+    ImmediateOutOfRange { value: i32, bits: u32, signed: bool },
+
     #[error("You have used labels without defining them:\n{}", ManyContexts(.0))]
     UndefinedLabels(Vec<token::Context>),
 }
@@ -69,8 +122,8 @@ pub enum LexerError {
     #[error("Unknown char '{0}'")]
     UnknownChar(char),
 
-    #[error("Unexpected espace sequence '\\{0}'")]
-    UnexpectedEscapeSequence(char),
+    #[error("Invalid escape sequence '\\{}'. The escapes are {}, and hex ones, which take two digits in the ASCII range, like '{}'", found.bright_yellow(), "\\n \\t \\r \\0 \\\\ \\\" \\'".bright_blue(), "\\x41".bright_blue())]
+    InvalidEscapeSequence { found: String },
 
     #[error("Expected escape sequence after '\\', found nothing")]
     ExpectedEscapeSequence,
@@ -78,8 +131,17 @@ pub enum LexerError {
     #[error("Expected character, found nothing")]
     ExpectedChar,
 
+    #[error("Empty character literal")]
+    EmptyCharLiteral,
+
+    #[error("Character literal '{}' has more than one character", .0.bright_yellow())]
+    CharLiteralTooLong(String),
+
     #[error("Invalid number '{0}'")]
     InvalidNumber(String),
+
+    #[error("The integer '{}' doesn't fit in 32 bits", .0.bright_yellow())]
+    IntegerOutOfRange(String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -96,11 +158,33 @@ pub enum PreprocessorError {
     #[error("The argument '{arg}' in macro '{macro_name}' was defined more than once.")]
     DuplicateMacroArg { macro_name: String, arg: String },
 
+    #[error("The argument '{arg}...' in macro '{macro_name}' takes the remaining arguments, so it must be the last one.")]
+    VariadicMacroArgNotLast { macro_name: String, arg: String },
+
     #[error(
         "The argument '{arg}' in macro '{macro_name}' was used in the macro body, but not defined."
     )]
     UndefinedMacroArg { macro_name: String, arg: String },
 
+    #[error("The macro '{}' expects {expected} arguments, but was invoked with {found}.", .name.bright_yellow())]
+    MacroArityMismatch {
+        name: String,
+        expected: String,
+        found: usize,
+    },
+
+    #[error("The macro '{}' uses itself, so it would never stop expanding.", .0.bright_yellow())]
+    RecursiveMacro(String),
+
+    #[error("Expected a number or an .equ in the condition, found '{}'", some_or_eof(.0).bright_yellow())]
+    InvalidConditionOperand(Option<token::Data>),
+
+    #[error("Found {}{} without a matching {}", ".".bright_yellow(), .0.bright_yellow(), ".if".bright_blue())]
+    UnmatchedConditional(String),
+
+    #[error(".if was not terminated by .endif.")]
+    UnterminatedIf,
+
     #[error("{} is not a valid name for an .equ. The name must be a valid identifier.", .0.bright_blue())]
     EquWithInvalidName(token::Data),
     #[error(".equ should have a name and a value: {}", ".equ <name> <value>".bright_blue())]
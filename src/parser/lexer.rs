@@ -1,7 +1,8 @@
 //! TODO: replace unwraps and panics in this file by proper error handling
 
 use super::error::{Error, LexerError};
-use super::token::{Context, ContextualizeResult, Data, Token};
+use super::token::{Context, ContextualizeResult, Data, Relocation, Token};
+use std::num::IntErrorKind;
 use std::rc::Rc;
 use std::{fs, io};
 
@@ -83,24 +84,51 @@ impl Lexer {
             None => self.cursor = self.content.len(),
         }
 
-        self.context.advance_char(next_char);
+        // A lone '\r' (classic Mac line ending) also breaks the line
+        match next_char {
+            '\r' if self.peek() != Some('\n') => self.context.advance_char('\n'),
+            c => self.context.advance_char(c),
+        }
         Some(next_char)
     }
 
+    /// Is the cursor at a backslash that ends the line, like `li a0, \<newline> 1`?
+    fn at_line_continuation(&self) -> bool {
+        let rest = &self.content[self.cursor..];
+        rest.starts_with("\\\n") || rest.starts_with("\\\r")
+    }
+
     pub fn consume_comment(&mut self) {
-        while !matches!(self.consume(), None | Some('\n')) {
+        while !matches!(self.consume(), None | Some('\n' | '\r')) {
             // continue consuming
         }
     }
 
-    fn next_identifier(&mut self) -> Token {
-        let mut id = String::new();
-        while let Some(allowed_identifier!()) = self.peek() {
-            let c = self.consume().unwrap();
-            id.push(c);
-        }
+    /// Consumes an identifier and returns it. Identifiers are ASCII-only, so they can be sliced
+    /// directly out of the content instead of being built char by char
+    fn next_identifier(&mut self) -> String {
+        let start = self.cursor;
+        let len = self.content[start..]
+            .bytes()
+            .take_while(|&b| matches!(b as char, allowed_identifier!()))
+            .count();
+
+        // No newlines or tabs in identifiers, so every char is a single column
+        self.cursor += len;
+        self.context.column += len as u32;
+
+        self.content[start..start + len].to_owned()
+    }
 
-        Token::new(Data::Identifier(id))
+    /// Sets the width of a token that ends at the cursor. Tokens that span more than one line, like
+    /// string literals with line breaks, are left without one.
+    fn spanned(&self, token: Token) -> Token {
+        let width = if token.ctx.line == self.context.line {
+            (self.context.column + 1).saturating_sub(token.ctx.column)
+        } else {
+            0
+        };
+        Token { width, ..token }
     }
 
     // WARN: assumes the '\' has already been consumed
@@ -111,11 +139,36 @@ impl Lexer {
             Some('r') => Ok('\r'),
             Some('\\') => Ok('\\'),
             Some('"') => Ok('"'),
-            Some(c) => Err(LexerError::UnexpectedEscapeSequence(c).into()),
+            Some('\'') => Ok('\''),
+            Some('0') => Ok('\0'),
+            Some('x') => self.next_hex_escape(),
+            Some(c) => Err(LexerError::InvalidEscapeSequence {
+                found: c.to_string(),
+            }
+            .into()),
             None => Err(LexerError::ExpectedEscapeSequence.into()),
         }
     }
 
+    // WARN: assumes the '\x' has already been consumed
+    fn next_hex_escape(&mut self) -> Result<char, Error> {
+        // Only hex digits are consumed, so a short escape like `"\x4"` doesn't swallow the quote
+        let mut digits = String::new();
+        while digits.len() < 2 && self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            digits.extend(self.consume());
+        }
+
+        // Only ASCII is accepted, because anything above 0x7F would be encoded as two UTF-8 bytes
+        // in string literals
+        match u8::from_str_radix(&digits, 16) {
+            Ok(x) if digits.len() == 2 && x.is_ascii() => Ok(x as char),
+            _ => Err(LexerError::InvalidEscapeSequence {
+                found: format!("x{}", digits),
+            }
+            .into()),
+        }
+    }
+
     fn next_string_literal(&mut self) -> Result<Token, Error> {
         let mut string = String::new();
         expect!(Some('"') = self.consume());
@@ -134,28 +187,68 @@ impl Lexer {
 
     fn next_char_literal(&mut self) -> Result<Token, Error> {
         expect!(Some('\'') = self.consume());
-        let mut c = match self.consume() {
+        let c = match self.consume() {
+            Some('\'') => return Err(LexerError::EmptyCharLiteral.into()),
+            Some('\\') => self.next_escape_sequence()?,
             Some(c) => c,
             None => return Err(LexerError::ExpectedChar.into()),
         };
-        if c == '\\' {
-            c = self.next_escape_sequence()?;
+
+        if let Some('\'') = self.peek() {
+            self.consume().unwrap();
+            return Ok(Token::new(Data::CharLiteral(c)));
         }
-        expect!(Some('\'') = self.consume());
 
-        Ok(Token::new(Data::CharLiteral(c)))
+        // Something like 'ab'. If the literal is closed on the same line, we can report it as too
+        // long, otherwise we just complain about the missing quote
+        let mut literal = String::from(c);
+        while let Some(next) = self.peek().filter(|&c| c != '\'' && c != '\n') {
+            literal.push(next);
+            self.consume().unwrap();
+        }
+        match self.consume() {
+            Some('\'') => Err(LexerError::CharLiteralTooLong(literal).into()),
+            found => Err(LexerError::UnexpectedChar {
+                expected: '\'',
+                found: found.unwrap_or('\0'),
+            }
+            .into()),
+        }
     }
 
     fn next_number(&mut self) -> Result<Token, Error> {
         let cursor = self.cursor;
         let mut i = 0;
-        while let Some('-' | '.' | '0'..='9' | 'x' | 'o' | 'a'..='f' | 'A'..='F') = self.peek() {
+        while let Some(
+            '-' | '+' | '.' | '0'..='9' | 'x' | 'o' | 'p' | 'P' | 'a'..='f' | 'A'..='F',
+        ) = self.peek()
+        {
             self.consume().unwrap();
             i += 1; // Those characters are guaranteed to be ASCII!
         }
 
         let mut slice = &self.content[cursor..cursor + i];
 
+        // `1f` and `1b` refer to numeric local labels
+        if let Some((digits, direction)) = slice.split_at_checked(i.saturating_sub(1)) {
+            if matches!(direction, "f" | "b") && is_decimal(digits) {
+                let number = digits
+                    .parse()
+                    .map_err(|_| LexerError::IntegerOutOfRange(digits.to_string()))?;
+                let forward = direction == "f";
+                return Ok(Token::new(Data::NumericLabelRef { number, forward }));
+            }
+        }
+
+        // `1:` defines a numeric local label
+        if is_decimal(slice) && self.peek() == Some(':') {
+            let number = slice
+                .parse()
+                .map_err(|_| LexerError::IntegerOutOfRange(slice.to_string()))?;
+            self.consume().unwrap();
+            return Ok(Token::new(Data::NumericLabel(number)));
+        }
+
         let mut negative = false;
         if let Some(positive_part) = slice.strip_prefix('-') {
             slice = positive_part;
@@ -174,10 +267,20 @@ impl Lexer {
             slice.parse::<u32>()
         };
 
-        if res.is_err() {
-            let mut fres = slice
-                .parse::<f32>()
-                .map_err(|_| LexerError::InvalidNumber(slice.to_string()))?;
+        if let Err(e) = &res {
+            // Integers that don't fit, like `10000000000`, are still fine as floats. Where an
+            // integer is expected, the parser reports them as out of range.
+            let fres = match slice.strip_prefix("0x") {
+                Some(hex) => parse_hex_float(hex),
+                None => slice.parse::<f64>().ok(),
+            };
+            let mut fres = fres.ok_or_else(|| match e.kind() {
+                IntErrorKind::PosOverflow => {
+                    let literal = &self.content[cursor..cursor + i];
+                    LexerError::IntegerOutOfRange(literal.to_string())
+                }
+                _ => LexerError::InvalidNumber(slice.to_string()),
+            })?;
             if negative {
                 fres = -fres;
             }
@@ -186,20 +289,41 @@ impl Lexer {
 
         let mut x = res.unwrap() as i32;
         if negative {
-            x = -x;
+            x = x.wrapping_neg();
         }
         Ok(Token::new(Data::Integer(x)))
     }
 }
 
-impl Iterator for Lexer {
-    type Item = Result<Token, Error>;
+fn is_decimal(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit())
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let next_char = match self.peek() {
-            None => return None,
-            Some(c) => c,
-        };
+/// Parses the part after `0x` of a C-style hexadecimal float, like `1.8p1` (which is 3.0).
+/// The binary exponent after the `p` is required.
+fn parse_hex_float(s: &str) -> Option<f64> {
+    let (mantissa, exponent) = s.split_once(['p', 'P'])?;
+    let mut exponent = exponent.parse::<i32>().ok()?;
+
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if int_part.is_empty() && frac_part.is_empty() {
+        return None;
+    }
+
+    // f64 holds 13 hex digits exactly, way more than an f32 needs
+    let mut value = 0f64;
+    for c in int_part.chars().chain(frac_part.chars()) {
+        value = value * 16.0 + c.to_digit(16)? as f64;
+    }
+    exponent = exponent.saturating_sub(4 * frac_part.len() as i32);
+
+    Some(value * 2f64.powi(exponent))
+}
+
+impl Lexer {
+    /// Lexes the next token, without its width
+    fn next_token(&mut self) -> Option<Result<Token, Error>> {
+        let next_char = self.peek()?;
 
         let mut ctx = self.context.clone();
         ctx.advance_char(next_char);
@@ -208,36 +332,41 @@ impl Iterator for Lexer {
             // whitespace
             ' ' | ',' | '\n' | '\t' | '\x09'..='\x0d' => {
                 self.consume().unwrap();
-                self.next()
+                self.next_token()
+            }
+
+            // line continuation, the newline is consumed like any other whitespace
+            '\\' if self.at_line_continuation() => {
+                while !matches!(self.consume(), None | Some('\n' | '\r')) {}
+                self.next_token()
             }
 
             // comments
             '#' => {
                 self.consume_comment();
-                self.next()
+                self.next_token()
             }
 
             '.' => {
                 self.consume().unwrap();
-                let id = match self.next_identifier().data {
-                    Data::Identifier(id) => id,
-                    _ => unreachable!(),
-                };
+                let id = self.next_identifier();
                 Some(Ok(Token::new(Data::Directive(id)).with_ctx(ctx)))
             }
 
             '%' => {
                 self.consume().unwrap();
-                let id = match self.next_identifier().data {
-                    Data::Identifier(id) => id,
-                    _ => unreachable!(),
+                let id = self.next_identifier();
+                let data = match Relocation::from_name(&id) {
+                    // `%hi(label)` is a relocation, `%hi` by itself is a macro argument
+                    Some(relocation) if self.peek() == Some('(') => Data::Relocation(relocation),
+                    _ => Data::MacroArg(id),
                 };
-                Some(Ok(Token::new(Data::MacroArg(id)).with_ctx(ctx)))
+                Some(Ok(Token::new(data).with_ctx(ctx)))
             }
 
             '"' => Some(self.next_string_literal().with_ctx(ctx)),
             '\'' => Some(self.next_char_literal().with_ctx(ctx)),
-            ':' | '(' | ')' => {
+            ':' | '(' | ')' | '=' | '!' | '<' | '>' => {
                 self.consume().unwrap();
                 Some(Ok(Token::new(Data::Char(next_char)).with_ctx(ctx)))
             }
@@ -245,18 +374,14 @@ impl Iterator for Lexer {
             '-' | '0'..='9' => Some(self.next_number().with_ctx(ctx)),
 
             allowed_identifier!(start) => {
-                let identifier = self.next_identifier();
+                let id = self.next_identifier();
                 if let Some(':') = self.peek() {
                     // label
                     self.consume().unwrap();
-                    let id = match identifier.data {
-                        Data::Identifier(id) => id,
-                        _ => unreachable!(),
-                    };
                     Some(Ok(Token::new(Data::Label(id)).with_ctx(ctx)))
                 } else {
                     // just an identifier
-                    Some(Ok(identifier.with_ctx(ctx)))
+                    Some(Ok(Token::new(Data::Identifier(id)).with_ctx(ctx)))
                 }
             }
 
@@ -265,6 +390,15 @@ impl Iterator for Lexer {
     }
 }
 
+impl Iterator for Lexer {
+    type Item = Result<Token, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.next_token()?;
+        Some(token.map(|token| self.spanned(token)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -366,6 +500,37 @@ DE1(t0, LABEL)
         );
     }
 
+    #[test]
+    fn test_token_widths() {
+        let data = "li t0, 0x10 '\\n' \"a\\tb\" label: .word -1.5 %hi(x)";
+        let lexer = Lexer::from_content(String::from(data), "widths.s");
+        let widths = lexer.map(|t| t.unwrap().width).collect::<Vec<_>>();
+        assert_eq!(widths, [2, 2, 4, 4, 6, 6, 5, 4, 3, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_identifier_chars() {
+        let data = "azAZ_09.$@ _x: .dir.x %arg_1 $t@0 é";
+        let lexer = Lexer::from_content(String::from(data), "identifiers.s");
+        let tokens = lexer
+            .map(|t| t.map(|t| (t.data, t.ctx.column)))
+            .take(6)
+            .collect::<Vec<_>>();
+
+        use crate::parser::token::Data::*;
+        assert!(matches!(
+            &tokens[..],
+            [
+                Ok((Identifier(a), 2)),
+                Ok((Label(b), 13)),
+                Ok((Directive(c), 17)),
+                Ok((MacroArg(d), 24)),
+                Ok((Identifier(e), 31)),
+                Err(_),
+            ] if a == "azAZ_09.$@" && b == "_x" && c == "dir.x" && d == "arg_1" && e == "$t@0"
+        ));
+    }
+
     #[test]
     fn test_numbers() {
         let data = "
@@ -420,6 +585,70 @@ DE1(t0, LABEL)
         );
     }
 
+    #[test]
+    fn test_integer_range() {
+        let lex = |input: &str| {
+            let mut lexer = Lexer::from_content(String::from(input), "test_integer_range.s");
+            lexer.next().unwrap()
+        };
+
+        assert_eq!(lex("0xFFFFFFFF").unwrap().data, Data::Integer(-1));
+        assert_eq!(lex("4294967295").unwrap().data, Data::Integer(-1));
+        assert_eq!(lex("-0x80000000").unwrap().data, Data::Integer(i32::MIN));
+        for invalid in [
+            "0x100000000",
+            "0o40000000000",
+            "0b100000000000000000000000000000000",
+        ] {
+            match lex(invalid) {
+                Err(Error::WithContext { err, .. }) => assert!(
+                    matches!(*err, Error::Lexer(LexerError::IntegerOutOfRange(_))),
+                    "{invalid}: unexpected error {err:?}"
+                ),
+                other => panic!("{invalid}: expected an out of range error, got {other:?}"),
+            }
+        }
+
+        // Decimal integers that don't fit are read as floats, so `.float 10000000000` works
+        assert_eq!(lex("4294967296").unwrap().data, Data::Float(4294967296.0));
+        assert_eq!(lex("-4294967296").unwrap().data, Data::Float(-4294967296.0));
+        assert!(Data::Float(-4294967296.0).is_oversized_integer());
+        assert!(!Data::Float(4294967296.5).is_oversized_integer());
+    }
+
+    #[test]
+    fn test_hex_float() {
+        let input = ".float 0x1.8p1 0x1p-2 -0x1.4p-3 0x.8p+1 0x10P0 0x1.fffffep127 0x1p-149 1e+2";
+        let lexer = Lexer::from_content(String::from(input), "test_hex_float.s");
+        let floats = lexer
+            .skip(1)
+            .map(|t| match t.unwrap().data {
+                Data::Float(x) => (x as f32).to_bits(),
+                other => panic!("expected a float, found {other:?}"),
+            })
+            .collect::<Vec<_>>();
+
+        let expected = [
+            3.0,
+            0.25,
+            -0.15625,
+            1.0,
+            16.0,
+            f32::MAX,
+            f32::from_bits(1),
+            100.0,
+        ];
+        assert_eq!(floats, expected.map(f32::to_bits));
+
+        for invalid in ["0x1.8", "0xp1", "0x1.gp1"] {
+            let mut lexer = Lexer::from_content(String::from(invalid), "invalid.s");
+            assert!(
+                lexer.next().unwrap().is_err(),
+                "{invalid} should be invalid"
+            );
+        }
+    }
+
     #[test]
     fn test_identifier() {
         let lexer = Lexer::from_content(
@@ -459,4 +688,105 @@ DE1(t0, LABEL)
             ]
         );
     }
+
+    #[test]
+    fn test_escape_sequences() {
+        let input = r#".asciz "a\0b" "\x41\x7e" '\0' '\x0A'"#;
+        let lexer = Lexer::from_content(String::from(input), "escapes.s");
+        let tokens = lexer.map(|t| t.unwrap().data).collect::<Vec<_>>();
+        use crate::parser::token::Data::*;
+        assert_eq!(
+            tokens,
+            &[
+                Directive("asciz".into()),
+                StringLiteral("a\0b".into()),
+                StringLiteral("A~".into()),
+                CharLiteral('\0'),
+                CharLiteral('\n'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_escape_sequences() {
+        for (input, expected) in [
+            (r#""\xZZ""#, "x"),
+            (r#""\x4""#, "x4"),
+            (r#"'\x4'"#, "x4"),
+            (r#""\xFF""#, "xFF"),
+            (r#""\q""#, "q"),
+        ] {
+            let mut lexer = Lexer::from_content(String::from(input), "escapes.s");
+            let found = match lexer.next().unwrap() {
+                Err(Error::WithContext { err, .. }) => match *err {
+                    Error::Lexer(LexerError::InvalidEscapeSequence { found }) => found,
+                    err => panic!("{input}: unexpected error {err:?}"),
+                },
+                other => panic!("{input}: expected an invalid escape, got {other:?}"),
+            };
+            assert_eq!(found, expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_escaped_char_literals() {
+        let input = r"'\'' '\n' 'ç'";
+        let lexer = Lexer::from_content(String::from(input), "chars.s");
+        let tokens = lexer.map(|t| t.unwrap().data).collect::<Vec<_>>();
+        use crate::parser::token::Data::*;
+        assert_eq!(
+            tokens,
+            &[CharLiteral('\''), CharLiteral('\n'), CharLiteral('ç')]
+        );
+    }
+
+    #[test]
+    fn test_invalid_char_literals() {
+        let mut lexer = Lexer::from_content(String::from("''"), "chars.s");
+        let err = lexer.next().unwrap().unwrap_err().to_string();
+        assert!(err.contains("Empty character literal"), "{err}");
+
+        let mut lexer = Lexer::from_content(String::from("'ab'"), "chars.s");
+        let err = lexer.next().unwrap().unwrap_err().to_string();
+        assert!(err.contains("has more than one character"), "{err}");
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        let lexer = Lexer::from_content(String::from("li a0, \\\n    1\nnop"), "continue.s");
+        let tokens = lexer.map(|t| t.unwrap()).collect::<Vec<_>>();
+        let oneline = Lexer::from_content(String::from("li a0, 1 nop"), "oneline.s");
+
+        let data = tokens.iter().map(|t| t.data.clone()).collect::<Vec<_>>();
+        let oneline = oneline.map(|t| t.unwrap().data).collect::<Vec<_>>();
+        assert_eq!(data, oneline);
+
+        // `1` is in the second physical line, and `nop` in the third
+        assert_eq!(tokens[2].ctx.line, 2);
+        assert_eq!(tokens[3].ctx.line, 3);
+    }
+
+    #[test]
+    fn test_line_endings_agree_with_context_lines() {
+        use crate::utf8_lossy_lines::Utf8LossyLinesExt;
+
+        let input = "main: # comment\rli a0, 1\r\nnop\n\rret";
+        let lexer = Lexer::from_content(String::from(input), "endings.s");
+        let tokens = lexer.map(|t| t.unwrap()).collect::<Vec<_>>();
+
+        let lines = input
+            .as_bytes()
+            .utf8_lossy_lines()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lines, ["main: # comment", "li a0, 1", "nop", "", "ret"]);
+
+        for token in tokens {
+            let (Data::Identifier(id) | Data::Label(id)) = &token.data else {
+                continue;
+            };
+            let line = &lines[token.ctx.line as usize - 1];
+            assert!(line.contains(id.as_str()), "{id} is not in line {line:?}");
+        }
+    }
 }
@@ -13,15 +13,16 @@ pub mod register_names;
 mod text;
 pub mod token;
 
-use std::{iter::Peekable, mem};
+use std::{fs, iter::Peekable, mem, path::Path, rc::Rc};
 
 use self::{lexer::Lexer, token::Token};
 use crate::{
-    instruction::{FloatInstruction, Instruction},
+    instruction::{AtomicInstruction, FloatInstruction, Instruction},
     parser::{error::Contextualize, register_names::RegNames},
 };
 use error::{Error, ParserError};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use owo_colors::OwoColorize;
 pub use preprocessor::Preprocess;
 
 /// Represents a successful parser result. This is the same format the simulator
@@ -30,11 +31,56 @@ pub struct Parsed {
     pub code: Vec<Instruction>,
     pub code_ctx: Vec<token::Context>,
     pub data: Vec<u8>,
+    /// How many bytes at the start of `data` the program declared. The rest is padding.
+    pub data_len: usize,
     pub globl: Option<usize>,
+    /// Segment and address of each label. Text and data addresses overlap, since both segments
+    /// start at 0. Numeric local labels, like `1:`, aren't included.
+    pub labels: HashMap<String, (Segment, usize)>,
+}
+
+impl Parsed {
+    /// Each instruction along with where it is in the source, in order. The exit sequence added
+    /// after the last instruction isn't in the source, so it's not included.
+    pub fn instructions_with_context(
+        &self,
+    ) -> impl Iterator<Item = (&Instruction, &token::Context)> {
+        self.code.iter().zip(&self.code_ctx)
+    }
 }
 
 pub type ParseResult = Result<Parsed, Error>;
 
+/// `.text` can't move the code to this address or past it. The text segment starts at 0, so
+/// RARS's default of `.text 0x00400000` would pad the program with a million `nop`s. This is as
+/// far as a `jal` at address 0 reaches.
+const TEXT_SEGMENT_SIZE: usize = 0x10_0000;
+
+/// Where RARS puts the text and data segments by default. FPGRARS's segments start at 0, so
+/// addresses after `.text` or `.data` from these on are taken relative to them, and programs
+/// written for RARS, with `.data 0x10010000`, start at the beginning of the segment like they do
+/// there.
+const RARS_TEXT_BASE: u32 = 0x0040_0000;
+const RARS_DATA_BASE: u32 = 0x1001_0000;
+
+/// Directives emitted by compilers that don't affect how FPGRARS runs the program. They're
+/// accepted and ignored together with their operands, so compiler output can be assembled as is.
+const IGNORED_DIRECTIVES: &[&str] = &[
+    "option",
+    "attribute",
+    "size",
+    "type",
+    "file",
+    "ident",
+    "loc",
+    "addrsig",
+    "addrsig_sym",
+];
+
+fn is_ignored_directive(d: &str) -> bool {
+    IGNORED_DIRECTIVES.contains(&d) || d.starts_with("cfi_")
+}
+
 /// The "current" parser directive
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Segment {
@@ -43,11 +89,25 @@ pub enum Segment {
     Data,
 }
 
+impl Segment {
+    /// Maps the name of a section, like `.text` or `.rodata.str1.1`, to the segment it goes in.
+    /// `.bss` goes in the data segment, which is zero-filled anyway.
+    pub fn from_section(name: &str) -> Option<Segment> {
+        let name = name.trim_start_matches('.');
+        match name.split('.').next().unwrap_or(name) {
+            "text" | "init" | "fini" => Some(Segment::Text),
+            "data" | "sdata" | "rodata" | "srodata" | "bss" | "sbss" => Some(Segment::Data),
+            _ => None,
+        }
+    }
+}
+
 type Label = String;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum LabelUse {
-    Code(usize, token::Context),
+    /// Index of the instruction and the relocation applied to the label, if any
+    Code(usize, Option<token::Relocation>, token::Context),
     Data(usize, data::Type, token::Context),
     Globl(token::Context),
 }
@@ -55,12 +115,33 @@ pub enum LabelUse {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LabelUseType {
     Code,
+    /// Like `Code`, but with a relocation operator, like `%hi(label)`
+    Relocated(token::Relocation),
     Data,
     Globl,
 }
 
+/// Options that change how strict the parser is
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    /// Report immediates that don't fit in their instruction's field, like RARS does
+    pub imm_range_check: bool,
+    /// Warn about branches and jumps that are too far for the immediate of the real instruction
+    pub hw_range_check: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            imm_range_check: true,
+            hw_range_check: false,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ParserContext {
+    pub options: ParseOptions,
     /// Text segment
     pub code: Vec<Instruction>,
     /// Context for each instruction, for debugging purposes
@@ -72,8 +153,11 @@ pub struct ParserContext {
     /// Labels that have been defined in .data, but we don't yet know the address of because of
     /// alignment
     pub data_label_backlog: Vec<Label>,
+    /// The last data directive, like `.word`, while no values have been given to it yet
+    pub empty_data_directive: Option<(String, token::Context)>,
     pub segment: Segment,
-    pub labels: HashMap<Label, usize>,
+    /// Segment and address of the labels defined so far
+    pub labels: HashMap<Label, (Segment, usize)>,
     /// This parser only makes one pass over the tokens. This means that some instructions will
     /// reference labels which have not yet been defined. When this happens, we store the position
     /// of the instruction or data in the backlog, so when the label is defined we can go back and
@@ -81,17 +165,97 @@ pub struct ParserContext {
     pub backlog: HashMap<Label, Vec<LabelUse>>,
     pub regnames: RegNames,
     pub globl: Option<usize>,
+    /// RV64 instructions we already warned about, so each warning is only shown once
+    pub warned_rv64: HashSet<String>,
+    /// How many float literals didn't fit in an `f32`. Each one is warned about.
+    pub lossy_floats: usize,
+    /// How many times each numeric local label (like `1:`) was defined so far. Each definition is
+    /// a different label, so `1b` and `1f` can refer to the previous and the next one.
+    pub numeric_labels: HashMap<u32, usize>,
+    /// Indices of the `call`s and `tail`s. Real assemblers expand them to `auipc` + `jalr`, so
+    /// they reach any address.
+    pub far_jumps: HashSet<usize>,
+}
+
+/// Name of the `index`-th definition of the numeric label `number` in `ParserContext::labels`.
+/// Other labels can't start with a digit, so they don't clash.
+fn numeric_label_name(number: u32, index: usize) -> Label {
+    format!("{}:{}", number, index)
 }
 
 impl ParserContext {
+    /// Whether the identifier `id` in the data segment is probably an instruction, because `.text`
+    /// is missing. Jump tables use labels named like mnemonics (`.word b, j, add`), so it has to
+    /// be a mnemonic that isn't a known label, followed by a register in the same line.
+    fn looks_like_instruction(
+        &self,
+        id: &str,
+        id_ctx: &token::Context,
+        next: Option<&Result<token::Token, Error>>,
+    ) -> bool {
+        let Some(Ok(token::Token {
+            data: token::Data::Identifier(operand),
+            ctx,
+            ..
+        })) = next
+        else {
+            return false;
+        };
+
+        let is_register = register_names::find(&self.regnames.regs, operand).is_some()
+            || register_names::find(&self.regnames.floats, operand).is_some();
+        is_register
+            && ctx.line == id_ctx.line
+            && Rc::ptr_eq(&ctx.file, &id_ctx.file)
+            && !self.labels.contains_key(id)
+            && !self.backlog.contains_key(id)
+            && !self.data_label_backlog.iter().any(|label| label == id)
+            && text::is_instruction(id)
+    }
+
+    /// Continues the code at `address`, filling the gap with `nop`s. Code can't go back to an
+    /// address that was already used.
+    fn set_text_address(&mut self, address: u32, ctx: &token::Context) -> Result<(), Error> {
+        let offset = address.checked_sub(RARS_TEXT_BASE).unwrap_or(address) as usize;
+        let (min, max) = (4 * self.code.len(), TEXT_SEGMENT_SIZE);
+        if !offset.is_multiple_of(4) || !(min..max).contains(&offset) {
+            let err = ParserError::InvalidTextAddress { address, min, max };
+            return Err(err.with_context(ctx.clone()));
+        }
+
+        let len = offset / 4;
+        self.code.resize_with(len, || Instruction::Addi(0, 0, 0));
+        self.code_ctx.resize(len, ctx.clone());
+        Ok(())
+    }
+
+    /// Continues the data at `address`, filling the gap with zeros. Like with the code, data can't
+    /// go back.
+    fn set_data_address(
+        &mut self,
+        address: u32,
+        segment_size: usize,
+        ctx: token::Context,
+    ) -> Result<(), Error> {
+        let offset = address.checked_sub(RARS_DATA_BASE).unwrap_or(address) as usize;
+        let (min, max) = (self.data.len(), segment_size);
+        if !(min..=max).contains(&offset) {
+            return Err(ParserError::InvalidDataAddress { address, min, max }.with_context(ctx));
+        }
+
+        self.data.resize(offset, 0);
+        Ok(())
+    }
+
     /// Returns the address of `label`. If the label hasn't been defined yet, we return zero and
     /// add it to the backlog.
     pub fn use_label(&mut self, label: &str, use_type: LabelUseType, ctx: token::Context) -> u32 {
         match self.labels.get(label) {
-            Some(&pos) => pos as u32,
+            Some(&(_, pos)) => pos as u32,
             None => {
                 let entry = match use_type {
-                    LabelUseType::Code => LabelUse::Code(self.code.len(), ctx),
+                    LabelUseType::Code => LabelUse::Code(self.code.len(), None, ctx),
+                    LabelUseType::Relocated(r) => LabelUse::Code(self.code.len(), Some(r), ctx),
                     LabelUseType::Data => LabelUse::Data(self.data.len(), self.data_type, ctx),
                     LabelUseType::Globl => LabelUse::Globl(ctx),
                 };
@@ -106,14 +270,17 @@ impl ParserContext {
 
     /// When a label is defined, we should call this function to clear the backlog entries related
     /// to it.
-    pub fn define_label(&mut self, label: impl Into<Label>, value: usize) {
+    pub fn define_label(&mut self, label: impl Into<Label>, segment: Segment, value: usize) {
         let label = label.into();
         let backlog = self.backlog.remove(&label);
-        self.labels.insert(label, value);
+        self.labels.insert(label, (segment, value));
 
         for use_ in backlog.unwrap_or_default() {
             match use_ {
-                LabelUse::Code(i, _) => text::unlabel(&mut self.code, i, value),
+                LabelUse::Code(i, None, _) => text::unlabel(&mut self.code, i, value),
+                LabelUse::Code(i, Some(r), _) => {
+                    text::unlabel(&mut self.code, i, r.apply(value as u32) as usize)
+                }
                 LabelUse::Data(i, t, _) => data::unlabel(&mut self.data, i, t, value as u32),
                 LabelUse::Globl(_) => {
                     self.globl = Some(value);
@@ -122,13 +289,49 @@ impl ParserContext {
         }
     }
 
+    /// Returns the label defined by the next occurrence of `number:`
+    pub fn define_numeric_label(&mut self, number: u32) -> Label {
+        let count = self.numeric_labels.entry(number).or_default();
+        *count += 1;
+        numeric_label_name(number, *count - 1)
+    }
+
+    /// Returns the label `1f` or `1b` refers to. `1b` must come after a `1:`, while `1f` might be
+    /// defined later, like any other label.
+    pub fn numeric_label_ref(
+        &self,
+        number: u32,
+        forward: bool,
+        ctx: &token::Context,
+    ) -> Result<Label, Error> {
+        let count = self.numeric_labels.get(&number).copied().unwrap_or(0);
+        if forward {
+            return Ok(numeric_label_name(number, count));
+        }
+        match count.checked_sub(1) {
+            Some(index) => Ok(numeric_label_name(number, index)),
+            None => Err(ParserError::UndefinedLabels(vec![ctx.clone()]).into()),
+        }
+    }
+
+    /// Errors if the last data directive was left without values, like in `.word` followed by
+    /// `.text`. Called whenever a directive could end the values of the previous one.
+    pub fn check_empty_data_directive(&mut self) -> Result<(), Error> {
+        match self.empty_data_directive.take() {
+            Some((directive, ctx)) => {
+                Err(ParserError::EmptyDataDirective(directive).with_context(ctx))
+            }
+            None => Ok(()),
+        }
+    }
+
     /// Defines the address of labels in the data segment with the address self.data.len(). This
     /// should be called after we are sure of the labels alignment
     pub fn commit_data_label_backlog(&mut self) {
         let addr = self.data.len();
         let backlog = mem::take(&mut self.data_label_backlog);
         for label in backlog {
-            self.define_label(label, addr);
+            self.define_label(label, Segment::Data, addr);
         }
     }
 }
@@ -139,20 +342,54 @@ impl ParserContext {
 /// fpgrars::parser::parse("riscv.s", 0x1000).is_ok();
 /// ```
 pub fn parse(entry_file: &str, data_segment_size: usize) -> ParseResult {
+    parse_with_options(
+        entry_file,
+        vec![0; data_segment_size],
+        ParseOptions::default(),
+    )
+}
+
+/// Same as [`parse`], but with custom [`ParseOptions`]. The data segment is written over `data`,
+/// which keeps its length, so the simulator's memory can be reused instead of allocating a new one.
+pub fn parse_with_options(entry_file: &str, data: Vec<u8>, options: ParseOptions) -> ParseResult {
     let tokens = Lexer::new(entry_file)?.preprocess().peekable();
-    parse_tokens(tokens, data_segment_size)
+    parse_tokens(tokens, data, options)
+}
+
+/// Parses a program from memory. `name` is used as the file name in error messages, and to resolve
+/// `.include`s. The data segment is written over `data`, like in [`parse_with_options`].
+pub fn parse_str_with_options(
+    content: String,
+    name: &str,
+    data: Vec<u8>,
+    options: ParseOptions,
+) -> ParseResult {
+    token::context::cache_source(name, &content);
+    let tokens = Lexer::from_content(content, name).preprocess().peekable();
+    parse_tokens(tokens, data, options)
 }
 
 pub fn parse_str(content: String, data_segment_size: usize) -> ParseResult {
-    let tokens = Lexer::from_content(content, "<no file>").preprocess().peekable();
-    parse_tokens(tokens, data_segment_size)
+    let tokens = Lexer::from_content(content, "<no file>")
+        .preprocess()
+        .peekable();
+    parse_tokens(tokens, vec![0; data_segment_size], ParseOptions::default())
 }
 
+/// Parses the tokens, writing the data segment over `data`. Its length is the size of the data
+/// segment, and bytes that aren't part of it are zeroed.
 pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
     mut tokens: Peekable<I>,
-    data_segment_size: usize,
+    mut data: Vec<u8>,
+    options: ParseOptions,
 ) -> ParseResult {
-    let mut ctx = ParserContext::default();
+    let data_segment_size = data.len();
+    data.clear();
+    let mut ctx = ParserContext {
+        options,
+        data,
+        ..Default::default()
+    };
 
     use token::Data::*;
     while let Some(token) = tokens.next() {
@@ -160,24 +397,52 @@ pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
 
         match token.data {
             Directive(d) if d == "text" => {
+                ctx.check_empty_data_directive()?;
                 ctx.segment = Segment::Text;
+                if let Some(address) = parse_segment_address(&mut tokens, &token.ctx) {
+                    ctx.set_text_address(address, &token.ctx)?;
+                }
                 continue;
             }
             Directive(d) if d == "data" => {
+                ctx.check_empty_data_directive()?;
                 ctx.segment = Segment::Data;
+                if let Some(address) = parse_segment_address(&mut tokens, &token.ctx) {
+                    ctx.set_data_address(address, data_segment_size, token.ctx)?;
+                }
                 continue;
             }
             Directive(d) if d == "globl" || d == "global" => {
                 parse_globl(&mut tokens, &mut ctx, token.ctx)?;
                 continue;
             }
+            Directive(d) if d == "extern" => {
+                parse_extern(&mut tokens, &mut ctx, token.ctx)?;
+                continue;
+            }
+            Directive(d) if d == "section" => {
+                ctx.check_empty_data_directive()?;
+                ctx.segment = parse_section(&mut tokens, token.ctx)?;
+                continue;
+            }
+            Directive(d) if is_ignored_directive(&d) => {
+                skip_line(&mut tokens, &token.ctx);
+                continue;
+            }
             _ => {}
         }
 
         match ctx.segment {
             Segment::Text => match token.data {
-                Label(label) => ctx.define_label(label, 4 * ctx.code.len()),
+                Label(label) => ctx.define_label(label, Segment::Text, 4 * ctx.code.len()),
+                NumericLabel(number) => {
+                    let label = ctx.define_numeric_label(number);
+                    ctx.define_label(label, Segment::Text, 4 * ctx.code.len())
+                }
                 Identifier(id) => text::parse_instruction(&mut tokens, &mut ctx, id, token.ctx)?,
+                Directive(d) if d.parse::<data::Type>().is_ok() || d == "incbin" => {
+                    return Err(ParserError::DataInTextSegment(d).with_context(token.ctx))
+                }
                 Directive(d) => {
                     return Err(ParserError::UnknownDirective(d).with_context(token.ctx))
                 }
@@ -189,10 +454,33 @@ pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
             },
             Segment::Data => match token.data {
                 Label(label) => ctx.data_label_backlog.push(label),
+                NumericLabel(number) => {
+                    let label = ctx.define_numeric_label(number);
+                    ctx.data_label_backlog.push(label)
+                }
                 Directive(d) if d.parse::<data::Type>().is_ok() => {
+                    ctx.check_empty_data_directive()?;
                     ctx.data_type = d.parse().unwrap();
+                    ctx.empty_data_directive = Some((d, token.ctx));
                 }
-                Identifier(_) | CharLiteral(_) | StringLiteral(_) | Integer(_) | Float(_) => {
+                Directive(d) if d == "incbin" => {
+                    ctx.check_empty_data_directive()?;
+                    parse_incbin(&mut tokens, &mut ctx, token.ctx)?;
+                }
+                // Forgetting a `.text` is a common mistake, and storing the instruction as a label
+                // address would make it hard to find
+                Identifier(id) if ctx.looks_like_instruction(&id, &token.ctx, tokens.peek()) => {
+                    return Err(ParserError::InstructionInDataSegment(id).with_context(token.ctx))
+                }
+                // Commas are whitespace to the lexer, so trailing commas and empty items between
+                // commas (like in `.word 1,,2,`) never get here
+                Identifier(_)
+                | NumericLabelRef { .. }
+                | CharLiteral(_)
+                | StringLiteral(_)
+                | Integer(_)
+                | Float(_) => {
+                    ctx.empty_data_directive = None;
                     data::push_data(token, &mut ctx)?
                 }
 
@@ -208,6 +496,8 @@ pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
         }
     }
 
+    ctx.check_empty_data_directive()?;
+
     // Commit labels that were defined without any data, in the end of the backlog, to the position
     // of the end of the data segment
     ctx.commit_data_label_backlog();
@@ -220,7 +510,7 @@ pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
             .flat_map(|(_label, uses)| {
                 // extract contexts
                 uses.iter().map(|u| match u {
-                    LabelUse::Code(_, c) => c.clone(),
+                    LabelUse::Code(_, _, c) => c.clone(),
                     LabelUse::Data(_, _, c) => c.clone(),
                     LabelUse::Globl(c) => c.clone(),
                 })
@@ -230,6 +520,11 @@ pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
         return Err(ParserError::UndefinedLabels(ctxs).into());
     }
 
+    check_jump_targets(&ctx)?;
+    if ctx.options.hw_range_check {
+        warn_hw_ranges(&ctx);
+    }
+
     // If the program ever drops off bottom, we make an "exit" ecall and terminate execution
     ctx.code.extend(vec![
         Instruction::Li(17, 10), // li a7 10
@@ -237,42 +532,751 @@ pub fn parse_tokens<I: Iterator<Item = Result<Token, Error>>>(
         Instruction::Ecall,
     ]);
 
+    let data_len = ctx.data.len().min(data_segment_size);
     ctx.data.resize(data_segment_size, 0);
 
+    let labels = ctx
+        .labels
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with(|c: char| c.is_ascii_digit()))
+        .collect();
+
     Ok(Parsed {
         code: ctx.code,
         code_ctx: ctx.code_ctx,
         data: ctx.data,
+        data_len,
         globl: ctx.globl,
+        labels,
     })
 }
 
-/// Parser a .globl directive
+/// Makes sure every branch and `jal` lands on an instruction. Labels always do, but numeric
+/// targets may be misaligned or outside the code. Jumping right past the last instruction is fine,
+/// since that's where the exit sequence is put.
+fn check_jump_targets(ctx: &ParserContext) -> Result<(), Error> {
+    use Instruction::*;
+    let code_end = 4 * ctx.code.len();
+
+    for (instruction, instr_ctx) in ctx.code.iter().zip(&ctx.code_ctx) {
+        let target = match *instruction {
+            Beq(_, _, t)
+            | Bne(_, _, t)
+            | Blt(_, _, t)
+            | Bge(_, _, t)
+            | Bltu(_, _, t)
+            | Bgeu(_, _, t)
+            | Jal(_, t) => t,
+            _ => continue,
+        };
+
+        if target % 4 != 0 || target > code_end {
+            return Err(
+                ParserError::InvalidJumpTarget(target, code_end).with_context(instr_ctx.clone())
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Branches and `jal`s whose offset doesn't fit in the immediate of the real instruction, which
+/// has 13 bits (±4KiB) for branches and 21 bits (±1MiB) for `jal`. FPGRARS stores the whole target,
+/// so they work here, but not in hardware. Returns the index, offset and immediate width of each.
+fn hw_range_violations(ctx: &ParserContext) -> Vec<(usize, i64, u32)> {
+    use Instruction::*;
+
+    let mut violations = Vec::new();
+    for (i, instruction) in ctx.code.iter().enumerate() {
+        let (target, bits) = match *instruction {
+            Beq(_, _, t)
+            | Bne(_, _, t)
+            | Blt(_, _, t)
+            | Bge(_, _, t)
+            | Bltu(_, _, t)
+            | Bgeu(_, _, t) => (t, 13),
+            Jal(_, t) if !ctx.far_jumps.contains(&i) => (t, 21),
+            _ => continue,
+        };
+
+        let offset = target as i64 - 4 * i as i64;
+        let limit = 1i64 << (bits - 1);
+        if !(-limit..limit).contains(&offset) {
+            violations.push((i, offset, bits));
+        }
+    }
+    violations
+}
+
+fn warn_hw_ranges(ctx: &ParserContext) {
+    for (i, offset, bits) in hw_range_violations(ctx) {
+        eprintln!(
+            "   {} This jumps {} bytes away, but the real instruction only reaches {} bytes\n{}",
+            "[warning]".bright_yellow(),
+            offset.bright_yellow(),
+            format!("±{}", 1 << (bits - 1)).bright_blue(),
+            ctx.code_ctx[i]
+        );
+    }
+}
+
+/// Parses the operands of `.globl` and `.extern`: a label, optionally followed by a size in the
+/// same line, like `.extern buffer, 16`
+fn parse_symbol_and_size(
+    tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
+    directive_ctx: token::Context,
+) -> Result<(Label, token::Context, Option<usize>), Error> {
+    let label = tokens
+        .next()
+        .ok_or_else(|| ParserError::UnexpectedToken(None).with_context(directive_ctx))??;
+
+    let name = match label.data {
+        token::Data::Identifier(name) => name,
+        _ => return Err(ParserError::UnexpectedToken(Some(label.data)).with_context(label.ctx)),
+    };
+
+    let size = match tokens.peek() {
+        Some(Ok(token)) if token.ctx.line == label.ctx.line && token.ctx.file == label.ctx.file => {
+            match token.data {
+                token::Data::Integer(size) if size >= 0 => Some(size as usize),
+                token::Data::Integer(_) => {
+                    let token = tokens.next().unwrap()?;
+                    return Err(
+                        ParserError::UnexpectedToken(Some(token.data)).with_context(token.ctx)
+                    );
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    if size.is_some() {
+        tokens.next();
+    }
+
+    Ok((name, label.ctx, size))
+}
+
+/// Parser a .globl directive. A size after the label is accepted, but ignored.
 fn parse_globl(
     tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
     parser: &mut ParserContext,
     globl_ctx: token::Context,
 ) -> Result<(), Error> {
-    let label = tokens
+    let (label, _, _) = parse_symbol_and_size(tokens, globl_ctx.clone())?;
+    let addr = parser.use_label(&label, LabelUseType::Globl, globl_ctx) as usize;
+    parser.globl = Some(addr);
+    Ok(())
+}
+
+/// Parses an `.extern <label>, <size>` directive, which reserves `size` zeroed bytes in the data
+/// segment for the label. Without a size, it only declares a label that's defined elsewhere.
+fn parse_extern(
+    tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
+    parser: &mut ParserContext,
+    extern_ctx: token::Context,
+) -> Result<(), Error> {
+    let (label, _, size) = parse_symbol_and_size(tokens, extern_ctx)?;
+    if let Some(size) = size {
+        let addr = parser.data.len().next_multiple_of(4);
+        parser.data.resize(addr + size, 0);
+        parser.define_label(label, Segment::Data, addr);
+    }
+    Ok(())
+}
+
+/// Parses an `.incbin "file"` directive, which appends the bytes of the file to the data segment,
+/// aligned to a word boundary. Like `.include`, the path is relative to the file that has the
+/// directive.
+fn parse_incbin(
+    tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
+    parser: &mut ParserContext,
+    incbin_ctx: token::Context,
+) -> Result<(), Error> {
+    let path = match tokens.next().transpose()?.map(|t| t.data) {
+        Some(token::Data::StringLiteral(path)) => path,
+        other => {
+            return Err(ParserError::ExpectedIncbinPath(other)
+                .with_context(incbin_ctx)
+                .with_tip(format!(
+                    "The correct usage is {}",
+                    ".incbin \"sprite.bin\"".bright_blue()
+                )))
+        }
+    };
+
+    let path = Path::new(incbin_ctx.file.as_str())
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(path);
+    let bytes = fs::read(&path).map_err(|err| {
+        ParserError::IncbinFailed {
+            path: path.display().to_string(),
+            err,
+        }
+        .with_context(incbin_ctx)
+    })?;
+
+    data::push_bytes(parser, &bytes);
+    Ok(())
+}
+
+/// Parses `.section <name>[, flags...]`, returning the segment the section maps to. The flags
+/// that follow the name are ignored.
+fn parse_section(
+    tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
+    section_ctx: token::Context,
+) -> Result<Segment, Error> {
+    let name = tokens
         .next()
-        .ok_or_else(|| ParserError::UnexpectedToken(None).with_context(globl_ctx.clone()))??;
+        .ok_or_else(|| ParserError::UnexpectedToken(None).with_context(section_ctx.clone()))??;
+
+    let segment = match &name.data {
+        token::Data::Directive(s) => Segment::from_section(s),
+        token::Data::Identifier(s) | token::Data::StringLiteral(s) => Segment::from_section(s),
+        _ => {
+            return Err(ParserError::UnexpectedToken(Some(name.data)).with_context(name.ctx));
+        }
+    };
+
+    let segment = segment.ok_or_else(|| {
+        ParserError::UnknownSection(name.data.to_string()).with_context(name.ctx.clone())
+    })?;
+
+    skip_line(tokens, &name.ctx);
+    Ok(segment)
+}
+
+/// Parses the address after `.text` or `.data`, like the one in `.data 0x100`, if there's one in
+/// the same line as the directive at `ctx`
+fn parse_segment_address(
+    tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
+    ctx: &token::Context,
+) -> Option<u32> {
+    let address = match tokens.peek() {
+        Some(Ok(token::Token {
+            data: token::Data::Integer(address),
+            ctx: address_ctx,
+            ..
+        })) if address_ctx.line == ctx.line && address_ctx.file == ctx.file => *address as u32,
+        _ => return None,
+    };
+    tokens.next();
+    Some(address)
+}
 
-    match label.data {
-        token::Data::Identifier(label) => {
-            let addr = parser.use_label(&label, LabelUseType::Globl, globl_ctx) as usize;
-            parser.globl = Some(addr);
-            Ok(())
+/// Skips the tokens that are in the same line as `ctx`. Lexer errors are skipped too, because
+/// the operands of ignored directives (like `.size main, .-main`) aren't always valid for us.
+fn skip_line(
+    tokens: &mut Peekable<impl Iterator<Item = Result<token::Token, Error>>>,
+    ctx: &token::Context,
+) {
+    let same_line = |other: &token::Context| other.line == ctx.line && other.file == ctx.file;
+    while let Some(next) = tokens.peek() {
+        let next_ctx = match next {
+            Ok(token) => &token.ctx,
+            Err(Error::WithContext { ctx, .. }) => ctx,
+            Err(_) => break,
+        };
+        if !same_line(next_ctx) {
+            break;
         }
-        _ => Err(ParserError::UnexpectedToken(Some(label.data)).with_context(label.ctx)),
+        tokens.next();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use error::LexerError;
     use proptest::prelude::*;
 
+    #[test]
+    fn test_numeric_labels() {
+        let input = "
+            li t0 3
+            1: addi t0 t0 -1
+            bnez t0 1b
+            j 1f
+            1: nop
+            1:
+            j 1b
+            .data
+            1: .word 1b 1f
+            1: .word 2
+            ";
+        let Parsed { code, data, .. } = parse_str(input.to_owned(), 0x100).unwrap();
+
+        use Instruction::*;
+        assert_eq!(
+            &code[..6],
+            &[
+                Li(5, 3),
+                Addi(5, 5, -1i32 as u32),
+                Bne(5, 0, 4),
+                Jal(0, 16),
+                Addi(0, 0, 0),
+                Jal(0, 20),
+            ]
+        );
+        assert_eq!(&data[..12], &[0, 0, 0, 0, 8, 0, 0, 0, 2, 0, 0, 0]);
+
+        assert!(parse_str(
+            "j 1b
+ 1: nop"
+                .to_owned(),
+            0x100
+        )
+        .is_err());
+        assert!(parse_str(
+            "1: nop
+ j 1f"
+                .to_owned(),
+            0x100
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_hw_range_violations() {
+        use Instruction::*;
+        let violations = |code: Vec<Instruction>, far_jumps: &[usize]| {
+            let ctx = ParserContext {
+                code,
+                far_jumps: far_jumps.iter().copied().collect(),
+                ..Default::default()
+            };
+            hw_range_violations(&ctx)
+        };
+        let at = |pc: usize, instruction: Instruction| {
+            let mut code = Vec::new();
+            code.resize_with(pc / 4, || Addi(0, 0, 0));
+            code.push(instruction);
+            code
+        };
+
+        // Branches reach from -4096 to 4094
+        assert!(violations(at(0, Beq(0, 0, 4092)), &[]).is_empty());
+        assert_eq!(violations(at(0, Bne(0, 0, 4096)), &[]), [(0, 4096, 13)]);
+        assert!(violations(at(4096, Blt(0, 0, 0)), &[]).is_empty());
+        assert_eq!(
+            violations(at(4100, Bgeu(0, 0, 0)), &[]),
+            [(1025, -4100, 13)]
+        );
+
+        // Jumps reach from -1MiB to 1MiB - 2
+        assert!(violations(at(0, Jal(0, (1 << 20) - 4)), &[]).is_empty());
+        assert_eq!(violations(at(0, Jal(1, 1 << 20)), &[]), [(0, 1 << 20, 21)]);
+        assert!(violations(at(1 << 20, Jal(0, 0)), &[]).is_empty());
+        assert_eq!(
+            violations(at((1 << 20) + 4, Jal(0, 0)), &[]),
+            [((1 << 18) + 1, -(1 << 20) - 4, 21)]
+        );
+
+        // `call` and `tail` reach anywhere
+        assert!(violations(at(0, Jal(1, 1 << 20)), &[0]).is_empty());
+    }
+
+    #[test]
+    fn test_incbin() {
+        let dir = crate::temp_path("incbin");
+        fs::create_dir(&dir).unwrap();
+        let bytes = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x7f, 0x0a];
+        fs::write(dir.join("sprite.bin"), bytes).unwrap();
+
+        let input = ".data
+            .byte 1
+            sprite: .incbin \"sprite.bin\"
+            .byte 2
+            .text
+            la t0 sprite";
+        let name = dir.join("main.s");
+        let Parsed { code, data, .. } = parse_str_with_options(
+            input.to_owned(),
+            name.to_str().unwrap(),
+            vec![0; 0x100],
+            ParseOptions::default(),
+        )
+        .unwrap();
+
+        // The bytes are word-aligned, like a `.word`
+        assert_eq!(&data[0..4], &[1, 0, 0, 0]);
+        assert_eq!(&data[4..11], &bytes);
+        assert_eq!(data[11], 2);
+        assert_eq!(code[0], Instruction::Li(5, 4));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let missing = parse_str(".data\n.incbin \"fpgrars_missing.bin\"".to_owned(), 0x100);
+        assert!(missing.is_err());
+        assert!(parse_str(".data\n.incbin 42".to_owned(), 0x100).is_err());
+        assert!(parse_str(".incbin \"a.bin\"".to_owned(), 0x100).is_err());
+    }
+
+    #[test]
+    fn test_invalid_jump_targets() {
+        assert!(parse_str("j 0x1000".to_owned(), 0x100).is_err());
+        assert!(parse_str("nop\n beqz t0 6".to_owned(), 0x100).is_err());
+        assert!(parse_str("nop\n j 4\n j 0".to_owned(), 0x100).is_ok());
+        assert!(parse_str("j end\n nop\n end:".to_owned(), 0x100).is_ok());
+
+        match parse_str("nop\n jal 0x10000".to_owned(), 0x100) {
+            Err(Error::WithContext { err, ctx }) => {
+                assert!(matches!(
+                    *err,
+                    Error::Parser(ParserError::InvalidJumpTarget(0x10000, 8))
+                ));
+                assert_eq!(ctx.line, 2);
+            }
+            _ => panic!("expected an invalid jump target error"),
+        }
+    }
+
+    #[test]
+    fn test_instructions_with_context() {
+        let code = "
+            .data
+            value: .word 7
+            .text
+            main:
+                la t0 value
+            1:  lw t1 0(t0)
+                bnez t1 1b
+            end: nop
+        ";
+        let parsed = parse_str(code.to_owned(), 0x100).unwrap();
+
+        use Instruction::*;
+        let lines: Vec<_> = parsed
+            .instructions_with_context()
+            .map(|(instruction, ctx)| (instruction, ctx.line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (&Li(5, 0), 6),
+                (&Lw(6, 0, 5), 7),
+                (&Bne(6, 0, 4), 8),
+                (&Addi(0, 0, 0), 9)
+            ]
+        );
+
+        let mut labels: Vec<_> = parsed
+            .labels
+            .iter()
+            .map(|(k, &v)| (k.as_str(), v))
+            .collect();
+        labels.sort_by_key(|&(name, _)| name);
+        assert_eq!(
+            labels,
+            [
+                ("end", (Segment::Text, 12)),
+                ("main", (Segment::Text, 0)),
+                ("value", (Segment::Data, 0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_globl_and_extern() {
+        let parsed = parse_str("nop\n main: nop\n .globl main".to_owned(), 0x100).unwrap();
+        assert_eq!(parsed.globl, Some(4));
+
+        // A trailing size on .globl is ignored, and doesn't eat the next line
+        let code = ".globl main, 4\n nop\n main: nop";
+        let parsed = parse_str(code.to_owned(), 0x100).unwrap();
+        assert_eq!(parsed.globl, Some(4));
+
+        let code = "
+            .data
+            before: .byte 1
+            .extern buf, 16
+            after: .word 2
+            .text
+            la a0, buf
+            la a1, after
+        ";
+        let parsed = parse_str(code.to_owned(), 0x100).unwrap();
+        assert_eq!(parsed.data[..4], [1, 0, 0, 0]);
+        assert!(parsed.data[4..20].iter().all(|&b| b == 0));
+        assert_eq!(parsed.data[20..24], [2, 0, 0, 0]);
+
+        use Instruction::*;
+        assert_eq!(parsed.code[..2], [Li(10, 4), Li(11, 20)]);
+
+        assert!(parse_str(".extern buf, -4".to_owned(), 0x100).is_err());
+    }
+
+    #[test]
+    fn test_rv64_aliases() {
+        use Instruction::*;
+        let parsed = parse_str(
+            "sext.w t0, t1\n negw t2, t3\n negw t4, t5".to_owned(),
+            0x100,
+        );
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.code[..3], [Mv(5, 6), Sub(7, 0, 28), Sub(29, 0, 30)]);
+    }
+
+    #[test]
+    fn test_section() {
+        let code = "
+            .section .rodata
+            five: .word 5
+            .section .text.startup,\"ax\",@progbits
+            lw a0, five
+            .section .bss
+            buf: .space 4
+            .section .sdata
+            .word 6
+        ";
+        let parsed = parse_str(code.to_owned(), 0x100).unwrap();
+        // `lw` with a label expands to two instructions, then comes the exit sequence
+        assert_eq!(parsed.code.len(), 2 + 3);
+        assert_eq!(parsed.data[..12], [5, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0]);
+
+        let err = parse_str(".section .debug_info".to_owned(), 0x100);
+        assert!(matches!(err, Err(Error::WithContext { err, .. })
+            if matches!(*err, Error::Parser(ParserError::UnknownSection(ref s)) if s == ".debug_info")));
+    }
+
+    #[test]
+    fn test_wrong_segment() {
+        let parse = |code: &str| match parse_str(code.to_owned(), 0x100) {
+            Err(Error::WithContext { err, .. }) => Some(*err),
+            _ => None,
+        };
+
+        let err = parse(".data\n x: .word 1\n main: add t0, t1, t2");
+        assert!(
+            matches!(err, Some(Error::Parser(ParserError::InstructionInDataSegment(ref s))) if s == "add")
+        );
+        assert!(err.unwrap().to_string().contains(".text"));
+
+        let err = parse("nop\n .word 1");
+        assert!(
+            matches!(err, Some(Error::Parser(ParserError::DataInTextSegment(ref s))) if s == "word")
+        );
+
+        // Labels are still fine as data
+        assert!(parse_str(
+            ".data\n x: .word main\n .text\n main: nop".to_owned(),
+            0x100
+        )
+        .is_ok());
+
+        // Even when they're named like instructions, as in jump tables
+        for code in [
+            ".data\n table: .word b, j, add\n .text\n b: nop\n j: nop\n add: nop",
+            ".data\n table: .word ret\n .text\n ret: nop",
+        ] {
+            assert!(parse(code).is_none(), "{code}");
+        }
+    }
+
+    #[test]
+    fn test_segment_addresses() {
+        let code = "
+            .data 0x100
+            x: .word 7
+            .text 0x10
+            main: la t0, x
+            .data
+            y: .byte 1
+        ";
+        let parsed = parse_str(code.to_owned(), 0x200).unwrap();
+        // 4 `nop`s before `main`, then the exit sequence
+        assert_eq!(parsed.code.len(), 4 + 1 + 3);
+        assert_eq!(parsed.code[0], Instruction::Addi(0, 0, 0));
+        assert_eq!(parsed.code[4], Instruction::Li(5, 0x100));
+        assert_eq!(parsed.data[0x100..0x105], [7, 0, 0, 0, 1]);
+
+        let invalid_text = |code: &str| match parse_str(code.to_owned(), 0x200) {
+            Err(Error::WithContext { err, .. }) => {
+                matches!(*err, Error::Parser(ParserError::InvalidTextAddress { .. }))
+            }
+            _ => false,
+        };
+        assert!(invalid_text(".text 6"));
+        assert!(invalid_text("nop\n nop\n .text 4"));
+        assert!(!invalid_text("nop\n .text 4\n nop"));
+        assert!(!invalid_text(".text 0xFFFFC\n nop"));
+        assert!(invalid_text(".text 0x100000"));
+        assert!(invalid_text(".text 0x00400002"));
+    }
+
+    #[test]
+    fn test_rars_segment_addresses() {
+        // RARS's default addresses are the start of FPGRARS's segments
+        let code = "
+            .data 0x10010000
+            x: .word 7
+            .data 0x10010010
+            y: .word 8
+            .text 0x00400000
+            main: la t0, x
+            la t1, y
+        ";
+        let parsed = parse_str(code.to_owned(), 0x200).unwrap();
+        assert_eq!(parsed.labels["x"], (Segment::Data, 0));
+        assert_eq!(parsed.labels["y"], (Segment::Data, 0x10));
+        assert_eq!(parsed.labels["main"], (Segment::Text, 0));
+        assert_eq!(parsed.code[0], Instruction::Li(5, 0));
+        assert_eq!(parsed.code[1], Instruction::Li(6, 0x10));
+        assert_eq!(parsed.data[0x10..0x14], [8, 0, 0, 0]);
+
+        let invalid_data = |code: &str| match parse_str(code.to_owned(), 0x200) {
+            Err(Error::WithContext { err, .. }) => {
+                matches!(*err, Error::Parser(ParserError::InvalidDataAddress { .. }))
+            }
+            _ => false,
+        };
+        assert!(invalid_data(".data 0x10010201"));
+        assert!(invalid_data(".data 0x8000"));
+        assert!(invalid_data(".data\n .word 1\n .data 0x10010000"));
+    }
+
+    #[test]
+    fn test_immediate_range() {
+        let parse = |code: &str| parse_str(code.to_owned(), 0x100);
+        let out_of_range_with = |code: &str, expected: i32, expected_bits: u32| match parse(code) {
+            Err(Error::WithContext { err, .. }) => assert!(
+                matches!(*err, Error::Parser(ParserError::ImmediateOutOfRange { value, bits, .. })
+                    if value == expected && bits == expected_bits),
+                "{code}: unexpected error {err:?}"
+            ),
+            Err(e) => panic!("{code}: expected an out of range error, got {e:?}"),
+            Ok(_) => panic!("{code}: expected an out of range error"),
+        };
+        let out_of_range = |code: &str, expected: i32| out_of_range_with(code, expected, 12);
+
+        assert!(parse("addi t0, t1, 2047").is_ok());
+        assert!(parse("addi t0, t1, -2048").is_ok());
+        assert!(parse("lw t0, 2047(sp)\n sw t0, -2048(sp)").is_ok());
+        assert!(parse("flw ft0, -4(sp)\n jalr ra, 8(t0)").is_ok());
+        out_of_range("addi t0, t1, 2048", 2048);
+        out_of_range("andi t0, t1, -2049", -2049);
+        out_of_range("lw t0, 2048(sp)", 2048);
+        out_of_range("sb t0, -5000(sp)", -5000);
+        out_of_range("fsw ft0, 4096(sp)", 4096);
+        out_of_range("jalr ra, t0, 2048", 2048);
+
+        // Shift amounts are 5-bit unsigned fields
+        assert!(parse("slli t0, t0, 31\n srli t0, t0, 0\n c.srai t0, 31").is_ok());
+        out_of_range_with("slli t0, t0, 32", 32, 5);
+        out_of_range_with("srai t0, t0, 40", 40, 5);
+        out_of_range_with("srli t0, t0, -1", -1, 5);
+        out_of_range_with("c.slli t0, 32", 32, 5);
+
+        // Labels are addresses, and `lw rd, imm` is a pseudoinstruction that loads from `imm`
+        assert!(parse(".data\n.space 0x90\nx: .word 0\n.text\naddi t0, zero, x").is_ok());
+        assert!(parse("lw t0, 0x10000\n li t0, 5000").is_ok());
+
+        let options = ParseOptions {
+            imm_range_check: false,
+            ..ParseOptions::default()
+        };
+        let tokens = Lexer::from_content("addi t0, t1, 5000".to_owned(), "loose.s")
+            .preprocess()
+            .peekable();
+        assert!(parse_tokens(tokens, vec![0; 0x100], options).is_ok());
+    }
+
+    #[test]
+    fn test_oversized_integers() {
+        let parse = |code: &str| parse_str(code.to_owned(), 0x100);
+        for code in [
+            "li t0, 4294967296",
+            "li t0, -4294967296",
+            ".data\n.word 4294967296",
+        ] {
+            match parse(code) {
+                Err(Error::WithContext { err, .. }) => assert!(
+                    matches!(*err, Error::Lexer(LexerError::IntegerOutOfRange(ref s)) if s.ends_with("4294967296")),
+                    "{code}: unexpected error {err:?}"
+                ),
+                Err(e) => panic!("{code}: expected an out of range error, got {e:?}"),
+                Ok(_) => panic!("{code}: expected an out of range error"),
+            }
+        }
+
+        // They're fine as floats
+        let parsed = parse(".data\n.float 10000000000").unwrap();
+        assert_eq!(parsed.data[..4], 1e10f32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_hi_lo_relocations() {
+        let code = "
+            .data
+            .space 0x17fc
+            before: .word 0
+            .text
+            lui a0, %hi(before)
+            addi a0, a0, %lo(before)
+            lui a1, %hi(after)
+            lw a1, %lo(after)(a1)
+            lui a2, %hi(0x12345fff)
+            addi a2, a2, %lo(0x12345fff)
+            .data
+            after: .word 0
+        ";
+        let parsed = parse_str(code.to_owned(), 0x2000).unwrap();
+
+        use Instruction::*;
+        let pairs = parsed.code[..6].chunks(2).map(|pair| match pair {
+            [Lui(_, hi), Addi(_, _, lo) | Lw(_, lo, _)] => (hi << 12).wrapping_add(*lo),
+            _ => panic!("unexpected instructions {pair:?}"),
+        });
+        assert_eq!(pairs.collect::<Vec<_>>(), [0x17fc, 0x1800, 0x12345fff]);
+
+        // The lower bits are sign extended, so the upper bits compensate for it
+        assert_eq!(parsed.code[2], Lui(11, 2));
+        assert_eq!(parsed.code[3], Lw(11, -0x800i32 as u32, 11));
+    }
+
     proptest! {
+        #[test]
+        fn hi_lo_reconstruct_address(addr: u32) {
+            use token::Relocation::{Hi, Lo};
+            prop_assert_eq!((Hi.apply(addr) << 12).wrapping_add(Lo.apply(addr)), addr);
+        }
+
+        #[test]
+        fn labels_resolve_before_and_after_definition(
+            // The underscore keeps them from being instructions or registers, like `j` or `a0`
+            names in prop::collection::hash_set("[a-z][a-z0-9_]{0,10}_", 1..40)
+        ) {
+            let names: Vec<_> = names.into_iter().collect();
+            let n = names.len();
+
+            // Every label is used before and after it's defined, in code and in data
+            let mut code = String::new();
+            for name in &names {
+                code += &format!("j {name}\n");
+            }
+            let mut expected = std::collections::HashMap::new();
+            for (i, name) in names.iter().enumerate() {
+                code += &format!("{name}: nop\n");
+                expected.insert(name.as_str(), 4 * (n + i));
+            }
+            for name in &names {
+                code += &format!("j {name}\n");
+            }
+            code += ".data\n";
+            for name in &names {
+                code += &format!(".word {name}\n");
+            }
+
+            let parsed = parse_str(code, 4 * n).unwrap();
+            for (i, name) in names.iter().enumerate() {
+                let target = expected[name.as_str()];
+                prop_assert_eq!(&parsed.code[i], &Instruction::Jal(0, target));
+                prop_assert_eq!(&parsed.code[2 * n + i], &Instruction::Jal(0, target));
+                let word = u32::from_le_bytes(parsed.data[4 * i..4 * i + 4].try_into().unwrap());
+                prop_assert_eq!(word as usize, target);
+            }
+        }
+
         #[test]
         fn parse_doesnt_crash(s in "\\PC*") {
             let _ = parse_str(s, 0x100);
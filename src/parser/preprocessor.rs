@@ -13,8 +13,8 @@ use super::{
 
 static MACRO_EXAMPLE_TIP: &str =
     "\x1b[1mHere's an example of a macro using arguments correctly:\x1b[0m
-   .macro Name(%arg1, %arg2)
-       add %arg1, %arg1, %arg2
+   .macro Name(%arg1, %arg2 = 1)
+       addi %arg1, %arg1, %arg2
    .end_macro";
 
 lazy_static! {
@@ -44,10 +44,32 @@ impl Preprocess for Lexer {
 struct Macro {
     name: String,
     args: HashMap<String, usize>,
+    /// Name of the last argument if it was declared like `%args...`. It takes every argument
+    /// after the fixed ones.
+    variadic: Option<String>,
+    /// Default value of each argument, used when an invocation omits it
+    defaults: Vec<Option<Token>>,
     labels_defined: HashSet<String>,
     body: Vec<Token>,
 }
 
+impl Macro {
+    /// Minimum and maximum number of arguments an invocation can have. There's no maximum if the
+    /// last argument is variadic.
+    fn arity(&self) -> (usize, Option<usize>) {
+        let min = self
+            .defaults
+            .iter()
+            .rposition(Option::is_none)
+            .map_or(0, |i| i + 1);
+        let max = match self.variadic {
+            Some(_) => None,
+            None => Some(self.args.len()),
+        };
+        (min, max)
+    }
+}
+
 /// A preprocessor for RISC-V assembly files that supports includes, macros and equs.
 /// Generally constructed by calling the [`Preprocess::preprocess`] method.
 pub struct Preprocessor {
@@ -61,6 +83,14 @@ pub struct Preprocessor {
     macro_invocations: u64,
     /// Registered equs
     equs: HashMap<String, Token>,
+    /// `.if`s whose `.endif` wasn't found yet
+    conditionals: Vec<token::Context>,
+}
+
+/// Where skipping a conditional block stopped
+enum BlockEnd {
+    Else,
+    Endif,
 }
 
 impl Preprocessor {
@@ -71,6 +101,7 @@ impl Preprocessor {
             macros: HashMap::new(),
             macro_invocations: 0,
             equs: HashMap::new(),
+            conditionals: Vec::new(),
         }
     }
 
@@ -102,39 +133,101 @@ impl Preprocessor {
         self.equs.contains_key(name)
     }
 
+    /// Whether the body of the macro `name` invokes it again, directly or through other macros.
+    /// Expanding it would never end.
+    fn is_recursive_macro(&self, name: &str) -> bool {
+        use token::Data::Identifier;
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![name];
+        while let Some(current) = stack.pop() {
+            let Some(m) = self.macros.get(current) else {
+                continue;
+            };
+            for token in &m.body {
+                match &token.data {
+                    Identifier(id) if id == name => return true,
+                    Identifier(id) if self.macros.contains_key(id) && visited.insert(id) => {
+                        stack.push(id)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        false
+    }
+
     /// When a macro has been invoked in the assembly code, `expand_macro` expands the invocation,
-    /// putting the body of the macro into `self.buffer`.
-    fn expand_macro(&mut self, name: &str, args: &[Token]) {
+    /// putting the body of the macro into `self.buffer`. Macros invoked in the body are expanded
+    /// when `next` reads them from the buffer, each with its own label suffix.
+    fn expand_macro(
+        &mut self,
+        name: &str,
+        mut args: Vec<Token>,
+        ctx: token::Context,
+    ) -> Result<(), Error> {
         use token::Data;
         let index = self.macro_invocations;
         self.macro_invocations += 1;
 
         let m = self.macros.get(name).unwrap();
-        let expanded_body = m.body.iter().map(|token| match &token.data {
+
+        let (min, max) = m.arity();
+        if args.len() < min || max.is_some_and(|max| args.len() > max) {
+            let expected = match max {
+                Some(max) if max == min => min.to_string(),
+                Some(max) => format!("{} to {}", min, max),
+                None => format!("at least {}", min),
+            };
+            let err = PreprocessorError::MacroArityMismatch {
+                name: m.name.clone(),
+                expected,
+                found: args.len(),
+            }
+            .with_context(ctx);
+            return Err(if args.is_empty() {
+                err.with_tip(MACRO_ARGS_TIP.as_str())
+            } else {
+                err
+            });
+        }
+
+        // Fill the omitted arguments with their defaults. Every argument after the first `min`
+        // has one.
+        let defaults = m.defaults.get(args.len()..).unwrap_or_default();
+        args.extend(defaults.iter().flatten().cloned());
+
+        let variadic_args = args.get(m.args.len()..).unwrap_or_default();
+        let expanded_body = m.body.iter().flat_map(|token| match &token.data {
+            Data::MacroArg(arg) if m.variadic.as_ref() == Some(arg) => {
+                // The body is stored in reverse, so the arguments have to be too
+                variadic_args.iter().rev().cloned().map(Ok).collect()
+            }
             Data::MacroArg(ref arg) => {
                 let index = m.args[arg];
-                Ok(args[index].clone())
+                vec![Ok(args[index].clone())]
             }
             Data::Label(label) => {
                 // NOTE: Labels are expanded with a unique suffix to avoid name collisions:
                 // `label:` => `label_M0:`, `label_M1:`, etc.
                 let mut token = token.clone();
                 token.data = Data::Label(format!("{}_M{}", label, index));
-                Ok(token)
+                vec![Ok(token)]
             }
             Data::Identifier(id) if m.labels_defined.contains(id) => {
                 // NOTE: Labels that are used inside the macro body and were also defined within it
                 // are also expanded with the unique suffix. See NOTE above.
                 let mut token = token.clone();
                 token.data = Data::Identifier(format!("{}_M{}", id, index));
-                Ok(token)
+                vec![Ok(token)]
             }
-            _ => Ok(token.clone()),
+            _ => vec![Ok(token.clone())],
         });
 
         for token in expanded_body {
             self.buffer.push(token);
         }
+        Ok(())
     }
 
     fn consume_include(&mut self, include_ctx: token::Context) -> Result<(), Error> {
@@ -213,18 +306,25 @@ impl Preprocessor {
                 Some(Token {
                     data: Label(d),
                     ctx,
+                    width,
                 }) => {
                     r#macro.labels_defined.insert(d.clone());
                     r#macro.body.push(Token {
                         data: Label(d),
                         ctx,
+                        width,
                     });
                 }
 
-                Some(token) => {
+                Some(mut token) => {
                     // Make sure the argument being used was defined
-                    if let MacroArg(arg) = &token.data {
-                        if !r#macro.args.contains_key(arg) {
+                    if let MacroArg(arg) = &mut token.data {
+                        // The variadic argument can be used with or without the `...`
+                        if let Some(name) = arg.strip_suffix("...") {
+                            *arg = name.to_owned();
+                        }
+                        if !r#macro.args.contains_key(arg) && r#macro.variadic.as_ref() != Some(arg)
+                        {
                             return Err(PreprocessorError::UndefinedMacroArg {
                                 macro_name: r#macro.name.clone(),
                                 arg: arg.clone(),
@@ -281,11 +381,34 @@ impl Preprocessor {
 
         let tokens = self.consume_until(Char(')'), args_start_ctx)?;
 
-        for token in tokens {
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
             match token {
+                Token {
+                    data: MacroArg(_),
+                    ctx,
+                    ..
+                } if r#macro.variadic.is_some() => {
+                    return Err(PreprocessorError::VariadicMacroArgNotLast {
+                        macro_name: r#macro.name.clone(),
+                        arg: r#macro.variadic.take().unwrap(),
+                    }
+                    .with_context(ctx)
+                    .with_tip(MACRO_EXAMPLE_TIP));
+                }
+
+                Token {
+                    data: MacroArg(arg),
+                    ..
+                } if arg.ends_with("...") => {
+                    let name = arg.trim_end_matches('.').to_owned();
+                    r#macro.variadic = Some(name);
+                }
+
                 Token {
                     data: MacroArg(arg),
                     ctx,
+                    ..
                 } => {
                     use hashbrown::hash_map::Entry;
                     let index = r#macro.args.len();
@@ -300,6 +423,24 @@ impl Preprocessor {
                     }
 
                     entry.or_insert(index);
+
+                    // `%arg = value`. The value can't be another argument, which is what we'd find
+                    // in `%arg = , %next`, since commas are whitespace
+                    let default = match tokens.next_if(|t| t.data == Char('=')) {
+                        Some(equals) => match tokens.next() {
+                            Some(value) if !matches!(value.data, MacroArg(_)) => Some(value),
+                            other => {
+                                let ctx = other.as_ref().map_or(equals.ctx, |t| t.ctx.clone());
+                                return Err(PreprocessorError::UnexpectedToken(
+                                    other.map(|t| t.data),
+                                )
+                                .with_context(ctx)
+                                .with_tip(MACRO_EXAMPLE_TIP));
+                            }
+                        },
+                        None => None,
+                    };
+                    r#macro.defaults.push(default);
                 }
 
                 other => {
@@ -346,6 +487,145 @@ impl Preprocessor {
         }
     }
 
+    /// Reads a number in an `.if` condition, which can also be the name of an `.equ`
+    fn consume_condition_operand(&mut self, if_ctx: &token::Context) -> Result<i32, Error> {
+        use token::Data::Identifier;
+
+        let token = self.next_token().transpose()?;
+        let value = match token.as_ref().map(|t| &t.data) {
+            Some(Identifier(id)) if self.is_registered_equ(id) => self.equs[id].data.extract_u32(),
+            Some(data) => data.extract_u32(),
+            None => None,
+        };
+
+        match value {
+            Some(x) => Ok(x as i32),
+            None => {
+                let ctx = token.as_ref().map_or(if_ctx.clone(), |t| t.ctx.clone());
+                Err(
+                    PreprocessorError::InvalidConditionOperand(token.map(|t| t.data))
+                        .with_context(ctx)
+                        .with_tip(format!(
+                            "Conditions compare numbers and .equs, like in {}",
+                            ".if DEBUG == 1".bright_blue()
+                        )),
+                )
+            }
+        }
+    }
+
+    /// Consumes the next token if it's the char `c`
+    fn consume_char(&mut self, c: char) -> bool {
+        match self.peek() {
+            Some(Ok(Token {
+                data: token::Data::Char(found),
+                ..
+            })) if *found == c => {
+                self.next_token();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Reads the condition of an `.if`: a number, which is true if it's not zero, or a comparison
+    /// between two numbers with `==`, `!=`, `<`, `<=`, `>` or `>=`
+    fn consume_condition(&mut self, if_ctx: &token::Context) -> Result<bool, Error> {
+        let lhs = self.consume_condition_operand(if_ctx)?;
+
+        let compare: fn(&i32, &i32) -> bool = if self.consume_char('=') {
+            if !self.consume_char('=') {
+                return Err(
+                    PreprocessorError::UnexpectedToken(Some(token::Data::Char('=')))
+                        .with_context(if_ctx.clone())
+                        .with_tip(format!("Did you mean {}?", "==".bright_blue())),
+                );
+            }
+            i32::eq
+        } else if self.consume_char('!') {
+            if !self.consume_char('=') {
+                return Err(
+                    PreprocessorError::UnexpectedToken(Some(token::Data::Char('!')))
+                        .with_context(if_ctx.clone())
+                        .with_tip(format!("Did you mean {}?", "!=".bright_blue())),
+                );
+            }
+            i32::ne
+        } else if self.consume_char('<') {
+            if self.consume_char('=') {
+                i32::le
+            } else {
+                i32::lt
+            }
+        } else if self.consume_char('>') {
+            if self.consume_char('=') {
+                i32::ge
+            } else {
+                i32::gt
+            }
+        } else {
+            return Ok(lhs != 0);
+        };
+
+        let rhs = self.consume_condition_operand(if_ctx)?;
+        Ok(compare(&lhs, &rhs))
+    }
+
+    /// Skips the tokens of a conditional block whose condition was false, until its `.endif` or,
+    /// if `stop_at_else`, its `.else`. Nested `.if`s are skipped entirely.
+    fn skip_conditional_block(
+        &mut self,
+        stop_at_else: bool,
+        if_ctx: &token::Context,
+    ) -> Result<BlockEnd, Error> {
+        use token::Data::Directive;
+
+        let mut depth = 0;
+        loop {
+            // Skipped code isn't assembled, so it doesn't matter if it's valid
+            let token = match self.next_token() {
+                Some(Ok(token)) => token,
+                Some(Err(_)) => continue,
+                None => {
+                    return Err(PreprocessorError::UnterminatedIf.with_context(if_ctx.clone()));
+                }
+            };
+
+            match token.data {
+                Directive(d) if d == "if" => depth += 1,
+                Directive(d) if d == "endif" && depth == 0 => return Ok(BlockEnd::Endif),
+                Directive(d) if d == "endif" => depth -= 1,
+                Directive(d) if d == "else" && depth == 0 && stop_at_else => {
+                    return Ok(BlockEnd::Else)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads an `.if`, skipping the block that shouldn't be assembled
+    fn consume_if(&mut self, if_ctx: token::Context) -> Result<(), Error> {
+        if self.consume_condition(&if_ctx)? {
+            self.conditionals.push(if_ctx);
+            return Ok(());
+        }
+
+        match self.skip_conditional_block(true, &if_ctx)? {
+            BlockEnd::Else => self.conditionals.push(if_ctx),
+            BlockEnd::Endif => {}
+        }
+        Ok(())
+    }
+
+    /// Reads an `.else` found after a block that was assembled, skipping until the `.endif`
+    fn consume_else(&mut self, else_ctx: token::Context) -> Result<(), Error> {
+        let if_ctx = self.conditionals.pop().ok_or_else(|| {
+            PreprocessorError::UnmatchedConditional("else".into()).with_context(else_ctx)
+        })?;
+        self.skip_conditional_block(false, &if_ctx)?;
+        Ok(())
+    }
+
     /// Read an .equ
     fn consume_equ(&mut self, ctx: token::Context) -> Result<(), Error> {
         use token::Data::Identifier;
@@ -373,9 +653,13 @@ impl Iterator for Preprocessor {
     type Item = Result<Token, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let token = match self.next_token()? {
-            Ok(t) => t,
-            Err(e) => return Some(Err(e)),
+        let token = match self.next_token() {
+            Some(Ok(t)) => t,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                let if_ctx = self.conditionals.pop()?;
+                return Some(Err(PreprocessorError::UnterminatedIf.with_context(if_ctx)));
+            }
         };
 
         use super::token::Data::*;
@@ -393,6 +677,25 @@ impl Iterator for Preprocessor {
                 }
                 self.next()
             }
+            Directive(d) if d == "if" => {
+                if let Err(e) = self.consume_if(token.ctx.clone()) {
+                    return Some(Err(e));
+                }
+                self.next()
+            }
+            Directive(d) if d == "else" => {
+                if let Err(e) = self.consume_else(token.ctx.clone()) {
+                    return Some(Err(e));
+                }
+                self.next()
+            }
+            Directive(d) if d == "endif" => {
+                if self.conditionals.pop().is_none() {
+                    let err = PreprocessorError::UnmatchedConditional(d).with_context(token.ctx);
+                    return Some(Err(err));
+                }
+                self.next()
+            }
             Directive(d) if d == "equ" || d == "eqv" => {
                 if let Err(e) = self.consume_equ(token.ctx.clone()) {
                     return Some(Err(e));
@@ -400,12 +703,20 @@ impl Iterator for Preprocessor {
                 self.next()
             }
             Identifier(id) if self.is_registered_macro(&id) => {
+                if self.is_recursive_macro(&id) {
+                    return Some(Err(PreprocessorError::RecursiveMacro(id)
+                        .with_context(token.ctx)
+                        .with_tip("Macros are expanded before the program runs, so they can't use themselves, even inside other macros")));
+                }
+
                 let args = self.consume_macro_invocation_args(token.ctx.clone());
                 if let Err(e) = args {
                     return Some(Err(e));
                 }
 
-                self.expand_macro(&id, &args.unwrap());
+                if let Err(e) = self.expand_macro(&id, args.unwrap(), token.ctx) {
+                    return Some(Err(e));
+                }
                 self.next()
             }
             Identifier(id) if self.is_registered_equ(&id) => {
@@ -468,6 +779,230 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_variadic_macro() {
+        let input = "
+            .macro Bytes(%first, %rest...)
+                .byte %first %rest
+            .end_macro
+
+            Bytes(1)
+            Bytes(1, 2)
+            Bytes(1, 2, 3, 4)";
+
+        let expanded = "
+            .byte 1
+            .byte 1 2
+            .byte 1 2 3 4";
+
+        let tokens = Lexer::from_content(String::from(input), "macro.s").preprocess();
+        let expanded_tokens = Lexer::from_content(String::from(expanded), "expanded.s");
+
+        let tokens: Vec<_> = tokens.map(|t| t.unwrap().data).collect();
+        let expanded_tokens: Vec<_> = expanded_tokens.map(|t| t.unwrap().data).collect();
+
+        assert_eq!(tokens, expanded_tokens);
+
+        let not_last = ".macro Bad(%rest..., %last)\n.end_macro";
+        let mut tokens = Lexer::from_content(String::from(not_last), "macro.s").preprocess();
+        assert!(tokens.any(|t| t.is_err()));
+    }
+
+    #[test]
+    fn test_nested_macros() {
+        let input = "
+            .macro INNER(%x)
+                Skip:
+                    beqz %x Skip
+            .end_macro
+
+            .macro OUTER(%x)
+                Loop:
+                    INNER(%x)
+                    j Loop
+            .end_macro
+
+            OUTER(t0)
+            OUTER(t1)";
+
+        let expanded = "
+            Loop_M0:
+                Skip_M1:
+                    beqz t0 Skip_M1
+                j Loop_M0
+            Loop_M2:
+                Skip_M3:
+                    beqz t1 Skip_M3
+                j Loop_M2";
+
+        let tokens = Lexer::from_content(String::from(input), "macro.s").preprocess();
+        let expanded_tokens = Lexer::from_content(String::from(expanded), "expanded.s");
+
+        let tokens: Vec<_> = tokens.map(|t| t.unwrap().data).collect();
+        let expanded_tokens: Vec<_> = expanded_tokens.map(|t| t.unwrap().data).collect();
+
+        assert_eq!(tokens, expanded_tokens);
+
+        // Labels defined by the outer macro can be passed to the inner one
+        let input = "
+            .macro JUMP(%target)
+                j %target
+            .end_macro
+
+            .macro FOREVER
+                Loop:
+                    JUMP(Loop)
+            .end_macro
+
+            FOREVER";
+
+        let tokens = Lexer::from_content(String::from(input), "macro.s").preprocess();
+        let tokens: Vec<_> = tokens.map(|t| t.unwrap().data).collect();
+        let expanded_tokens: Vec<_> = Lexer::from_content("Loop_M0: j Loop_M0".into(), "e.s")
+            .map(|t| t.unwrap().data)
+            .collect();
+        assert_eq!(tokens, expanded_tokens);
+    }
+
+    #[test]
+    fn test_recursive_macros() {
+        let input = "
+            .macro A
+                B
+            .end_macro
+
+            .macro B
+                nop
+                A
+            .end_macro
+
+            A";
+
+        let mut tokens = Lexer::from_content(String::from(input), "macro.s").preprocess();
+        let err = tokens.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("uses itself"), "{err}");
+    }
+
+    #[test]
+    fn test_macro_defaults() {
+        let input = "
+            .macro Fill(%rd, %color = 0xff)
+                li %rd %color
+            .end_macro
+
+            Fill(t0)
+            Fill(t1, 7)";
+
+        let expanded = "
+            li t0 0xff
+            li t1 7";
+
+        let tokens = Lexer::from_content(String::from(input), "macro.s").preprocess();
+        let expanded_tokens = Lexer::from_content(String::from(expanded), "expanded.s");
+
+        let tokens: Vec<_> = tokens.map(|t| t.unwrap().data).collect();
+        let expanded_tokens: Vec<_> = expanded_tokens.map(|t| t.unwrap().data).collect();
+
+        assert_eq!(tokens, expanded_tokens);
+
+        // %rd has no default
+        let input = ".macro Fill(%rd, %color = 0)\n.end_macro\nFill()";
+        let mut tokens = Lexer::from_content(String::from(input), "macro.s").preprocess();
+        let err = tokens.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("1 to 2 arguments"), "{err}");
+
+        for input in [".macro Fill(%color = )", ".macro Fill(%color = , %rd)"] {
+            let input = format!("{input}\n.end_macro");
+            let mut tokens = Lexer::from_content(input, "macro.s").preprocess();
+            assert!(tokens.any(|t| t.is_err()));
+        }
+    }
+
+    #[test]
+    fn test_macro_arity_mismatch() {
+        let arity_error = |input: &str| {
+            let input = format!(".macro Add(%rd, %rs)\nadd %rd %rd %rs\n.end_macro\n{input}");
+            let mut tokens = Lexer::from_content(input, "macro.s").preprocess();
+            match tokens.next().unwrap().unwrap_err() {
+                Error::WithContext { err, ctx } => match *err {
+                    Error::Preprocessor(PreprocessorError::MacroArityMismatch {
+                        name,
+                        expected,
+                        found,
+                    }) => (name, expected, found, ctx.line),
+                    other => panic!("Expected an arity mismatch, got {:?}", other),
+                },
+                other => panic!("Expected an error with context, got {:?}", other),
+            }
+        };
+
+        assert_eq!(arity_error("Add(t0)"), ("Add".into(), "2".into(), 1, 4));
+        assert_eq!(
+            arity_error("Add(t0, t1, t2)"),
+            ("Add".into(), "2".into(), 3, 4)
+        );
+
+        let input = ".macro Add(%rd, %rs)\nadd %rd %rd %rs\n.end_macro\nAdd(t0, t1)";
+        let tokens = Lexer::from_content(input.into(), "macro.s").preprocess();
+        assert!(tokens.collect::<Result<Vec<_>, _>>().is_ok());
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let preprocess = |input: &str| -> Vec<_> {
+            Lexer::from_content(String::from(input), "if.s")
+                .preprocess()
+                .map(|t| t.unwrap().data)
+                .collect()
+        };
+        let lex = |input: &str| -> Vec<_> {
+            Lexer::from_content(String::from(input), "expanded.s")
+                .map(|t| t.unwrap().data)
+                .collect()
+        };
+
+        let program = |debug: i32| {
+            format!(
+                "
+                .equ DEBUG {debug}
+                .if DEBUG
+                    li a7 1
+                .else
+                    li a7 2
+                .endif
+                .if DEBUG == 1
+                    .if 2 > 3
+                        nop
+                    .endif
+                    ecall
+                .endif
+                .if DEBUG != 1
+                    ret
+                .endif"
+            )
+        };
+
+        assert_eq!(preprocess(&program(1)), lex("li a7 1 ecall"));
+        assert_eq!(preprocess(&program(0)), lex("li a7 2 ret"));
+
+        assert_eq!(preprocess(".if -1 < 0\n nop\n .endif"), lex("nop"));
+        assert_eq!(
+            preprocess(".if 2 >= 3\n nop\n .else\n ret\n .endif"),
+            lex("ret")
+        );
+
+        let errors = |input: &str| {
+            let mut tokens = Lexer::from_content(String::from(input), "if.s").preprocess();
+            tokens.any(|t| t.is_err())
+        };
+        assert!(errors(".if 1\n nop"));
+        assert!(errors(".if 0\n nop"));
+        assert!(errors(".endif"));
+        assert!(errors(".else"));
+        assert!(errors(".if UNDEFINED\n.endif"));
+        assert!(errors(".if 1 = 1\n.endif"));
+    }
+
     #[test]
     fn test_macro_labels() {
         let input = "
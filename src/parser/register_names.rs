@@ -7,6 +7,13 @@ pub const UEPC_INDEX: u8 = 2;
 pub const USTATUS_INDEX: u8 = 3;
 pub const UTVEC_INDEX: u8 = 4;
 pub const UCAUSE_INDEX: u8 = 5;
+pub const USCRATCH_INDEX: u8 = 6;
+pub const UTVAL_INDEX: u8 = 7;
+pub const INSTRET_INDEX: u8 = 8;
+pub const INSTRETH_INDEX: u8 = 9;
+pub const CYCLE_INDEX: u8 = 10;
+pub const TIMEH_INDEX: u8 = 11;
+pub const CYCLEH_INDEX: u8 = 12;
 
 pub type RegMap = HashMap<String, u8>;
 
@@ -49,6 +56,7 @@ pub fn regs() -> RegMap {
 
     // Insert named registers
     insert_names(&mut map, &REGVEC);
+    map.insert("fp".to_owned(), 8); // frame pointer, the same as s0
 
     map
 }
@@ -73,19 +81,26 @@ pub fn floats() -> RegMap {
     map
 }
 
+/// Status register names, by index. Aliases aren't included.
+pub const STATUSVEC: [&str; 13] = [
+    "time", "misa", "uepc", "ustatus", "utvec", "ucause", "uscratch", "utval", "instret",
+    "instreth", "cycle", "timeh", "cycleh",
+];
+
 pub fn status() -> RegMap {
     let mut map = RegMap::default();
 
-    map.insert("time".to_owned(), TIME_INDEX);
-    map.insert("misa".to_owned(), MISA_INDEX);
-    map.insert("uepc".to_owned(), UEPC_INDEX);
-    map.insert("ustatus".to_owned(), USTATUS_INDEX);
-    map.insert("utvec".to_owned(), UTVEC_INDEX);
-    map.insert("ucause".to_owned(), UCAUSE_INDEX);
-
-    let names = vec!["uscratch", "utval", "instret", "instreth", "cycle", "timeh"];
-    for name in names {
-        map.insert(name.to_string(), map.len() as u8);
+    insert_names(&mut map, &STATUSVEC);
+
+    // FPGRARS only models user mode, so the supervisor and machine mode registers are the same
+    // as the user ones. This lets trap handlers written for those modes run.
+    for prefix in ["s", "m"] {
+        map.insert(format!("{prefix}epc"), UEPC_INDEX);
+        map.insert(format!("{prefix}status"), USTATUS_INDEX);
+        map.insert(format!("{prefix}tvec"), UTVEC_INDEX);
+        map.insert(format!("{prefix}cause"), UCAUSE_INDEX);
+        map.insert(format!("{prefix}scratch"), USCRATCH_INDEX);
+        map.insert(format!("{prefix}tval"), UTVAL_INDEX);
     }
 
     map.insert("0".to_owned(), USTATUS_INDEX);
@@ -99,14 +114,20 @@ pub fn status() -> RegMap {
     map
 }
 
+/// Finds a register by name. Names are case-insensitive, so `S0` and `RA` work too.
+pub fn find(map: &RegMap, name: &str) -> Option<u8> {
+    match map.get(name) {
+        Some(&i) => Some(i),
+        None => map.get(&name.to_ascii_lowercase()).copied(),
+    }
+}
+
 pub trait TryGetRegister {
     fn try_get(&self, name: &str) -> Result<u8, Error>;
 }
 
 impl TryGetRegister for RegMap {
     fn try_get(&self, name: &str) -> Result<u8, Error> {
-        self.get(name)
-            .copied()
-            .ok_or_else(|| Error::RegisterNotFound(name.to_owned()))
+        find(self, name).ok_or_else(|| Error::RegisterNotFound(name.to_owned()))
     }
 }
@@ -1,15 +1,18 @@
 pub mod unlabel;
-use std::iter::Peekable;
+use std::{cell::RefCell, iter::Peekable, rc::Rc};
 
 pub use unlabel::unlabel;
 
 use super::{
-    error::{Contextualize, Error, ParserError},
-    register_names::RegMap,
+    error::{imm_bounds, Contextualize, Error, LexerError, ParserError},
+    register_names::{self, RegMap, FLOATVEC},
     token::{self, Token},
     ParserContext,
 };
-use crate::{instruction::Instruction, parser::LabelUseType};
+use crate::{
+    instruction::{Instruction, RoundingMode},
+    parser::LabelUseType,
+};
 use lazy_static::lazy_static;
 use owo_colors::OwoColorize;
 
@@ -26,16 +29,30 @@ lazy_static! {
         'a'.bright_blue(),
         "'".bright_blue(),
     );
+    static ref TIP_FLOAT_REGISTER: String = format!(
+        "The float registers are {} to {}, or by their ABI names: {}",
+        "f0".bright_blue(),
+        "f31".bright_blue(),
+        FLOATVEC
+            .iter()
+            .map(|name| name.bright_blue().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    );
 }
 
 fn is_register(regs: &RegMap, token: Option<&Result<Token, Error>>) -> bool {
-    use token::Data::Identifier;
+    find_register(regs, token.and_then(|t| t.as_ref().ok())).is_some()
+}
+
+/// The register named by `token`, if it's an identifier in `map`
+fn find_register(map: &RegMap, token: Option<&Token>) -> Option<u8> {
     match token {
-        Some(Ok(Token {
-            data: Identifier(id),
+        Some(Token {
+            data: token::Data::Identifier(id),
             ..
-        })) => regs.contains_key(id),
-        _ => false,
+        }) => register_names::find(map, id),
+        _ => None,
     }
 }
 
@@ -48,7 +65,7 @@ pub fn parse_instruction(
     let instr = instruction.to_lowercase();
     let instr = instr.as_str();
 
-    let mut ipc = InstructionParsingContext::new(tokens, parser, instr, instr_ctx.clone());
+    let mut ipc = InstructionParsingContext::new(tokens, parser, instr, &instr_ctx);
 
     let found = ipc.parse_type_r()?
         || ipc.parse_type_i()?
@@ -56,7 +73,9 @@ pub fn parse_instruction(
         || ipc.parse_type_b_and_jumps()?
         || ipc.parse_type_u()?
         || ipc.parse_csr()?
-        || ipc.parse_float()?;
+        || ipc.parse_float()?
+        || ipc.parse_atomic()?
+        || ipc.parse_compressed()?;
     if !found {
         let err = ParserError::UnknownInstruction(instruction).with_context(instr_ctx);
         return Err(err);
@@ -64,11 +83,48 @@ pub fn parse_instruction(
     Ok(())
 }
 
+/// Whether `name` is the mnemonic of an instruction we know, like `add` or `nop`. We ask
+/// [`parse_instruction`] itself, without operands: it fails for most instructions, but only
+/// unknown ones fail with [`ParserError::UnknownInstruction`].
+pub fn is_instruction(name: &str) -> bool {
+    thread_local! {
+        /// Parser the names are tried on, so we don't build a new one every time
+        static PROBE: RefCell<ParserContext> = RefCell::default();
+    }
+
+    PROBE.with(|probe| {
+        let mut probe = probe.borrow_mut();
+        // Instructions without operands are pushed, and RV64 ones would be warned about
+        probe.code.clear();
+        probe.code_ctx.clear();
+        probe.warned_rv64.clear();
+        probe.warned_rv64.insert(name.to_lowercase());
+
+        let mut tokens = std::iter::empty().peekable();
+        let res = parse_instruction(
+            &mut tokens,
+            &mut probe,
+            name.to_owned(),
+            token::Context::empty(),
+        );
+        !matches!(res, Err(Error::WithContext { ref err, .. })
+            if matches!(**err, Error::Parser(ParserError::UnknownInstruction(_))))
+    })
+}
+
 struct InstructionParsingContext<'a, TI: Iterator<Item = Result<Token, Error>>> {
     tokens: &'a mut Peekable<TI>,
     parser: &'a mut ParserContext,
     instr: &'a str,
-    instr_ctx: token::Context,
+    instr_ctx: &'a token::Context,
+    /// Line and column right after the last token consumed by this instruction. Missing operands
+    /// are reported here, instead of at the instruction name.
+    end: (u32, u32),
+    /// File of the last token consumed, if it isn't the instruction's, like a macro argument
+    end_file: Option<Rc<String>>,
+    /// Value and context of the last immediate, if it was a number. Labels are addresses, so
+    /// their range isn't checked.
+    last_literal: Option<(i32, token::Context)>,
 }
 
 impl<'a, TI> InstructionParsingContext<'a, TI>
@@ -79,13 +135,42 @@ where
         tokens: &'a mut Peekable<TI>,
         parser: &'a mut ParserContext,
         instr: &'a str,
-        instr_ctx: token::Context,
+        instr_ctx: &'a token::Context,
     ) -> Self {
+        let end = (
+            instr_ctx.line,
+            instr_ctx.column + instr.chars().count() as u32,
+        );
         Self {
             tokens,
             parser,
             instr,
             instr_ctx,
+            end,
+            end_file: None,
+            last_literal: None,
+        }
+    }
+
+    /// Consumes the next token, keeping track of where it ends
+    fn next_token(&mut self) -> Result<Option<Token>, Error> {
+        let token = self.tokens.next().transpose()?;
+        if let Some(token) = &token {
+            let ctx = &token.ctx;
+            self.end = (ctx.line, ctx.column + token.width);
+            self.end_file =
+                (!Rc::ptr_eq(&ctx.file, &self.instr_ctx.file)).then(|| ctx.file.clone());
+        }
+        Ok(token)
+    }
+
+    /// Context right after the last token consumed. Only built when there's an error to report.
+    fn end_ctx(&self) -> token::Context {
+        let file = self.end_file.as_ref().unwrap_or(&self.instr_ctx.file);
+        token::Context {
+            file: file.clone(),
+            line: self.end.0,
+            column: self.end.1,
         }
     }
 
@@ -96,15 +181,17 @@ where
     }
 
     fn register(&mut self) -> Result<u8, Error> {
-        let token = self.tokens.next().transpose()?;
+        let token = self.next_token()?;
         let regs = &self.parser.regnames.regs;
 
-        use token::Data::Identifier;
-        match token.as_ref().map(|t| &t.data) {
-            Some(Identifier(id)) if regs.contains_key(id) => Ok(regs[id]),
+        match (
+            find_register(regs, token.as_ref()),
+            token.as_ref().map(|t| &t.data),
+        ) {
+            (Some(i), _) => Ok(i),
 
-            None => Err(ParserError::ExpectedRegister(None).with_context(self.instr_ctx.clone())),
-            Some(other) => {
+            (None, None) => Err(ParserError::ExpectedRegister(None).with_context(self.end_ctx())),
+            (None, Some(other)) => {
                 let ctx = token.as_ref().unwrap().ctx.clone();
                 Err(ParserError::ExpectedRegister(Some(other.to_string())).with_context(ctx))
             }
@@ -112,17 +199,19 @@ where
     }
 
     fn status_register(&mut self) -> Result<u8, Error> {
-        let token = self.tokens.next().transpose()?;
+        let token = self.next_token()?;
         let status = &self.parser.regnames.status;
 
-        use token::Data::Identifier;
-        match token.as_ref().map(|t| &t.data) {
-            Some(Identifier(id)) if status.contains_key(id) => Ok(status[id]),
+        match (
+            find_register(status, token.as_ref()),
+            token.as_ref().map(|t| &t.data),
+        ) {
+            (Some(i), _) => Ok(i),
 
-            None => {
-                Err(ParserError::ExpectedStatusRegister(None).with_context(self.instr_ctx.clone()))
+            (None, None) => {
+                Err(ParserError::ExpectedStatusRegister(None).with_context(self.end_ctx()))
             }
-            Some(other) => {
+            (None, Some(other)) => {
                 let ctx = token.as_ref().unwrap().ctx.clone();
                 Err(ParserError::ExpectedStatusRegister(Some(other.to_string())).with_context(ctx))
             }
@@ -130,64 +219,153 @@ where
     }
 
     fn float_register(&mut self) -> Result<u8, Error> {
-        let token = self.tokens.next().transpose()?;
+        let token = self.next_token()?;
         let floats = &self.parser.regnames.floats;
 
-        use token::Data::Identifier;
-        match token.as_ref().map(|t| &t.data) {
-            Some(Identifier(id)) if floats.contains_key(id) => Ok(floats[id]),
+        match (
+            find_register(floats, token.as_ref()),
+            token.as_ref().map(|t| &t.data),
+        ) {
+            (Some(i), _) => Ok(i),
 
-            None => {
-                Err(ParserError::ExpectedFloatRegister(None).with_context(self.instr_ctx.clone()))
-            }
-            Some(other) => {
+            (None, None) => Err(ParserError::ExpectedFloatRegister(None)
+                .with_context(self.end_ctx())
+                .with_tip(&*TIP_FLOAT_REGISTER)),
+            (None, Some(other)) => {
                 let ctx = token.as_ref().unwrap().ctx.clone();
-                Err(ParserError::ExpectedFloatRegister(Some(other.to_string())).with_context(ctx))
+                Err(ParserError::ExpectedFloatRegister(Some(other.to_string()))
+                    .with_context(ctx)
+                    .with_tip(&*TIP_FLOAT_REGISTER))
             }
         }
     }
 
+    /// Parses an optional rounding mode, like `rtz`. Defaults to `rne` when there's none.
+    fn rounding_mode(&mut self) -> Result<RoundingMode, Error> {
+        use token::Data::Identifier;
+        let rm = match self.tokens.peek() {
+            Some(Ok(Token {
+                data: Identifier(id),
+                ..
+            })) => RoundingMode::from_name(id),
+            _ => None,
+        };
+
+        if rm.is_some() {
+            self.next_token()?;
+        }
+        Ok(rm.unwrap_or_default())
+    }
+
     fn immediate(&mut self) -> Result<u32, Error> {
-        let token = self.tokens.next().transpose()?;
+        let token = self.next_token()?;
         self.immediate_from(token)
     }
 
+    /// Parses an immediate that must fit in a 12-bit signed field, like the one in `addi`
+    fn immediate12(&mut self) -> Result<u32, Error> {
+        let x = self.immediate()?;
+        self.check_imm_range(12, true)?;
+        Ok(x)
+    }
+
+    /// Parses the shift amount of `slli`, `srli` and `srai`, a 5-bit unsigned field
+    fn shift_amount(&mut self) -> Result<u32, Error> {
+        let x = self.immediate()?;
+        self.check_imm_range(5, false)?;
+        Ok(x)
+    }
+
+    /// Checks that the last immediate fits in a field with `bits` bits
+    fn check_imm_range(&self, bits: u32, signed: bool) -> Result<(), Error> {
+        let Some((value, ctx)) = &self.last_literal else {
+            return Ok(());
+        };
+
+        let (min, max) = imm_bounds(bits, signed);
+        if !self.parser.options.imm_range_check || (min..=max).contains(&(*value as i64)) {
+            return Ok(());
+        }
+
+        Err(ParserError::ImmediateOutOfRange {
+            value: *value,
+            bits,
+            signed,
+        }
+        .with_context(ctx.clone()))
+    }
+
     fn immediate_from(&mut self, token: Option<Token>) -> Result<u32, Error> {
-        use token::Data::Identifier;
-        match token.as_ref().map(|t| (&t.data, t.data.extract_u32())) {
-            Some((Identifier(label), _)) => {
+        use token::Data::{Char, Identifier, NumericLabelRef, Relocation};
+        self.last_literal = None;
+        let Some(Token { data, ctx, .. }) = token else {
+            return Err(ParserError::ExpectedImmediate(None)
+                .with_context(self.end_ctx())
+                .with_tip(&*TIP_IMMEDIATE));
+        };
+
+        match data {
+            Identifier(label) => {
                 // The immediate is a label
-                let ctx = token.as_ref().unwrap().ctx.clone();
-                let x = self.parser.use_label(label, LabelUseType::Code, ctx);
-                Ok(x)
+                Ok(self.parser.use_label(&label, LabelUseType::Code, ctx))
             }
 
-            Some((_, Some(x))) => {
-                // The immediate is a number
-                Ok(x)
+            NumericLabelRef { number, forward } => {
+                // The immediate is a numeric local label, like `1b`
+                let label = self.parser.numeric_label_ref(number, forward, &ctx)?;
+                Ok(self.parser.use_label(&label, LabelUseType::Code, ctx))
             }
 
-            None => Err(ParserError::ExpectedImmediate(None)
-                .with_context(self.instr_ctx.clone())
-                .with_tip(&*TIP_IMMEDIATE)),
-            Some((other, _)) => {
-                let ctx = token.as_ref().unwrap().ctx.clone();
-                Err(ParserError::ExpectedImmediate(Some(other.to_string()))
-                    .with_context(ctx)
-                    .with_tip(&*TIP_IMMEDIATE))
+            Relocation(r) => {
+                // The immediate is something like `%hi(label)` or `%lo(0x10010000)`
+                self.the_token(Char('('))?;
+                let x = match self.next_token()? {
+                    Some(Token {
+                        data: Identifier(label),
+                        ctx,
+                        ..
+                    }) => self
+                        .parser
+                        .use_label(&label, LabelUseType::Relocated(r), ctx),
+                    Some(Token {
+                        data: NumericLabelRef { number, forward },
+                        ctx,
+                        ..
+                    }) => {
+                        let label = self.parser.numeric_label_ref(number, forward, &ctx)?;
+                        self.parser
+                            .use_label(&label, LabelUseType::Relocated(r), ctx)
+                    }
+                    inner => self.immediate_from(inner)?,
+                };
+                self.the_token(Char(')'))?;
+                self.last_literal = None;
+                Ok(r.apply(x))
             }
+
+            data => match data.extract_u32() {
+                Some(x) => {
+                    // The immediate is a number
+                    self.last_literal = Some((x as i32, ctx));
+                    Ok(x)
+                }
+                None if data.is_oversized_integer() => {
+                    Err(LexerError::IntegerOutOfRange(data.to_string()).with_context(ctx))
+                }
+                None => Err(ParserError::ExpectedImmediate(Some(data.to_string()))
+                    .with_context(ctx)
+                    .with_tip(&*TIP_IMMEDIATE)),
+            },
         }
     }
 
     fn the_token(&mut self, data: token::Data) -> Result<token::Data, Error> {
-        let token = self.tokens.next().transpose()?;
+        let token = self.next_token()?;
 
         match token.as_ref().map(|t| &t.data) {
             Some(d) if &data == d => Ok(data),
 
-            None => {
-                Err(ParserError::ExpectedToken(data, None).with_context(self.instr_ctx.clone()))
-            }
+            None => Err(ParserError::ExpectedToken(data, None).with_context(self.end_ctx())),
             Some(other) => {
                 let ctx = token.as_ref().unwrap().ctx.clone();
                 Err(ParserError::ExpectedToken(data, Some(other.clone())).with_context(ctx))
@@ -195,6 +373,30 @@ where
         }
     }
 
+    /// Error for an instruction we recognize, but that needs a `feature` we don't have
+    fn unsupported(&self, feature: &'static str) -> Error {
+        ParserError::UnsupportedInstruction {
+            instr: self.instr.to_owned(),
+            feature,
+        }
+        .with_context(self.instr_ctx.clone())
+    }
+
+    /// Warns, only the first time, that the RV64 instruction `instr` is treated as the 32-bit
+    /// `replacement`
+    fn warn_rv64_once(&mut self, instr: &str, replacement: &str) {
+        if !self.parser.warned_rv64.insert(instr.to_owned()) {
+            return;
+        }
+        eprintln!(
+            "   {} {} is an RV64 instruction, FPGRARS runs it as {}, which works on 32 bits\n{}",
+            "[warning]".bright_yellow(),
+            instr.bright_yellow(),
+            replacement.bright_blue(),
+            self.instr_ctx
+        );
+    }
+
     fn parse_type_r(&mut self) -> Result<bool, Error> {
         use super::Instruction::*;
 
@@ -219,10 +421,17 @@ where
             "divu" => Divu(reg!(), reg!(), reg!()).into(),
             "rem" => Rem(reg!(), reg!(), reg!()).into(),
             "remu" => Remu(reg!(), reg!(), reg!()).into(),
-            "uret" => URet.into(),
+            // Only user mode is modeled, so returning from any trap is the same
+            "uret" | "sret" | "mret" => URet.into(),
             "neg" => Sub(reg!(), 0, reg!()).into(),
+            "negw" => {
+                self.warn_rv64_once("negw", "neg");
+                Sub(reg!(), 0, reg!()).into()
+            }
             "not" => Xori(reg!(), reg!(), (-1i32) as u32).into(),
             "mv" => Mv(reg!(), reg!()).into(),
+            // Sign extends the lower 32 bits in RV64, which is the whole register in RV32
+            "sext.w" => Mv(reg!(), reg!()).into(),
             "snez" => Sltu(reg!(), 0, reg!()).into(),
             "sltz" => Slt(reg!(), reg!(), 0).into(),
             "sgtz" => Slt(reg!(), 0, reg!()).into(),
@@ -246,6 +455,10 @@ where
         macro_rules! reg { () => { self.register()? }; }
         #[rustfmt::skip]
         macro_rules! imm { () => { self.immediate()? }; }
+        #[rustfmt::skip]
+        macro_rules! imm12 { () => { self.immediate12()? }; }
+        #[rustfmt::skip]
+        macro_rules! shamt { () => { self.shift_amount()? }; }
         macro_rules! paren {
             ($inner:expr) => {{
                 self.the_token(Char('('))?;
@@ -273,6 +486,7 @@ where
                     })) = self.tokens.peek()
                     {
                         // lw rd, imm(rs1)
+                        self.check_imm_range(12, true)?;
                         let rs1 = paren!(reg!());
                         $instruction(rd, imm, rs1)
                     } else {
@@ -296,15 +510,15 @@ where
             "lw" => load_madness!(Lw),
             "lbu" => load_madness!(Lbu),
             "lhu" => load_madness!(Lhu),
-            "addi" => Addi(reg!(), reg!(), imm!()),
-            "slti" => Slti(reg!(), reg!(), imm!()),
-            "sltiu" => Sltiu(reg!(), reg!(), imm!()),
-            "slli" => Slli(reg!(), reg!(), imm!()),
-            "srli" => Srli(reg!(), reg!(), imm!()),
-            "srai" => Srai(reg!(), reg!(), imm!()),
-            "ori" => Ori(reg!(), reg!(), imm!()),
-            "andi" => Andi(reg!(), reg!(), imm!()),
-            "xori" => Xori(reg!(), reg!(), imm!()),
+            "addi" => Addi(reg!(), reg!(), imm12!()),
+            "slti" => Slti(reg!(), reg!(), imm12!()),
+            "sltiu" => Sltiu(reg!(), reg!(), imm12!()),
+            "slli" => Slli(reg!(), reg!(), shamt!()),
+            "srli" => Srli(reg!(), reg!(), shamt!()),
+            "srai" => Srai(reg!(), reg!(), shamt!()),
+            "ori" => Ori(reg!(), reg!(), imm12!()),
+            "andi" => Andi(reg!(), reg!(), imm12!()),
+            "xori" => Xori(reg!(), reg!(), imm12!()),
             "seqz" => Sltiu(reg!(), reg!(), 1),
             "li" | "la" => Li(reg!(), imm!()),
             "nop" => Addi(0, 0, 0),
@@ -365,6 +579,7 @@ where
                     })) = self.tokens.peek()
                     {
                         // sw rd, imm(rs1)
+                        self.check_imm_range(12, true)?;
                         let rs1 = paren!(reg!());
                         $instruction(rd, imm, rs1)
                     } else {
@@ -435,10 +650,13 @@ where
                 Jal(reg!(), imm!() as usize)
             }
             "jal" => Jal(1, imm!() as usize),
-            "jalr" => Jalr(reg!(), reg!(), imm!()),
-            "jr" => Jalr(reg!(), 0, 0),
-            "call" => Jal(1, imm!() as usize),
-            "j" | "tail" | "b" => Jal(0, imm!() as usize),
+            "jalr" => self.jalr()?,
+            "jr" => Jalr(0, reg!(), 0),
+            "call" | "tail" => {
+                self.parser.far_jumps.insert(self.parser.code.len());
+                Jal(if self.instr == "call" { 1 } else { 0 }, imm!() as usize)
+            }
+            "j" | "b" => Jal(0, imm!() as usize),
             _ => return Ok(false),
         };
 
@@ -446,6 +664,45 @@ where
         Ok(true)
     }
 
+    /// Parses the operands of `jalr`, which can be any of
+    /// - `jalr rs1` (links to `ra`)
+    /// - `jalr rd, rs1, imm`
+    /// - `jalr rd, imm(rs1)`
+    /// - `jalr rd, (rs1)`
+    fn jalr(&mut self) -> Result<Instruction, Error> {
+        use super::Instruction::Jalr;
+        use token::Data::Char;
+
+        let first = self.register()?;
+        match self.tokens.peek() {
+            Some(Ok(Token {
+                data: Char('('), ..
+            })) => {
+                let rs1 = self.paren_register()?;
+                Ok(Jalr(first, rs1, 0))
+            }
+            token if is_register(&self.parser.regnames.regs, token) => {
+                let rs1 = self.register()?;
+                Ok(Jalr(first, rs1, self.immediate12()?))
+            }
+            Some(Ok(token)) if token.data.extract_u32().is_some() => {
+                let imm = self.immediate12()?;
+                let rs1 = self.paren_register()?;
+                Ok(Jalr(first, rs1, imm))
+            }
+            _ => Ok(Jalr(1, first, 0)),
+        }
+    }
+
+    /// Parses a register between parentheses, like the `(sp)` in `lw t0, 4(sp)`
+    fn paren_register(&mut self) -> Result<u8, Error> {
+        use token::Data::Char;
+        self.the_token(Char('('))?;
+        let reg = self.register()?;
+        self.the_token(Char(')'))?;
+        Ok(reg)
+    }
+
     fn parse_csr(&mut self) -> Result<bool, Error> {
         use super::Instruction::*;
 
@@ -491,7 +748,7 @@ where
         #[rustfmt::skip]
         macro_rules! reg { () => { self.register()? }; }
         #[rustfmt::skip]
-        macro_rules! imm { () => { self.immediate()? }; }
+        macro_rules! imm12 { () => { self.immediate12()? }; }
         #[rustfmt::skip]
         macro_rules! freg { () => { self.float_register()? }; }
         macro_rules! paren {
@@ -517,12 +774,20 @@ where
             "fsgnjn.s" => F::SgnjNS(freg!(), freg!(), freg!()),
             "fsgnjx.s" => F::SgnjXS(freg!(), freg!(), freg!()),
             "fclass.s" => F::Class(reg!(), freg!()),
-            "fcvt.s.w" => F::CvtSW(freg!(), reg!()),
-            "fcvt.s.wu" => F::CvtSWu(freg!(), reg!()),
-            "fcvt.w.s" => F::CvtWS(reg!(), freg!()),
-            "fcvt.wu.s" => F::CvtWuS(reg!(), freg!()),
-            "fmv.s.x" => F::MvSX(freg!(), reg!()),
-            "fmv.x.s" => F::MvXS(reg!(), freg!()),
+            "fcvt.s.w" => {
+                let instr = F::CvtSW(freg!(), reg!());
+                self.rounding_mode()?; // ignored
+                instr
+            }
+            "fcvt.s.wu" => {
+                let instr = F::CvtSWu(freg!(), reg!());
+                self.rounding_mode()?; // ignored
+                instr
+            }
+            "fcvt.w.s" => F::CvtWS(reg!(), freg!(), self.rounding_mode()?),
+            "fcvt.wu.s" => F::CvtWuS(reg!(), freg!(), self.rounding_mode()?),
+            "fmv.s.x" | "fmv.w.x" => F::MvSX(freg!(), reg!()),
+            "fmv.x.s" | "fmv.x.w" => F::MvXS(reg!(), freg!()),
             "fsqrt.s" => F::Sqrt(freg!(), freg!()),
             "fabs.s" => {
                 let (rd, rs1) = (freg!(), freg!());
@@ -536,20 +801,139 @@ where
                 let (rd, rs1) = (freg!(), freg!());
                 F::SgnjNS(rd, rs1, rs1)
             }
-            "flw" => F::Lw(freg!(), imm!(), paren!(reg!())),
-            "fsw" => F::Sw(freg!(), imm!(), paren!(reg!())),
+            "flw" => F::Lw(freg!(), imm12!(), paren!(reg!())),
+            "fsw" => F::Sw(freg!(), imm12!(), paren!(reg!())),
+
+            // Common in code written for other simulators, so we explain why they don't work
+            "fcvt.d.s" | "fcvt.s.d" | "fcvt.d.w" | "fcvt.d.wu" | "fcvt.w.d" | "fcvt.wu.d"
+            | "fcvt.d.l" | "fcvt.d.lu" | "fcvt.l.d" | "fcvt.lu.d" | "fmv.d.x" | "fmv.x.d"
+            | "fmv.d" | "fld" | "fsd" | "fadd.d" | "fsub.d" | "fmul.d" | "fdiv.d" | "fsqrt.d" => {
+                return Err(self.unsupported("double-precision floats (the D extension)"))
+            }
+            "fcvt.s.l" | "fcvt.s.lu" | "fcvt.l.s" | "fcvt.lu.s" => {
+                return Err(self.unsupported("64-bit integers (RV64)"))
+            }
             _ => return Ok(false),
         };
 
         self.push_instr(Instruction::Float(instr));
         Ok(true)
     }
+
+    fn parse_atomic(&mut self) -> Result<bool, Error> {
+        use super::AtomicInstruction as A;
+        use token::Data::Char;
+
+        #[rustfmt::skip]
+        macro_rules! reg { () => { self.register()? }; }
+        macro_rules! paren {
+            ($inner:expr) => {{
+                self.the_token(Char('('))?;
+                let res = $inner;
+                self.the_token(Char(')'))?;
+                res
+            }};
+        }
+
+        // The acquire/release bits don't mean anything when there's a single hart
+        let instr = [".aqrl", ".aq", ".rl"]
+            .iter()
+            .find_map(|suffix| self.instr.strip_suffix(suffix))
+            .unwrap_or(self.instr);
+
+        let instr = match instr {
+            "lr.w" => A::LrW(reg!(), paren!(reg!())),
+            "sc.w" => A::ScW(reg!(), reg!(), paren!(reg!())),
+            "amoswap.w" => A::AmoSwapW(reg!(), reg!(), paren!(reg!())),
+            "amoadd.w" => A::AmoAddW(reg!(), reg!(), paren!(reg!())),
+            "amoxor.w" => A::AmoXorW(reg!(), reg!(), paren!(reg!())),
+            "amoand.w" => A::AmoAndW(reg!(), reg!(), paren!(reg!())),
+            "amoor.w" => A::AmoOrW(reg!(), reg!(), paren!(reg!())),
+            "amomin.w" => A::AmoMinW(reg!(), reg!(), paren!(reg!())),
+            "amomax.w" => A::AmoMaxW(reg!(), reg!(), paren!(reg!())),
+            "amominu.w" => A::AmoMinuW(reg!(), reg!(), paren!(reg!())),
+            "amomaxu.w" => A::AmoMaxuW(reg!(), reg!(), paren!(reg!())),
+            _ => return Ok(false),
+        };
+
+        self.push_instr(Instruction::Atomic(instr));
+        Ok(true)
+    }
+
+    /// Parses RV32C mnemonics, found in the output of compilers with `-march=rv32ic`, as the
+    /// instructions they expand to. We don't model the encoding, so they still take 4 bytes.
+    fn parse_compressed(&mut self) -> Result<bool, Error> {
+        use super::Instruction::*;
+
+        #[rustfmt::skip]
+        macro_rules! reg { () => { self.register()? }; }
+        #[rustfmt::skip]
+        macro_rules! imm { () => { self.immediate()? }; }
+        #[rustfmt::skip]
+        macro_rules! imm12 { () => { self.immediate12()? }; }
+        #[rustfmt::skip]
+        macro_rules! shamt { () => { self.shift_amount()? }; }
+        // Instructions like `c.add rd, rs2`, where rd is also the first source
+        macro_rules! in_place {
+            ($instruction:expr, $operand:expr) => {{
+                let rd = reg!();
+                $instruction(rd, rd, $operand)
+            }};
+        }
+
+        let instr = match self.instr {
+            "c.nop" => Addi(0, 0, 0),
+            "c.ebreak" => Ebreak,
+            "c.li" => Addi(reg!(), 0, imm12!()),
+            "c.lui" => Lui(reg!(), imm!()),
+            "c.mv" => Mv(reg!(), reg!()),
+            "c.add" => in_place!(Add, reg!()),
+            "c.sub" => in_place!(Sub, reg!()),
+            "c.and" => in_place!(And, reg!()),
+            "c.or" => in_place!(Or, reg!()),
+            "c.xor" => in_place!(Xor, reg!()),
+            "c.addi" => in_place!(Addi, imm12!()),
+            "c.andi" => in_place!(Andi, imm12!()),
+            "c.slli" => in_place!(Slli, shamt!()),
+            "c.srli" => in_place!(Srli, shamt!()),
+            "c.srai" => in_place!(Srai, shamt!()),
+            "c.j" => Jal(0, imm!() as usize),
+            "c.jal" => Jal(1, imm!() as usize),
+            "c.jr" => Jalr(0, reg!(), 0),
+            "c.jalr" => Jalr(1, reg!(), 0),
+            "c.beqz" => Beq(reg!(), 0, imm!() as usize),
+            "c.bnez" => Bne(reg!(), 0, imm!() as usize),
+            _ => return Ok(false),
+        };
+
+        self.push_instr(instr);
+        Ok(true)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser::lexer::Lexer;
+    use crate::parser::{lexer::Lexer, LabelUse};
+
+    #[test]
+    fn test_is_instruction() {
+        for name in [
+            "add",
+            "ADD",
+            "nop",
+            "ret",
+            "negw",
+            "fcvt.s.w",
+            "amoswap.w.aqrl",
+            "c.li",
+        ] {
+            assert!(is_instruction(name), "{name} should be an instruction");
+        }
+        for name in ["table", "x", "main", "word", "amoswap.w.rw"] {
+            assert!(!is_instruction(name), "{name} shouldn't be an instruction");
+        }
+    }
 
     #[test]
     fn test_la() {
@@ -658,4 +1042,345 @@ mod tests {
             &[Sb(10, 45, 11), Sh(12, 0, 13), Li(15, 1234), Sw(14, 0, 15)]
         );
     }
+
+    #[test]
+    fn test_register_aliases() {
+        let input = "add fp, S0, x8
+            fadd.s FT0, ft1, F2
+            csrr T0, USTATUS
+            jal RA, 0x40";
+        let mut tokens = Lexer::from_content(String::from(input), "aliases.s").peekable();
+        let mut parser = ParserContext::default();
+
+        for _ in 0..4 {
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            );
+            assert!(res.is_ok());
+        }
+
+        use super::super::Instruction::*;
+        use crate::instruction::FloatInstruction::Add as FAdd;
+        assert_eq!(
+            &parser.code,
+            &[
+                Add(8, 8, 8),
+                Float(FAdd(0, 1, 2)),
+                CsrRs(5, register_names::USTATUS_INDEX, 0),
+                Jal(1, 0x40),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_link_registers() {
+        let input = "call 0x40
+            tail 0x80
+            j 0x10
+            jal 0x20
+            jal t0, 0x30";
+        let mut tokens = Lexer::from_content(String::from(input), "jumps.s").peekable();
+        let mut parser = ParserContext::default();
+
+        for _ in 0..5 {
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            );
+            assert!(res.is_ok());
+        }
+
+        // `call` links to ra, while `tail` and `j` don't save a return address
+        use super::super::Instruction::*;
+        assert_eq!(
+            &parser.code,
+            &[
+                Jal(1, 0x40),
+                Jal(0, 0x80),
+                Jal(0, 0x10),
+                Jal(1, 0x20),
+                Jal(5, 0x30)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jalr_forms() {
+        let input = "jalr t0
+            jalr ra, t0, 8
+            jalr ra, 8(t0)
+            jalr s0, -4(t1)
+            jalr zero, (a0)
+            jr t2
+            ret";
+        let mut tokens = Lexer::from_content(String::from(input), "jalr.s").peekable();
+        let mut parser = ParserContext::default();
+
+        for _ in 0..7 {
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            );
+            assert!(res.is_ok());
+        }
+
+        use super::super::Instruction::*;
+        assert_eq!(
+            &parser.code,
+            &[
+                Jalr(1, 5, 0),
+                Jalr(1, 5, 8),
+                Jalr(1, 5, 8),
+                Jalr(8, 6, -4i32 as u32),
+                Jalr(0, 10, 0),
+                Jalr(0, 7, 0),
+                Jalr(0, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_operand_context() {
+        let input = "add t0, t1";
+        let mut tokens = Lexer::from_content(String::from(input), "missing.s").peekable();
+        let mut parser = ParserContext::default();
+
+        let instruction = tokens.next().unwrap().unwrap();
+        let res = parse_instruction(
+            &mut tokens,
+            &mut parser,
+            instruction.data.to_string(),
+            instruction.ctx.clone(),
+        );
+
+        // The error should point right after `t1`, not at `add`
+        let after_t1 = instruction.ctx.column + input.len() as u32;
+        match res {
+            Err(Error::WithContext { ctx, .. }) => {
+                assert_eq!((ctx.line, ctx.column), (1, after_t1))
+            }
+            _ => panic!("expected an error with context"),
+        }
+    }
+
+    #[test]
+    fn test_error_contexts() {
+        // Code, and where the error should point to, relative to the instruction
+        let cases = [
+            ("add t0, t1, 5", 12),          // at the wrong operand
+            ("addi t0, t1", 11),            // right after the last operand
+            ("addi t0, t1, 4096", 13),      // at the out of range immediate
+            ("lui t0, %hi(", 12),           // right after the parenthesis
+            ("lui t0, %hi(0x10010000", 22), // right after the number, as it was written
+            ("lui t0, %hi(label", 17),
+            ("sw t0, 4(", 9),
+            ("frobnicate t0", 0), // at the unknown instruction
+        ];
+
+        for (input, offset) in cases {
+            let mut tokens = Lexer::from_content(String::from(input), "errors.s").peekable();
+            let mut parser = ParserContext::default();
+
+            let instruction = tokens.next().unwrap().unwrap();
+            let column = instruction.ctx.column + offset;
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction.data.to_string(),
+                instruction.ctx,
+            );
+
+            let ctx = match res {
+                Err(Error::WithContext { ctx, .. }) => ctx,
+                Err(Error::WithTip { err, .. }) => match *err {
+                    Error::WithContext { ctx, .. } => ctx,
+                    err => panic!("{input}: expected an error with context, got {err:?}"),
+                },
+                other => panic!("{input}: expected an error with context, got {other:?}"),
+            };
+            assert_eq!(
+                (ctx.file.as_str(), ctx.line, ctx.column),
+                ("errors.s", 1, column),
+                "{input}"
+            );
+        }
+
+        // Labels keep the context of their use, to report them if they're never defined
+        let mut tokens = Lexer::from_content(String::from("j nowhere"), "errors.s").peekable();
+        let mut parser = ParserContext::default();
+        let instruction = tokens.next().unwrap().unwrap();
+        let column = instruction.ctx.column + 2;
+        parse_instruction(
+            &mut tokens,
+            &mut parser,
+            instruction.data.to_string(),
+            instruction.ctx,
+        )
+        .unwrap();
+
+        let uses = &parser.backlog["nowhere"];
+        assert!(matches!(&uses[..], [LabelUse::Code(0, None, ctx)] if ctx.column == column));
+    }
+
+    #[test]
+    fn test_invalid_float_register_tip() {
+        for input in ["fadd.s f32, ft0, ft1", "fadd.s ft0, t0, ft1"] {
+            let mut tokens = Lexer::from_content(String::from(input), "float.s").peekable();
+            let mut parser = ParserContext::default();
+
+            let instruction = tokens.next().unwrap().unwrap();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction.data.to_string(),
+                instruction.ctx.clone(),
+            );
+
+            match res {
+                Err(Error::WithTip { tip, .. }) => {
+                    assert!(tip.contains("ft0"));
+                    assert!(tip.contains("fa0"));
+                    assert!(tip.contains("f31"));
+                }
+                _ => panic!("expected an error with a tip"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fmv_aliases() {
+        let input = "fmv.w.x ft0, t0
+            fmv.s.x ft0, t0
+            fmv.x.w a0, fa1
+            fmv.x.s a0, fa1";
+        let mut tokens = Lexer::from_content(String::from(input), "fmv.s").peekable();
+        let mut parser = ParserContext::default();
+
+        for _ in 0..4 {
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            );
+            assert!(res.is_ok());
+        }
+
+        use super::super::{FloatInstruction::*, Instruction::Float};
+        assert_eq!(
+            &parser.code,
+            &[
+                Float(MvSX(0, 5)),
+                Float(MvSX(0, 5)),
+                Float(MvXS(10, 11)),
+                Float(MvXS(10, 11))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compressed() {
+        let input = "c.add a0, a1
+            c.mv a0, a1
+            c.li t0, -3
+            c.jr ra
+            c.nop
+            c.addi sp, -16";
+        let mut tokens = Lexer::from_content(String::from(input), "compressed.s").peekable();
+        let mut parser = ParserContext::default();
+
+        for _ in 0..6 {
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            );
+            assert!(res.is_ok());
+        }
+
+        use super::super::Instruction::*;
+        assert_eq!(
+            &parser.code,
+            &[
+                Add(10, 10, 11),
+                Mv(10, 11),
+                Addi(5, 0, -3i32 as u32),
+                Jalr(0, 1, 0),
+                Addi(0, 0, 0),
+                Addi(2, 2, -16i32 as u32),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_float_instructions() {
+        let unsupported = |input: &str| {
+            let mut tokens = Lexer::from_content(String::from(input), "double.s").peekable();
+            let mut parser = ParserContext::default();
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            match parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            ) {
+                Err(Error::WithContext { err, .. }) => match *err {
+                    Error::Parser(ParserError::UnsupportedInstruction { instr, feature }) => {
+                        Some((instr, feature))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        };
+
+        let (instr, feature) = unsupported("fcvt.d.w fa0, a0").unwrap();
+        assert_eq!(instr, "fcvt.d.w");
+        assert!(feature.contains("double-precision"));
+        assert!(unsupported("fcvt.s.l fa0, a0").unwrap().1.contains("RV64"));
+        assert!(unsupported("fcvt.s.w fa0, a0").is_none());
+    }
+
+    #[test]
+    fn test_atomics() {
+        let input = "lr.w t0, (a0)
+            sc.w.rl t1, t2, (a0)
+            amoadd.w.aqrl s0, s1, (sp)";
+        let mut tokens = Lexer::from_content(String::from(input), "atomics.s").peekable();
+        let mut parser = ParserContext::default();
+
+        for _ in 0..3 {
+            let instruction = tokens.next().unwrap().unwrap().data.to_string();
+            let res = parse_instruction(
+                &mut tokens,
+                &mut parser,
+                instruction,
+                token::Context::empty(),
+            );
+            assert!(res.is_ok());
+        }
+
+        use super::super::{AtomicInstruction::*, Instruction::Atomic};
+        assert_eq!(
+            &parser.code,
+            &[
+                Atomic(LrW(5, 10)),
+                Atomic(ScW(6, 7, 10)),
+                Atomic(AmoAddW(8, 9, 2))
+            ]
+        );
+    }
 }
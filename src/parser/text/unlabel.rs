@@ -45,7 +45,8 @@ pub fn unlabel(code: &mut [Instruction], i: usize, label: usize) {
 
         Add(..) | Sub(..) | Sll(..) | Slt(..) | Sltu(..) | Xor(..) | Srl(..) | Sra(..) | Or(..)
         | And(..) | Mul(..) | Mulh(..) | Mulhu(..) | Mulhsu(..) | Div(..) | Divu(..) | Rem(..)
-        | Remu(..) | URet | Ecall | Ebreak | CsrRw(..) | CsrRs(..) | CsrRc(..) | Mv(..) => {
+        | Remu(..) | URet | Ecall | Ebreak | CsrRw(..) | CsrRs(..) | CsrRc(..) | Mv(..)
+        | Atomic(..) => {
             panic!("FPGRARS tried to unlabel an instruction that doesn't have a label! {instr:?}")
         }
     };
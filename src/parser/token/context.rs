@@ -1,13 +1,65 @@
 use crate::utf8_lossy_lines::Utf8LossyLinesExt;
+use hashbrown::HashMap;
 use owo_colors::OwoColorize;
 use std::{
+    cell::RefCell,
     fmt,
-    fs::File,
-    io::BufReader,
+    fs::{self, File},
+    io::{self, BufReader},
     path::{Path, PathBuf},
     rc::Rc,
+    time::SystemTime,
 };
 
+/// Lines of a file, along with when it was last modified, so we notice when it changes. Sources
+/// that aren't on disk have no modification time.
+#[derive(Clone)]
+struct CachedFile {
+    modified: Option<SystemTime>,
+    lines: Rc<Vec<String>>,
+}
+
+thread_local! {
+    /// Lines of the files we've already displayed contexts for, so printing many errors doesn't
+    /// read the same file over and over again
+    static FILE_CACHE: RefCell<HashMap<String, CachedFile>> = RefCell::default();
+}
+
+/// Reads the lines of a file, or gets them from the cache if the file wasn't modified since it was
+/// read. If the file can't be found anymore, the cached lines are used.
+fn cached_lines(file: &str) -> io::Result<Rc<Vec<String>>> {
+    let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+    if let Some(cached) = FILE_CACHE.with(|cache| cache.borrow().get(file).cloned()) {
+        if modified.is_none() || cached.modified == modified {
+            return Ok(cached.lines);
+        }
+    }
+
+    let reader = BufReader::new(File::open(file)?);
+    let lines = Rc::new(reader.utf8_lossy_lines().collect::<io::Result<Vec<_>>>()?);
+    let cached = CachedFile {
+        modified,
+        lines: lines.clone(),
+    };
+    FILE_CACHE.with(|cache| cache.borrow_mut().insert(file.to_owned(), cached));
+    Ok(lines)
+}
+
+/// Stores the source of a file that isn't on disk, like a program loaded from a string, so its
+/// contexts can still be displayed
+pub fn cache_source(file: &str, source: &str) {
+    let lines = source
+        .as_bytes()
+        .utf8_lossy_lines()
+        .collect::<io::Result<Vec<_>>>()
+        .expect("Reading from memory can't fail");
+    let cached = CachedFile {
+        modified: None,
+        lines: Rc::new(lines),
+    };
+    FILE_CACHE.with(|cache| cache.borrow_mut().insert(file.to_owned(), cached));
+}
+
 /// Token context, including the current filename, line and column.
 /// Displaying a context will read the file and print the 3 lines surrounding it, as well as point
 /// to the position of the token.
@@ -35,6 +87,12 @@ impl Context {
         }
     }
 
+    /// The line of source code this context points to
+    pub fn source_line(&self) -> Option<String> {
+        let lines = cached_lines(&self.file).ok()?;
+        lines.get((self.line as usize).checked_sub(1)?).cloned()
+    }
+
     pub fn advance_char(&mut self, c: char) {
         if c == '\n' {
             self.column = 1;
@@ -58,24 +116,31 @@ impl fmt::Display for Context {
             self.column.bright_yellow(),
         )?;
 
-        let file = File::open(&*self.file);
-        if let Err(e) = file {
-            return writeln!(
-                f,
-                "   While we were printing this error another error ocurred!\n   Couldn't open '{}' because: {}",
-                self.file.bright_yellow(), e.bold()
-            );
-        }
+        let lines = match cached_lines(&self.file) {
+            Ok(lines) => lines,
+            Err(e) => {
+                return writeln!(
+                    f,
+                    "   While we were printing this error another error ocurred!\n   Couldn't open '{}' because: {}",
+                    self.file.bright_yellow(), e.bold()
+                );
+            }
+        };
 
-        let reader = BufReader::new(file.unwrap());
         let from = self.line.saturating_sub(2) as usize;
-        for (line, i) in reader.utf8_lossy_lines().skip(from).take(3).zip(from + 1..) {
-            let line = line.unwrap();
-            writeln!(f, "{:^4}{} {}", i.bright_blue(), "|".bright_blue(), line)?;
+        for (line, i) in lines.iter().skip(from).take(3).zip(from + 1..) {
+            writeln!(
+                f,
+                "{:^4}{} {}",
+                i.bright_blue(),
+                "|".bright_blue(),
+                expand_tabs(line)
+            )?;
 
             // BUG: this breaks for files over 9999 lines ¯\_(ツ)_/¯
             if i == self.line as usize {
-                (0..self.column + 4).for_each(|_| write!(f, "{}", ".".bright_red()).unwrap());
+                let column = visual_column(line, self.column);
+                (0..column + 4).for_each(|_| write!(f, "{}", ".".bright_red()).unwrap());
                 writeln!(f, "{}", "^ Here".bright_red())?;
             }
         }
@@ -84,6 +149,42 @@ impl fmt::Display for Context {
     }
 }
 
+const TAB_WIDTH: u32 = 4;
+
+/// Replaces tabs by spaces up to the next tab stop, so the line is displayed the same way in
+/// every terminal
+fn expand_tabs(line: &str) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - expanded.chars().count() as u32 % TAB_WIDTH;
+            (0..spaces).for_each(|_| expanded.push(' '));
+        } else {
+            expanded.push(c);
+        }
+    }
+    expanded
+}
+
+/// Converts a column computed by [`Context::advance_char`] to the column it's displayed at
+/// when the line goes through [`expand_tabs`]
+fn visual_column(line: &str, column: u32) -> u32 {
+    let mut lexer_column = 1;
+    let mut visual = 1;
+    for c in line.chars() {
+        if lexer_column >= column {
+            return visual;
+        }
+        lexer_column += if c == '\t' { 4 } else { 1 };
+        visual = if c == '\t' {
+            (visual - 1) / TAB_WIDTH * TAB_WIDTH + TAB_WIDTH + 1
+        } else {
+            visual + 1
+        };
+    }
+    visual + column.saturating_sub(lexer_column)
+}
+
 pub struct ManyContexts<'a>(pub &'a Vec<Context>);
 impl<'a> fmt::Display for ManyContexts<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -136,3 +237,98 @@ impl NormalizePathExt for Path {
         normalized
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_uses_cached_file() {
+        let path = crate::temp_path("context_cache.s");
+        std::fs::write(&path, "first:\n  li a0, 1\n  li a1, 2\nlast:\n").unwrap();
+
+        let path = path.to_str().unwrap();
+        let context = |line| Context {
+            file: Rc::new(path.to_owned()),
+            line,
+            column: 3,
+        };
+
+        assert!(context(2).to_string().contains("li a0, 1"));
+        std::fs::remove_file(path).unwrap();
+
+        let second = context(2).to_string();
+        let third = context(3).to_string();
+        assert!(second.contains("li a0, 1"));
+        assert!(third.contains("li a1, 2"));
+        assert!(third.contains("last:"));
+        assert!(!third.contains("another error"));
+    }
+
+    #[test]
+    fn test_cache_notices_modified_file() {
+        let path = crate::temp_path("context_modified.s");
+        std::fs::write(&path, "  li a0, 1\n").unwrap();
+
+        let context = Context {
+            file: Rc::new(path.to_str().unwrap().to_owned()),
+            line: 1,
+            column: 3,
+        };
+        assert_eq!(context.source_line().as_deref(), Some("  li a0, 1"));
+
+        // Filesystems may store the modification time with low precision, so we move it forward
+        // instead of relying on the clock ticking between writes
+        std::fs::write(&path, "  li a0, 2\n").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(context.source_line().as_deref(), Some("  li a0, 2"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Removes the ANSI escape sequences added by owo_colors
+    fn strip_colors(s: &str) -> String {
+        let mut stripped = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                stripped.push(c);
+            }
+        }
+        stripped
+    }
+
+    #[test]
+    fn test_caret_with_tabs() {
+        let path = crate::temp_path("context_tabs.s");
+        std::fs::write(&path, "main:\n\tfoo a0, 1\nx:\tbar\n").unwrap();
+
+        let caret_under = |line, column| {
+            let ctx = Context {
+                file: Rc::new(path.to_str().unwrap().to_owned()),
+                line,
+                column,
+            };
+            let output = strip_colors(&ctx.to_string());
+            let lines: Vec<_> = output.lines().collect();
+            let caret = lines.iter().position(|l| l.ends_with("^ Here")).unwrap();
+            let offset = lines[caret].find('^').unwrap();
+            lines[caret - 1].chars().nth(offset).unwrap()
+        };
+
+        // The lexer stores the column after the first character of a token, and the caret points
+        // to the character before it
+        assert_eq!(caret_under(2, 6), 'f');
+        assert_eq!(caret_under(3, 8), 'b');
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
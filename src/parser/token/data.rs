@@ -8,13 +8,67 @@ pub enum Data {
     Label(String),
     Char(char),
     Integer(i32),
-    Float(f32),
+    Float(f64),
     StringLiteral(String),
     CharLiteral(char),
     MacroArg(String),
+    Relocation(Relocation),
+    /// Definition of a numeric local label, like `1:`
+    NumericLabel(u32),
+    /// Reference to the next (`1f`) or previous (`1b`) definition of a numeric local label
+    NumericLabelRef {
+        number: u32,
+        forward: bool,
+    },
+}
+
+/// Relocation operators, like the `%hi` in `lui a0, %hi(label)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relocation {
+    /// The upper 20 bits of an address, to be used with `lui`/`auipc`
+    Hi,
+    /// The lower 12 bits of an address, sign extended, to be added to the result of `%hi`
+    Lo,
+}
+
+impl Relocation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hi" => Some(Relocation::Hi),
+            "lo" => Some(Relocation::Lo),
+            _ => None,
+        }
+    }
+
+    /// Applies the relocation to an address. `(Hi.apply(x) << 12) + Lo.apply(x) == x`
+    pub fn apply(self, addr: u32) -> u32 {
+        match self {
+            // +0x800 compensates for the sign extension of the lower bits
+            Relocation::Hi => addr.wrapping_add(0x800) >> 12,
+            Relocation::Lo => ((addr << 20) as i32 >> 20) as u32,
+        }
+    }
+}
+
+impl fmt::Display for Relocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Relocation::Hi => write!(f, "%hi"),
+            Relocation::Lo => write!(f, "%lo"),
+        }
+    }
 }
 
 impl Data {
+    /// Whether this is an integer too big for 32 bits, like `4294967296`. The lexer reads those as
+    /// floats, so they still work in `.float`.
+    pub fn is_oversized_integer(&self) -> bool {
+        match self {
+            Data::Float(f) => f.fract() == 0.0 && f.abs() >= (1u64 << 32) as f64,
+            _ => false,
+        }
+    }
+
     pub fn extract_u32(&self) -> Option<u32> {
         match self {
             Data::Integer(i) => Some(*i as u32),
@@ -36,6 +90,11 @@ impl fmt::Display for Data {
             Data::StringLiteral(s) => write!(f, "\"{}\"", s),
             Data::CharLiteral(c) => write!(f, "'{}'", c),
             Data::MacroArg(a) => write!(f, "%{}", a),
+            Data::Relocation(r) => write!(f, "{}", r),
+            Data::NumericLabel(n) => write!(f, "{}:", n),
+            Data::NumericLabelRef { number, forward } => {
+                write!(f, "{}{}", number, if *forward { 'f' } else { 'b' })
+            }
         }
     }
 }
@@ -1,7 +1,7 @@
 pub mod context;
 pub mod data;
 pub use context::{Context, ManyContexts};
-pub use data::Data;
+pub use data::{Data, Relocation};
 
 use super::error::{Contextualize, Error};
 
@@ -10,6 +10,8 @@ use super::error::{Contextualize, Error};
 pub struct Token {
     pub data: Data,
     pub ctx: Context,
+    /// Number of columns the token takes up in the source, so errors can point right after it
+    pub width: u32,
 }
 
 impl Token {
@@ -17,6 +19,7 @@ impl Token {
         Self {
             data,
             ctx: Context::empty(),
+            width: 0,
         }
     }
 
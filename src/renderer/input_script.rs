@@ -0,0 +1,166 @@
+//!
+//! Scripted keyboard input, used to test interactive programs without a window (`--no-video`).
+//!
+//! A script has one event per line:
+//! ```text
+//! # comments start with '#'
+//! char a         # types the character 'a'
+//! keydown 0x1c   # presses the key with scancode 0x1c
+//! sleep 100      # waits 100ms
+//! keyup 0x1c     # releases the key with scancode 0x1c
+//! ```
+//!
+
+use super::InputSignal;
+use crate::config::parse_number;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptEvent {
+    Char(char),
+    KeyDown(u8),
+    KeyUp(u8),
+    Sleep(u64),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ScriptError {
+    #[error("line {0}: unknown event '{1}'")]
+    UnknownEvent(usize, String),
+
+    #[error("line {0}: missing argument for '{1}'")]
+    MissingArgument(usize, String),
+
+    #[error("line {0}: invalid argument '{1}'")]
+    InvalidArgument(usize, String),
+}
+
+pub fn parse(script: &str) -> Result<Vec<ScriptEvent>, ScriptError> {
+    let mut events = Vec::new();
+
+    for (i, line) in script.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+
+        let Some(event) = words.next() else {
+            continue;
+        };
+
+        let arg = words
+            .next()
+            .ok_or_else(|| ScriptError::MissingArgument(line_number, event.to_owned()))?;
+        let invalid = || ScriptError::InvalidArgument(line_number, arg.to_owned());
+        let scancode = || {
+            parse_number(arg)
+                .and_then(|x| u8::try_from(x).ok())
+                .ok_or_else(invalid)
+        };
+
+        let event = match event {
+            "char" => {
+                let mut chars = arg.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => ScriptEvent::Char(c),
+                    _ => return Err(invalid()),
+                }
+            }
+            "keydown" => ScriptEvent::KeyDown(scancode()?),
+            "keyup" => ScriptEvent::KeyUp(scancode()?),
+            "sleep" => ScriptEvent::Sleep(parse_number(arg).ok_or_else(invalid)?),
+            _ => return Err(ScriptError::UnknownEvent(line_number, event.to_owned())),
+        };
+        events.push(event);
+    }
+
+    Ok(events)
+}
+
+/// Writes an event to the keyboard MMIO, exactly like the bitmap display window would
+pub fn apply(mmio: &mut [u8], event: ScriptEvent) {
+    match event {
+        ScriptEvent::Char(c) => super::receive_char(mmio, c),
+        ScriptEvent::KeyDown(key) => super::key_down(mmio, key),
+        ScriptEvent::KeyUp(key) => super::key_up(mmio, key),
+        ScriptEvent::Sleep(_) => {}
+    }
+}
+
+/// Plays the events in a separate thread. `input_signal` is notified after each key event, like
+/// the display does.
+pub fn spawn(
+    mmio: Arc<Mutex<Vec<u8>>>,
+    events: Vec<ScriptEvent>,
+    input_signal: InputSignal,
+) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("FPGRARS Input Script".into())
+        .spawn(move || {
+            for event in events {
+                match event {
+                    ScriptEvent::Sleep(ms) => thread::sleep(Duration::from_millis(ms)),
+                    event => {
+                        apply(&mut mmio.lock(), event);
+                        input_signal.notify();
+                    }
+                }
+            }
+        })
+        .expect("Failed to spawn the input script thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{KDMMIO_CONTROL, KDMMIO_DATA, KEYBUFFER, KEYBUFFER_TAIL, KEYMAP};
+    use crate::simulator::memory::MMIO_SIZE;
+
+    #[test]
+    fn test_parse() {
+        let script = "# setup\nchar a\n\nkeydown 0x1c # enter\nsleep 100\nkeyup 28\n";
+        assert_eq!(
+            parse(script),
+            Ok(vec![
+                ScriptEvent::Char('a'),
+                ScriptEvent::KeyDown(0x1c),
+                ScriptEvent::Sleep(100),
+                ScriptEvent::KeyUp(0x1c),
+            ])
+        );
+
+        assert_eq!(
+            parse("keydown"),
+            Err(ScriptError::MissingArgument(1, "keydown".to_owned()))
+        );
+        assert_eq!(
+            parse("sleep 1\nkeydown 0x100"),
+            Err(ScriptError::InvalidArgument(2, "0x100".to_owned()))
+        );
+        assert_eq!(
+            parse("press 1"),
+            Err(ScriptError::UnknownEvent(1, "press".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_keydown_lands_in_mmio() {
+        let mut mmio = vec![0; MMIO_SIZE];
+        for event in parse("keydown 0x1c\nchar x").unwrap() {
+            apply(&mut mmio, event);
+        }
+
+        assert_eq!(mmio[KEYBUFFER], 0x1c);
+        assert_eq!(mmio[KEYMAP + 0x1c / 8], 1 << (0x1c % 8));
+        assert_eq!(mmio[KDMMIO_CONTROL], 1);
+        assert_eq!(mmio[KDMMIO_DATA], b'x');
+
+        apply(&mut mmio, ScriptEvent::KeyUp(0x1c));
+        assert_eq!(mmio[KEYBUFFER..KEYBUFFER + 3], [0x1c, 0xF0, 0x1c]);
+        assert_eq!(mmio[KEYBUFFER_TAIL], 3);
+        assert_eq!(mmio[KEYMAP + 0x1c / 8], 0);
+    }
+}
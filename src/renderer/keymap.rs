@@ -0,0 +1,76 @@
+//!
+//! Remaps the scancodes given by the window to the ones the program expects, since scancodes
+//! differ across platforms. A keymap file is a TOML table of `scancode = value` pairs:
+//! ```toml
+//! # Linux scancode = value expected by the program
+//! 0x1c = 0x5a
+//! 57 = 0x29
+//! ```
+//!
+
+use crate::config::parse_number;
+use hashbrown::HashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum KeymapError {
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("invalid scancode '{0}'")]
+    InvalidScancode(String),
+
+    #[error("'{1}' is not a valid value for scancode '{0}', it should fit in a byte")]
+    InvalidValue(String, i64),
+}
+
+/// Maps scancodes to the values written to the keyboard MMIO. Scancodes that aren't in the map
+/// are written unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Keymap(HashMap<u32, u8>);
+
+impl Keymap {
+    pub fn from_toml(source: &str) -> Result<Self, KeymapError> {
+        let table: std::collections::HashMap<String, i64> = toml::from_str(source)?;
+
+        let mut map = HashMap::with_capacity(table.len());
+        for (scancode, value) in table {
+            let key = parse_number(&scancode)
+                .and_then(|x| u32::try_from(x).ok())
+                .ok_or_else(|| KeymapError::InvalidScancode(scancode.clone()))?;
+            let value =
+                u8::try_from(value).map_err(|_| KeymapError::InvalidValue(scancode, value))?;
+            map.insert(key, value);
+        }
+
+        Ok(Self(map))
+    }
+
+    pub fn get(&self, scancode: u32) -> u8 {
+        self.0.get(&scancode).copied().unwrap_or(scancode as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keymap() {
+        let keymap = Keymap::from_toml("0x1c = 0x5a\n57 = 41").unwrap();
+        assert_eq!(keymap.get(0x1c), 0x5a);
+        assert_eq!(keymap.get(57), 41);
+        assert_eq!(keymap.get(30), 30);
+
+        assert_eq!(Keymap::default().get(0x1c), 0x1c);
+
+        assert_eq!(
+            Keymap::from_toml("a = 1"),
+            Err(KeymapError::InvalidScancode("a".to_owned()))
+        );
+        assert_eq!(
+            Keymap::from_toml("1 = 256"),
+            Err(KeymapError::InvalidValue("1".to_owned(), 256))
+        );
+    }
+}
@@ -1,16 +1,43 @@
-use glium::glutin;
-use parking_lot::Mutex;
+pub mod input_script;
+pub mod keymap;
+
+use glium::glutin::{
+    self,
+    event::{ModifiersState, VirtualKeyCode},
+};
+use parking_lot::{Condvar, Mutex};
 use pixel_canvas::{
     canvas::CanvasInfo,
     input::{Event, WindowEvent},
     Canvas, Color,
 };
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::mem;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub const FRAME_SELECT: usize = 0x20_0604;
+/// Number of frames drawn by the bitmap display, as a word. Programs can read it to sync with the
+/// display without an ecall. Only the display should write to it.
+pub const FRAME_COUNTER: usize = 0x20_0608;
+/// Width and height of the display in pixels, and the number of bytes in each pixel, as words.
+/// They're written once when the memory is created, so programs can adapt to `--width` and
+/// `--height`, and writes to them are ignored.
+pub const DISPLAY_WIDTH: usize = 0x20_0610;
+pub const DISPLAY_HEIGHT: usize = 0x20_0614;
+pub const DISPLAY_BYTES_PER_PIXEL: usize = 0x20_0618;
 pub const FRAME_0: usize = 0;
 pub const FRAME_1: usize = 0x10_0000;
 pub const FRAME_SIZE: usize = FRAME_1 - FRAME_0;
+/// Drawn on top of the selected frame with `--layers 2`, like a HUD. Transparent bytes in it show
+/// the frame below. It's outside of the video memory, so transparent bytes can be written to it.
+pub const OVERLAY_FRAME: usize = 0x22_0000;
+/// The keyboard and display registers, between the frames and the overlay. Frames can't be moved
+/// over them.
+const CONTROL_MMIO: Range<usize> = KDMMIO_CONTROL..OVERLAY_FRAME;
 
 /// Control bit for the Keyboard (Display?) MMIO.
 /// `mmio[KDMMIO_CONTROL] == 1` means that a new key has been put in `mmio[KDMMIO_DATA]`, like a
@@ -25,18 +52,77 @@ pub const KDMMIO_DATA: usize = 0x20_0004;
 pub const KDMMIO_KEYDOWN: usize = 0x21_0000;
 pub const KDMMIO_DATADOWN: usize = 0x21_0004;
 
-const KEYBUFFER: usize = 0x20_0100;
-const KEYBUFFER_SIZE: usize = 8;
+/// The last 8 bytes of key events, newest first, like the keyboard buffer of the FPGA. Each event
+/// shifts the older ones forward, so events are lost when they come in quickly.
+const LEGACY_KEYBUFFER: usize = 0x20_0100;
+const LEGACY_KEYBUFFER_SIZE: usize = 8;
+
+/// The keyboard buffer is a ring of `KEYBUFFER_SIZE` bytes at `mmio[KEYBUFFER]`, with the
+/// scancodes of the key events in the order they happened (key releases are `0xF0` followed by the
+/// scancode). `mmio[KEYBUFFER_TAIL]` is the word index where the display writes the next byte, and
+/// `mmio[KEYBUFFER_HEAD]` is the word index of the next byte the program should read, so there are
+/// unread events while they're different. Programs consume events by advancing the head, modulo
+/// `KEYBUFFER_SIZE`. When the buffer is full, new events are dropped and
+/// `mmio[KEYBUFFER_OVERFLOW]` is set to 1 until the program clears it.
+pub const KEYBUFFER_HEAD: usize = 0x20_0200;
+pub const KEYBUFFER_TAIL: usize = 0x20_0204;
+pub const KEYBUFFER_OVERFLOW: usize = 0x20_0208;
+pub const KEYBUFFER: usize = 0x20_0210;
+pub const KEYBUFFER_SIZE: usize = 256;
 const KEYMAP: usize = 0x20_0520;
 
-fn push_key_to_buffer(mmio: &mut [u8], key: u8) {
-    // Shift buffer
-    for i in (KEYBUFFER + 1..KEYBUFFER + KEYBUFFER_SIZE).rev() {
-        mmio[i] = mmio[i - 1];
+/// `mmio[KEYMODIFIERS]` has one bit for each modifier key that's currently held:
+///
+/// | bit | key         |
+/// |-----|-------------|
+/// | 0   | left shift  |
+/// | 1   | right shift |
+/// | 2   | left ctrl   |
+/// | 3   | right ctrl  |
+/// | 4   | left alt    |
+/// | 5   | right alt   |
+/// | 6   | left logo   |
+/// | 7   | right logo  |
+///
+/// So `mmio[KEYMODIFIERS] & 0b11 != 0` means that some shift is down.
+pub const KEYMODIFIERS: usize = 0x20_0540;
+
+fn read_word(mmio: &[u8], address: usize) -> u32 {
+    u32::from_le_bytes(mmio[address..address + 4].try_into().unwrap())
+}
+
+fn write_word(mmio: &mut [u8], address: usize, value: u32) {
+    mmio[address..address + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Shifts the bytes of one key event into the legacy buffer
+fn push_key_to_legacy_buffer(mmio: &mut [u8], bytes: &[u8]) {
+    let buffer = &mut mmio[LEGACY_KEYBUFFER..LEGACY_KEYBUFFER + LEGACY_KEYBUFFER_SIZE];
+    for &byte in bytes {
+        buffer.copy_within(..LEGACY_KEYBUFFER_SIZE - 1, 1);
+        buffer[0] = byte;
     }
+}
 
-    // Push key to mmio[KEYBUFFER]
-    mmio[KEYBUFFER] = key;
+/// Enqueues the bytes of one key event. If they don't all fit, none of them are written, so the
+/// program never sees half of an event.
+fn push_key_to_buffer(mmio: &mut [u8], bytes: &[u8]) {
+    let head = read_word(mmio, KEYBUFFER_HEAD) as usize % KEYBUFFER_SIZE;
+    let tail = read_word(mmio, KEYBUFFER_TAIL) as usize % KEYBUFFER_SIZE;
+
+    // One slot is always left empty, otherwise a full buffer would look empty
+    let used = (tail + KEYBUFFER_SIZE - head) % KEYBUFFER_SIZE;
+    if used + bytes.len() >= KEYBUFFER_SIZE {
+        mmio[KEYBUFFER_OVERFLOW] = 1;
+        return;
+    }
+
+    let mut tail = tail;
+    for &byte in bytes {
+        mmio[KEYBUFFER + tail] = byte;
+        tail = (tail + 1) % KEYBUFFER_SIZE;
+    }
+    write_word(mmio, KEYBUFFER_TAIL, tail as u32);
 }
 
 fn push_key_to_map(mmio: &mut [u8], key: u8) {
@@ -49,14 +135,129 @@ fn remove_key_from_map(mmio: &mut [u8], key: u8) {
     mmio[KEYMAP + byte as usize] &= !(1 << bit);
 }
 
+/// Bit of `mmio[KEYMODIFIERS]` that represents `key`, if it's a modifier
+fn modifier_bit(key: VirtualKeyCode) -> Option<u8> {
+    use VirtualKeyCode::*;
+    let bit = match key {
+        LShift => 0,
+        RShift => 1,
+        LControl => 2,
+        RControl => 3,
+        LAlt => 4,
+        RAlt => 5,
+        LWin => 6,
+        RWin => 7,
+        _ => return None,
+    };
+    Some(1 << bit)
+}
+
+/// A modifier key was pressed or released. Other keys are ignored.
+fn update_modifier(mmio: &mut [u8], key: VirtualKeyCode, pressed: bool) {
+    if let Some(bit) = modifier_bit(key) {
+        if pressed {
+            mmio[KEYMODIFIERS] |= bit;
+        } else {
+            mmio[KEYMODIFIERS] &= !bit;
+        }
+    }
+}
+
+/// Clears the modifiers in `mmio[KEYMODIFIERS]` that the window reports as released, such as the
+/// ones released while it wasn't focused. Bits are never set here: the window sends this event
+/// before the `KeyboardInput` of the key itself, and it doesn't know which side was pressed.
+fn sync_modifiers(mmio: &mut [u8], modifiers: ModifiersState) {
+    let held = [
+        modifiers.shift(),
+        modifiers.ctrl(),
+        modifiers.alt(),
+        modifiers.logo(),
+    ];
+    for (i, held) in held.into_iter().enumerate() {
+        if !held {
+            mmio[KEYMODIFIERS] &= !(0b11 << (2 * i));
+        }
+    }
+}
+
+/// Pauses the simulation if it's running, or resumes it if it's paused. Returns whether it's
+/// paused now.
+fn toggle_pause(paused: &AtomicBool) -> bool {
+    !paused.fetch_xor(true, Ordering::Relaxed)
+}
+
+/// A character was typed
+fn receive_char(mmio: &mut [u8], chr: char) {
+    let chr = if chr == '\r' { '\n' } else { chr };
+
+    mmio[KDMMIO_CONTROL] = 1;
+    mmio[KDMMIO_DATA] = chr as u8;
+
+    mmio[KDMMIO_KEYDOWN] = 1;
+    mmio[KDMMIO_DATADOWN] = chr as u8;
+}
+
+/// The key with scancode `key` was pressed
+fn key_down(mmio: &mut [u8], key: u8) {
+    push_key_to_legacy_buffer(mmio, &[key]);
+    push_key_to_buffer(mmio, &[key]);
+    push_key_to_map(mmio, key);
+}
+
+/// The key with scancode `key` was released
+fn key_up(mmio: &mut [u8], key: u8) {
+    mmio[KDMMIO_KEYDOWN] = 0;
+
+    push_key_to_legacy_buffer(mmio, &[0xF0, key]);
+    push_key_to_buffer(mmio, &[0xF0, key]);
+
+    remove_key_from_map(mmio, key);
+}
+
+/// Signaled by the display and input scripts when a key is typed, so a program that's waiting for
+/// input (with ecall 111) can sleep instead of spinning
+#[derive(Debug, Clone, Default)]
+pub struct InputSignal(Arc<(Mutex<bool>, Condvar)>);
+
+impl InputSignal {
+    pub fn notify(&self) {
+        let (pending, condvar) = &*self.0;
+        *pending.lock() = true;
+        condvar.notify_all();
+    }
+
+    /// Blocks until there's a notification, or until `timeout` passes. Notifications that came
+    /// before the call count too. Returns whether there was one.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (pending, condvar) = &*self.0;
+        let mut pending = pending.lock();
+        if !*pending {
+            condvar.wait_for(&mut pending, timeout);
+        }
+        mem::take(&mut *pending)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct State {
     mmio: Arc<Mutex<Vec<u8>>>,
     width: usize,
     height: usize,
     pixel_scale: usize,
+    keymap: keymap::Keymap,
+    /// Shared with the simulator, which stops running while it's set. Toggled by `PAUSE_KEY`
+    paused: Arc<AtomicBool>,
+    /// Shared with the simulator, notified on every key event
+    input_signal: InputSignal,
+    /// 2 to draw `OVERLAY_FRAME` on top of the selected frame
+    layers: usize,
+    transparent: Option<u8>,
+    layout: FrameLayout,
 }
 
+/// Pauses or resumes the simulation
+const PAUSE_KEY: glutin::event::VirtualKeyCode = glutin::event::VirtualKeyCode::F2;
+
 impl State {
     pub fn new(mmio: Arc<Mutex<Vec<u8>>>, width: usize, height: usize, pixel_scale: usize) -> Self {
         Self {
@@ -64,9 +265,42 @@ impl State {
             width,
             height,
             pixel_scale,
+            keymap: keymap::Keymap::default(),
+            paused: Arc::default(),
+            input_signal: InputSignal::default(),
+            layers: 1,
+            transparent: None,
+            layout: FrameLayout::new(width),
         }
     }
 
+    pub fn with_pause_flag(mut self, paused: Arc<AtomicBool>) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    pub fn with_input_signal(mut self, input_signal: InputSignal) -> Self {
+        self.input_signal = input_signal;
+        self
+    }
+
+    /// Draws `layers` layers. The overlay is composed with the `transparent` byte.
+    pub fn with_layers(mut self, layers: usize, transparent: Option<u8>) -> Self {
+        self.layers = layers;
+        self.transparent = transparent;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: FrameLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn with_keymap(mut self, keymap: keymap::Keymap) -> Self {
+        self.keymap = keymap;
+        self
+    }
+
     fn handle_input(_info: &CanvasInfo, state: &mut State, event: &Event<()>) -> bool {
         match event {
             // Match a received character
@@ -74,16 +308,30 @@ impl State {
                 event: WindowEvent::ReceivedCharacter(chr),
                 ..
             } => {
-                let chr = if *chr == '\r' { '\n' } else { *chr };
-
-                let mut mmio = state.mmio.lock();
-
-                mmio[KDMMIO_CONTROL] = 1;
-                mmio[KDMMIO_DATA] = chr as u8;
-
-                mmio[KDMMIO_KEYDOWN] = 1;
-                mmio[KDMMIO_DATADOWN] = chr as u8;
+                receive_char(&mut state.mmio.lock(), *chr);
+                state.input_signal.notify();
+                true
+            }
 
+            // The pause key isn't sent to the program
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            glutin::event::KeyboardInput {
+                                state: key_state,
+                                virtual_keycode: Some(PAUSE_KEY),
+                                ..
+                            },
+                        is_synthetic: false,
+                        ..
+                    },
+                ..
+            } => {
+                if *key_state == glutin::event::ElementState::Pressed {
+                    let paused = toggle_pause(&state.paused);
+                    eprintln!("{}", if paused { "Paused" } else { "Resumed" });
+                }
                 true
             }
 
@@ -95,6 +343,7 @@ impl State {
                             glutin::event::KeyboardInput {
                                 state: glutin::event::ElementState::Pressed,
                                 scancode: key,
+                                virtual_keycode,
                                 ..
                             },
                         is_synthetic: false,
@@ -103,10 +352,12 @@ impl State {
                 ..
             } => {
                 let mut mmio = state.mmio.lock();
-
-                push_key_to_buffer(&mut mmio, *key as u8);
-                push_key_to_map(&mut mmio, *key as u8);
-
+                key_down(&mut mmio, state.keymap.get(*key));
+                if let Some(virtual_keycode) = virtual_keycode {
+                    update_modifier(&mut mmio, *virtual_keycode, true);
+                }
+                drop(mmio);
+                state.input_signal.notify();
                 true
             }
 
@@ -118,6 +369,7 @@ impl State {
                             glutin::event::KeyboardInput {
                                 state: glutin::event::ElementState::Released,
                                 scancode: key,
+                                virtual_keycode,
                                 ..
                             },
                         is_synthetic: false,
@@ -126,14 +378,20 @@ impl State {
                 ..
             } => {
                 let mut mmio = state.mmio.lock();
+                key_up(&mut mmio, state.keymap.get(*key));
+                if let Some(virtual_keycode) = virtual_keycode {
+                    update_modifier(&mut mmio, *virtual_keycode, false);
+                }
+                drop(mmio);
+                state.input_signal.notify();
+                true
+            }
 
-                mmio[KDMMIO_KEYDOWN] = 0;
-
-                push_key_to_buffer(&mut mmio, 0xF0);
-                push_key_to_buffer(&mut mmio, *key as u8);
-
-                remove_key_from_map(&mut mmio, *key as u8);
-
+            Event::WindowEvent {
+                event: WindowEvent::ModifiersChanged(modifiers),
+                ..
+            } => {
+                sync_modifiers(&mut state.mmio.lock(), *modifiers);
                 true
             }
 
@@ -143,18 +401,19 @@ impl State {
 }
 
 /// Provides the color that should be drawn at position (y, x) of the display
-/// Basically a trait alias for Fn(memory, y, x) -> Color
-/// The given `memory` slice starts at the beginning of the current frame
+/// Basically a trait alias for Fn(memory, overlay, y, x) -> Color
+/// The given `memory` slice starts at the beginning of the current frame, and `overlay` at the
+/// beginning of the overlay, if it's enabled
 trait ColorProvider {
-    fn get(&self, memory: &[u8], y: usize, x: usize) -> Color;
+    fn get(&self, memory: &[u8], overlay: Option<&[u8]>, y: usize, x: usize) -> Color;
 }
 
 impl<F> ColorProvider for F
 where
-    F: Fn(&[u8], usize, usize) -> Color,
+    F: Fn(&[u8], Option<&[u8]>, usize, usize) -> Color,
 {
-    fn get(&self, memory: &[u8], y: usize, x: usize) -> Color {
-        self(memory, y, x)
+    fn get(&self, memory: &[u8], overlay: Option<&[u8]>, y: usize, x: usize) -> Color {
+        self(memory, overlay, y, x)
     }
 }
 
@@ -173,10 +432,11 @@ fn init_with_provider(initial_state: State, color_prov: impl ColorProvider + 'st
     let canvas = canvas.show_ms(true);
 
     canvas.render(move |state, image| {
-        let mmio = state.mmio.lock();
+        let mut mmio = state.mmio.lock();
+        count_frame(&mut mmio);
 
-        let frame = mmio[FRAME_SELECT];
-        let start = if frame == 0 { FRAME_0 } else { FRAME_1 };
+        let frame = state.layout.frame(&mmio);
+        let overlay = (state.layers > 1).then(|| &mmio[OVERLAY_FRAME..]);
 
         // Draw each MMIO pixel as a SCALExSCALE square
         for (y, row) in image
@@ -184,75 +444,527 @@ fn init_with_provider(initial_state: State, color_prov: impl ColorProvider + 'st
             .enumerate()
         {
             for (x, pixel) in row.iter_mut().enumerate() {
-                *pixel = color_prov.get(&mmio[start..], y, x);
+                *pixel = color_prov.get(frame, overlay, y, x);
             }
         }
     });
 }
 
-/// Init the 8-bit (BBGGGRRR) format bitmap display
+/// Number of bytes a pixel takes up in the video memory
 #[cfg(feature = "unb")]
-pub fn init(state: State) {
-    let color_to_rgb = |x: u8| {
-        let r = x & 0b111;
-        let g = (x >> 3) & 0b111;
-        let b = x >> 6;
-        Color {
-            r: r * 36,
-            g: g * 36,
-            b: b * 85,
-        }
-    };
+pub const BYTES_PER_PIXEL: usize = 1;
 
-    let pixel_scale = state.pixel_scale;
-    let width = state.width;
-    let height = state.height;
+/// Number of bytes a pixel takes up in the video memory.
+/// Note: the 24-bit format is word-aligned, which means every color takes up
+/// 32 bits in memory, but only 24 are actually used
+#[cfg(not(feature = "unb"))]
+pub const BYTES_PER_PIXEL: usize = 4;
 
-    let color_provider = move |mmio: &[u8], y: usize, x: usize| {
-        let (x, y) = (x / pixel_scale, height - 1 - y / pixel_scale);
-        let index = y * width + x;
+/// Number of bytes read to decode a pixel. Pixels can't be closer than this.
+#[cfg(feature = "unb")]
+pub const PIXEL_READ_SIZE: usize = 1;
+
+/// Number of bytes read to decode a pixel. Pixels can't be closer than this.
+#[cfg(not(feature = "unb"))]
+pub const PIXEL_READ_SIZE: usize = 3;
+
+/// The bytes of the pixel at byte `offset` of a frame. A pixel that doesn't fit in the frame reads
+/// as zeros, so a frame sliced to exactly its pixels can't be read past its end.
+#[inline]
+fn pixel_bytes(frame: &[u8], offset: usize) -> [u8; PIXEL_READ_SIZE] {
+    match frame.get(offset..offset + PIXEL_READ_SIZE) {
+        Some(bytes) => bytes.try_into().unwrap(),
+        None => [0; PIXEL_READ_SIZE],
+    }
+}
+
+/// Where the pixels are in the MMIO. The default layout has the frames at `FRAME_0` and
+/// `FRAME_1`, with the rows one after the other, but some hardware pads them, for example.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLayout {
+    /// Offset of frame 0 in the MMIO. Frame 1 comes `FRAME_SIZE` bytes after it.
+    pub base: usize,
+    /// Bytes from the start of a row to the start of the next one
+    pub stride: usize,
+    /// Bytes from the start of a pixel to the start of the next one
+    pub bytes_per_pixel: usize,
+}
+
+impl Default for FrameLayout {
+    /// The default layout of the default 320px wide display
+    fn default() -> Self {
+        Self::new(320)
+    }
+}
 
-        let x = if cfg!(debug_assertions) {
-            *mmio
-                .get(index)
-                .expect("Out of bound access to the video memory!")
+impl FrameLayout {
+    /// The default layout of a display `width` pixels wide
+    pub fn new(width: usize) -> Self {
+        Self {
+            base: FRAME_0,
+            stride: width * BYTES_PER_PIXEL,
+            bytes_per_pixel: BYTES_PER_PIXEL,
+        }
+    }
+
+    /// Offset of the pixel at (`x`, `y`) from the start of a frame
+    #[inline]
+    pub fn offset(&self, x: usize, y: usize) -> usize {
+        y * self.stride + x * self.bytes_per_pixel
+    }
+
+    /// Returns the frame currently selected by `mmio[FRAME_SELECT]`
+    pub fn frame<'a>(&self, mmio: &'a [u8]) -> &'a [u8] {
+        let start = if mmio[FRAME_SELECT] == 0 {
+            self.base
         } else {
-            unsafe { *mmio.get_unchecked(index) }
+            self.base + FRAME_SIZE
         };
+        &mmio[start..]
+    }
 
-        color_to_rgb(x)
-    };
+    /// Bytes from the start of a `width`x`height` frame to the end of its last pixel
+    pub fn frame_len(&self, width: usize, height: usize) -> usize {
+        self.offset(width - 1, height - 1) + PIXEL_READ_SIZE
+    }
 
-    init_with_provider(state, color_provider);
+    /// MMIO offsets of the pixels of both frames of a `width`x`height` display. With the default
+    /// layout, frames too big for it stop at the registers.
+    pub fn video(&self, width: usize, height: usize) -> Range<usize> {
+        let end = self.base + FRAME_SIZE + self.frame_len(width, height);
+        self.base..end.min(CONTROL_MMIO.start)
+    }
+
+    /// Whether every pixel of a `width`x`height` display, in both frames and in the overlay,
+    /// is inside an MMIO of `mmio_size` bytes, without overlapping other pixels or the registers
+    pub fn fits(&self, width: usize, height: usize, layers: usize, mmio_size: usize) -> bool {
+        if width == 0 || height == 0 || self.bytes_per_pixel < PIXEL_READ_SIZE {
+            return false;
+        }
+
+        let row_len = self.offset(width - 1, 0) + PIXEL_READ_SIZE;
+        if height > 1 && self.stride < row_len {
+            return false;
+        }
+
+        // Frame 1 starts `FRAME_SIZE` bytes after frame 0
+        let frame_len = self.frame_len(width, height);
+        if frame_len > FRAME_SIZE {
+            return false;
+        }
+
+        let video = self.base..self.base + FRAME_SIZE + frame_len;
+        let overlaps = |other: Range<usize>| video.start < other.end && other.start < video.end;
+        let overlay = OVERLAY_FRAME..OVERLAY_FRAME + frame_len;
+        if overlaps(CONTROL_MMIO) || (layers > 1 && overlaps(overlay.clone())) {
+            return false;
+        }
+
+        let end = if layers > 1 {
+            video.end.max(overlay.end)
+        } else {
+            video.end
+        };
+        end <= mmio_size
+    }
 }
 
-/// Init the 24-bit (R8G8B8) format bitmap display
-/// Note: this format is word-aligned, which means every color takes up
-/// 32 bits in memory, but only 24 are actually used
+/// Decodes the color of the pixel at byte `offset` of a frame in the 8-bit (BBGGGRRR) format
+#[cfg(feature = "unb")]
+#[inline]
+pub fn decode_pixel_at(frame: &[u8], offset: usize) -> Color {
+    let [x] = pixel_bytes(frame, offset);
+    let r = x & 0b111;
+    let g = (x >> 3) & 0b111;
+    let b = x >> 6;
+    Color {
+        r: r * 36,
+        g: g * 36,
+        b: b * 85,
+    }
+}
+
+/// Decodes the color of the pixel at byte `offset` of a frame in the 24-bit (R8G8B8) format
 #[cfg(not(feature = "unb"))]
+#[inline]
+pub fn decode_pixel_at(frame: &[u8], offset: usize) -> Color {
+    let [b, g, r] = pixel_bytes(frame, offset);
+    Color { r, g, b }
+}
+
+/// Decodes the color of the `index`-th pixel of a frame with the default layout
+#[inline]
+pub fn decode_pixel(frame: &[u8], index: usize) -> Color {
+    decode_pixel_at(frame, index * BYTES_PER_PIXEL)
+}
+
+/// Decodes the pixel at byte `offset` of `overlay` drawn on top of `frame`. Like in writes to the
+/// video memory, each transparent byte of the overlay lets the byte below it show.
+pub fn decode_layered_pixel(
+    frame: &[u8],
+    overlay: &[u8],
+    offset: usize,
+    transparent: Option<u8>,
+) -> Color {
+    let mut pixel = pixel_bytes(overlay, offset);
+    for (top, below) in pixel.iter_mut().zip(pixel_bytes(frame, offset)) {
+        if Some(*top) == transparent {
+            *top = below;
+        }
+    }
+    decode_pixel_at(&pixel, 0)
+}
+
+/// Increments the word at `mmio[FRAME_COUNTER]`, wrapping around
+fn count_frame(mmio: &mut [u8]) {
+    let frames = read_word(mmio, FRAME_COUNTER);
+    write_word(mmio, FRAME_COUNTER, frames.wrapping_add(1));
+}
+
+/// Decodes a `width`x`height` frame with the given layout to RGB8 bytes, top row first
+pub fn frame_to_rgb(frame: &[u8], width: usize, height: usize, layout: &FrameLayout) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let Color { r, g, b } = decode_pixel_at(frame, layout.offset(x, y));
+            rgb.extend_from_slice(&[r, g, b]);
+        }
+    }
+    rgb
+}
+
+/// Writes the current frame of the bitmap display to a PNG file
+pub fn screenshot(
+    mmio: &[u8],
+    width: usize,
+    height: usize,
+    layout: &FrameLayout,
+    path: &str,
+) -> io::Result<()> {
+    let rgb = frame_to_rgb(layout.frame(mmio), width, height, layout);
+
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&rgb)?;
+
+    Ok(())
+}
+
+/// Init the bitmap display, in the 8-bit (BBGGGRRR) format if the `unb` feature is enabled, or
+/// in the 24-bit (R8G8B8) format otherwise
 pub fn init(state: State) {
     let pixel_scale = state.pixel_scale;
-    let width = state.width;
     let height = state.height;
+    let transparent = state.transparent;
+    let layout = state.layout;
 
-    let color_provider = move |mmio: &[u8], y: usize, x: usize| {
-        let bytes_per_pixel = 4;
+    let color_provider = move |mmio: &[u8], overlay: Option<&[u8]>, y: usize, x: usize| {
         let (x, y) = (x / pixel_scale, height - 1 - y / pixel_scale);
-        let index = (y * width + x) * bytes_per_pixel;
-
-        let get = |i| {
-            if cfg!(debug_assertions) {
-                *mmio
-                    .get(i)
-                    .expect("Out of bound access to the video memory!")
-            } else {
-                unsafe { *mmio.get_unchecked(i) }
-            }
-        };
-
-        let (r, g, b) = (get(index + 2), get(index + 1), get(index));
-        Color { r, g, b }
+        let offset = layout.offset(x, y);
+        match overlay {
+            Some(overlay) => decode_layered_pixel(mmio, overlay, offset, transparent),
+            None => decode_pixel_at(mmio, offset),
+        }
     };
 
     init_with_provider(state, color_provider);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::memory::MMIO_SIZE;
+
+    #[cfg(not(feature = "unb"))]
+    #[test]
+    fn test_frame_to_rgb() {
+        // Two pixels in the B G R _ format
+        let frame = [0x10, 0x20, 0x30, 0x00, 0xff, 0x00, 0x80, 0xc7];
+        assert_eq!(
+            frame_to_rgb(&frame, 2, 1, &FrameLayout::new(2)),
+            vec![0x30, 0x20, 0x10, 0x80, 0x00, 0xff]
+        );
+    }
+
+    #[cfg(feature = "unb")]
+    #[test]
+    fn test_frame_to_rgb() {
+        // BBGGGRRR: pure red, pure green, pure blue
+        let frame = [0b00_000_111, 0b00_111_000, 0b11_000_000];
+        assert_eq!(
+            frame_to_rgb(&frame, 3, 1, &FrameLayout::new(3)),
+            vec![252, 0, 0, 0, 252, 0, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn test_last_pixel_of_tight_frame() {
+        let rgb = |c: Color| [c.r, c.g, c.b];
+        let white_ish = if cfg!(feature = "unb") {
+            [252, 252, 255]
+        } else {
+            [0, 0, 0xff]
+        };
+
+        let (width, height) = (3, 2);
+        let mut frame = vec![0; width * height * BYTES_PER_PIXEL];
+        let last = frame.len() - BYTES_PER_PIXEL;
+        frame[last] = 0xff;
+
+        let image = frame_to_rgb(&frame, width, height, &FrameLayout::new(width));
+        assert_eq!(image.len(), width * height * 3);
+        assert_eq!(image[image.len() - 3..], white_ish);
+        assert_eq!(
+            rgb(decode_layered_pixel(&frame, &frame, last, None)),
+            white_ish
+        );
+
+        // Pixels cut off by the end of the frame read as black instead of past it
+        let cut = &frame[..last + PIXEL_READ_SIZE - 1];
+        assert_eq!(
+            rgb(decode_pixel_at(cut, last)),
+            rgb(decode_pixel_at(&[0; 3], 0))
+        );
+        assert_eq!(
+            frame_to_rgb(cut, width, height, &FrameLayout::new(width)),
+            vec![0; width * height * 3]
+        );
+    }
+
+    #[test]
+    fn test_key_buffer() {
+        let mut mmio = vec![0; MMIO_SIZE];
+        key_down(&mut mmio, 0x1c);
+        key_up(&mut mmio, 0x1c);
+        assert_eq!(mmio[KEYBUFFER..KEYBUFFER + 3], [0x1c, 0xF0, 0x1c]);
+        assert_eq!(read_word(&mmio, KEYBUFFER_HEAD), 0);
+        assert_eq!(read_word(&mmio, KEYBUFFER_TAIL), 3);
+
+        // The program consumes two bytes, then the tail wraps around
+        write_word(&mut mmio, KEYBUFFER_HEAD, 2);
+        write_word(&mut mmio, KEYBUFFER_TAIL, KEYBUFFER_SIZE as u32 - 1);
+        key_up(&mut mmio, 0x2a);
+        assert_eq!(mmio[KEYBUFFER + KEYBUFFER_SIZE - 1], 0xF0);
+        assert_eq!(mmio[KEYBUFFER], 0x2a);
+        assert_eq!(read_word(&mmio, KEYBUFFER_TAIL), 1);
+        assert_eq!(mmio[KEYBUFFER_OVERFLOW], 0);
+    }
+
+    #[test]
+    fn test_legacy_key_buffer() {
+        // Programs written for the FPGA read the newest bytes at 0xFF200100 and 0xFF200104
+        let mut mmio = vec![0; MMIO_SIZE];
+        key_down(&mut mmio, 0x1c);
+        key_up(&mut mmio, 0x1c);
+        assert_eq!(
+            mmio[0x20_0100..0x20_0108],
+            [0x1c, 0xF0, 0x1c, 0, 0, 0, 0, 0]
+        );
+
+        for key in 1..=9 {
+            key_down(&mut mmio, key);
+        }
+        assert_eq!(mmio[0x20_0100..0x20_0108], [9, 8, 7, 6, 5, 4, 3, 2]);
+        // The mouse registers of the FPGA come right after it
+        assert_eq!(mmio[0x20_0110..0x20_0118], [0; 8]);
+    }
+
+    #[test]
+    fn test_key_buffer_overflow() {
+        let mut mmio = vec![0; MMIO_SIZE];
+        for _ in 0..KEYBUFFER_SIZE - 2 {
+            key_down(&mut mmio, 0x1c);
+        }
+        assert_eq!(mmio[KEYBUFFER_OVERFLOW], 0);
+
+        // A release takes two bytes, but there's only space for one
+        key_up(&mut mmio, 0x1c);
+        assert_eq!(mmio[KEYBUFFER_OVERFLOW], 1);
+        assert_eq!(read_word(&mmio, KEYBUFFER_TAIL), KEYBUFFER_SIZE as u32 - 2);
+
+        key_down(&mut mmio, 0x2a);
+        assert_eq!(read_word(&mmio, KEYBUFFER_TAIL), KEYBUFFER_SIZE as u32 - 1);
+        key_down(&mut mmio, 0x2a);
+        assert_eq!(read_word(&mmio, KEYBUFFER_TAIL), KEYBUFFER_SIZE as u32 - 1);
+
+        // Reading frees up space again
+        write_word(&mut mmio, KEYBUFFER_HEAD, 10);
+        mmio[KEYBUFFER_OVERFLOW] = 0;
+        key_up(&mut mmio, 0x2a);
+        assert_eq!(read_word(&mmio, KEYBUFFER_TAIL), 1);
+        assert_eq!(mmio[KEYBUFFER_OVERFLOW], 0);
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mut mmio = vec![0; MMIO_SIZE];
+        update_modifier(&mut mmio, VirtualKeyCode::LShift, true);
+        update_modifier(&mut mmio, VirtualKeyCode::RControl, true);
+        update_modifier(&mut mmio, VirtualKeyCode::A, true);
+        assert_eq!(mmio[KEYMODIFIERS], 0b1001);
+
+        update_modifier(&mut mmio, VirtualKeyCode::LShift, false);
+        assert_eq!(mmio[KEYMODIFIERS], 0b1000);
+
+        // Ctrl was released outside of the window
+        sync_modifiers(&mut mmio, ModifiersState::ALT);
+        assert_eq!(mmio[KEYMODIFIERS], 0);
+    }
+
+    #[test]
+    fn test_modifiers_event_order() {
+        // The window reports the new modifiers before the key that changed them
+        let mut mmio = vec![0; MMIO_SIZE];
+        sync_modifiers(&mut mmio, ModifiersState::SHIFT);
+        update_modifier(&mut mmio, VirtualKeyCode::RShift, true);
+        assert_eq!(mmio[KEYMODIFIERS], 0b10);
+
+        sync_modifiers(&mut mmio, ModifiersState::SHIFT | ModifiersState::ALT);
+        update_modifier(&mut mmio, VirtualKeyCode::LAlt, true);
+        assert_eq!(mmio[KEYMODIFIERS], 0b1_0010);
+
+        sync_modifiers(&mut mmio, ModifiersState::ALT);
+        update_modifier(&mut mmio, VirtualKeyCode::RShift, false);
+        assert_eq!(mmio[KEYMODIFIERS], 0b1_0000);
+    }
+
+    #[test]
+    fn test_toggle_pause() {
+        let paused = AtomicBool::new(false);
+        assert!(toggle_pause(&paused));
+        assert!(paused.load(Ordering::Relaxed));
+        assert!(!toggle_pause(&paused));
+        assert!(!paused.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_input_signal() {
+        let signal = InputSignal::default();
+        assert!(!signal.wait(Duration::from_millis(1)));
+
+        // Notifications before waiting aren't lost
+        signal.notify();
+        assert!(signal.wait(Duration::from_secs(5)));
+        assert!(!signal.wait(Duration::from_millis(1)));
+    }
+
+    /// `Color` isn't comparable
+    fn rgb(Color { r, g, b }: Color) -> (u8, u8, u8) {
+        (r, g, b)
+    }
+
+    #[cfg(not(feature = "unb"))]
+    #[test]
+    fn test_decode_layered_pixel() {
+        // B G R _ pixels: the first overlay pixel is transparent, the second covers the frame and
+        // the third is transparent only in the green channel
+        let frame = [
+            0x10, 0x20, 0x30, 0x00, 0x10, 0x20, 0x30, 0x00, 0x10, 0x20, 0x30, 0x00,
+        ];
+        let overlay = [
+            0xc7, 0xc7, 0xc7, 0xc7, 0x40, 0x50, 0x60, 0x00, 0x40, 0xc7, 0x60, 0x00,
+        ];
+        let decode = |i: usize| rgb(decode_layered_pixel(&frame, &overlay, 4 * i, Some(0xc7)));
+        assert_eq!(decode(0), (0x30, 0x20, 0x10));
+        assert_eq!(decode(1), (0x60, 0x50, 0x40));
+        assert_eq!(decode(2), (0x60, 0x20, 0x40));
+
+        // Without transparency, the overlay covers everything
+        let opaque = decode_layered_pixel(&frame, &overlay, 0, None);
+        assert_eq!(rgb(opaque), (0xc7, 0xc7, 0xc7));
+    }
+
+    #[cfg(feature = "unb")]
+    #[test]
+    fn test_decode_layered_pixel() {
+        let frame = [0b00_000_111, 0b00_000_111];
+        let overlay = [0xc7, 0b11_000_000];
+        let decode = |i| rgb(decode_layered_pixel(&frame, &overlay, i, Some(0xc7)));
+        assert_eq!(decode(0), rgb(decode_pixel(&frame, 0)));
+        assert_eq!(decode(1), rgb(decode_pixel(&overlay, 1)));
+    }
+
+    #[test]
+    fn test_frame_layout() {
+        let default = FrameLayout::new(320);
+        assert_eq!(default.offset(5, 2), (2 * 320 + 5) * BYTES_PER_PIXEL);
+
+        // Rows padded to 512 bytes, with packed 3-byte pixels
+        let padded = FrameLayout {
+            base: 0x1000,
+            stride: 512,
+            bytes_per_pixel: 3,
+        };
+        assert_eq!(padded.offset(0, 0), 0);
+        assert_eq!(padded.offset(5, 2), 2 * 512 + 5 * 3);
+
+        let mut mmio = vec![0; FRAME_SELECT + 1];
+        assert_eq!(padded.frame(&mmio).len(), mmio.len() - 0x1000);
+        mmio[FRAME_SELECT] = 1;
+        assert_eq!(padded.frame(&mmio).len(), mmio.len() - 0x1000 - FRAME_SIZE);
+
+        assert!(default.fits(320, 240, 2, MMIO_SIZE));
+        assert!(padded.fits(170, 240, 1, MMIO_SIZE));
+        // Rows would overlap
+        assert!(!padded.fits(172, 240, 1, MMIO_SIZE));
+        let overlapping = FrameLayout {
+            bytes_per_pixel: 0,
+            ..padded
+        };
+        assert!(!overlapping.fits(320, 240, 1, MMIO_SIZE));
+        assert!(!default.fits(320, 240, 1, FRAME_SIZE));
+
+        // Frame 0 would run into frame 1
+        let tall = FrameLayout {
+            stride: 8192,
+            ..default
+        };
+        assert!(!tall.fits(320, 240, 1, MMIO_SIZE));
+        assert!(tall.fits(320, 120, 1, MMIO_SIZE));
+
+        // Frame 1 would run into the keyboard registers
+        let late = FrameLayout {
+            base: FRAME_1 - 32 * 32 * BYTES_PER_PIXEL,
+            ..FrameLayout::new(32)
+        };
+        assert!(late.fits(32, 32, 1, MMIO_SIZE));
+        assert!(!late.fits(32, 33, 1, MMIO_SIZE));
+    }
+
+    #[test]
+    fn test_count_frame() {
+        let mmio = Arc::new(Mutex::new(vec![0; FRAME_COUNTER + 4]));
+        let shared = mmio.clone();
+        for _ in 0..3 {
+            count_frame(&mut shared.lock());
+        }
+        assert_eq!(mmio.lock()[FRAME_COUNTER..], [3, 0, 0, 0]);
+
+        mmio.lock()[FRAME_COUNTER..].copy_from_slice(&u32::MAX.to_le_bytes());
+        count_frame(&mut shared.lock());
+        assert_eq!(mmio.lock()[FRAME_COUNTER..], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_current_frame() {
+        let mut mmio = vec![0; FRAME_SELECT + 1];
+        mmio[FRAME_1] = 42;
+        assert_eq!(FrameLayout::default().frame(&mmio)[0], 0);
+        mmio[FRAME_SELECT] = 1;
+        assert_eq!(FrameLayout::default().frame(&mmio)[0], 42);
+
+        // The screenshot follows the layout of the display
+        let padded = FrameLayout {
+            base: 0x1000,
+            stride: 512,
+            bytes_per_pixel: 3,
+        };
+        mmio[0x1000 + FRAME_SIZE + 512 + 3] = 0xff;
+        let image = frame_to_rgb(padded.frame(&mmio), 2, 2, &padded);
+        let white_ish = frame_to_rgb(&[0xff, 0, 0], 1, 1, &FrameLayout::new(1));
+        assert_eq!(image[9..], white_ish);
+        assert_eq!(image[..9], [0; 9]);
+    }
+}
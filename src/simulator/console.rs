@@ -0,0 +1,76 @@
+//!
+//! Non-blocking console input. A background thread reads stdin into a queue, so programs can
+//! check whether a key was typed without waiting for it, which is what text-mode games need.
+//!
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+
+/// Bytes that were read from the console but not consumed by the program yet
+#[derive(Debug, Clone, Default)]
+pub struct InputQueue(Arc<Mutex<VecDeque<u8>>>);
+
+impl InputQueue {
+    /// Creates a queue filled by a thread that reads `reader` until it ends
+    pub fn spawn(mut reader: impl Read + Send + 'static) -> Self {
+        let queue = Self::default();
+        let producer = queue.clone();
+        thread::Builder::new()
+            .name("FPGRARS Console Input".into())
+            .spawn(move || {
+                let mut buf = [0; 256];
+                while let Ok(n @ 1..) = reader.read(&mut buf) {
+                    producer.push(&buf[..n]);
+                }
+            })
+            .expect("Failed to spawn the console input thread");
+        queue
+    }
+
+    pub fn push(&self, bytes: &[u8]) {
+        self.0.lock().extend(bytes);
+    }
+
+    /// Returns the next byte, or `None` if there's nothing to read yet
+    pub fn poll(&self) -> Option<u8> {
+        self.0.lock().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_poll() {
+        let queue = InputQueue::default();
+        assert_eq!(queue.poll(), None);
+
+        queue.push(b"ab");
+        assert_eq!(queue.poll(), Some(b'a'));
+        queue.push(b"c");
+        assert_eq!(queue.poll(), Some(b'b'));
+        assert_eq!(queue.poll(), Some(b'c'));
+        assert_eq!(queue.poll(), None);
+    }
+
+    #[test]
+    fn test_spawn_reads_everything() {
+        let queue = InputQueue::spawn(&b"hello"[..]);
+
+        let mut read = Vec::new();
+        let start = Instant::now();
+        while read.len() < 5 && start.elapsed() < Duration::from_secs(5) {
+            match queue.poll() {
+                Some(byte) => read.push(byte),
+                None => thread::yield_now(),
+            }
+        }
+        assert_eq!(read, b"hello");
+        assert_eq!(queue.poll(), None);
+    }
+}
@@ -0,0 +1,57 @@
+use super::memory::Access;
+use crate::parser::token::Context;
+use owo_colors::OwoColorize;
+use thiserror::Error;
+
+/// Describes an address, along with its signed value if it's "negative", since those usually come
+/// from a bad offset
+fn address_description(address: usize) -> String {
+    if address as i32 >= 0 {
+        format!("{}", address.bright_blue())
+    } else {
+        format!(
+            "{} (the same as {} if signed)",
+            address.bright_blue(),
+            (address as i32).bright_blue()
+        )
+    }
+}
+
+/// Represents the errors that stop the execution of a program. Returned by
+/// [run](struct.Simulator.html#method.run), so the caller decides what to do with them.
+#[derive(Debug, Error)]
+pub enum SimError {
+    #[error("Out of bounds {access} at byte {}!\n{}: when executing instruction\n{ctx}", address_description(*.address), "   Note".bright_yellow())]
+    MemoryOutOfBounds {
+        address: usize,
+        access: Access,
+        ctx: Context,
+    },
+
+    #[error("Tried to access instruction at pc {pc:x}, but code is only {:x} bytes long.\nLast instruction executed: {ctx}", .code_len)]
+    InstructionOutOfBounds {
+        pc: usize,
+        code_len: usize,
+        ctx: Context,
+    },
+
+    #[error("Your code tried calling the ecall {}, which is not implemented in FPGRARS!\n{ctx}", .number.bright_blue())]
+    UnimplementedEcall { number: u32, ctx: Context },
+
+    /// Division by zero or overflow, when they're set to trap but there's no trap handler
+    #[error("{description}\n{ctx}")]
+    ArithmeticException {
+        cause: u32,
+        description: &'static str,
+        ctx: Context,
+    },
+
+    #[error("Expected to read {what}, but the input has ended\n{ctx}")]
+    InputEnded { what: &'static str, ctx: Context },
+
+    #[error("{message}\n{ctx}")]
+    Sbrk { message: String, ctx: Context },
+
+    #[error("The stack pointer {} is outside of the data segment, it should be at most {}", format!("{:#x}", .sp).bright_yellow(), format!("{:#x}", .max).bright_blue())]
+    StackPointerOutOfBounds { sp: u32, max: usize },
+}
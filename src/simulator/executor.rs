@@ -1,22 +1,30 @@
 //! Deals with how instructions are executed
 
-use owo_colors::OwoColorize;
-
 use super::{into_register::IntoRegister, Simulator};
 use crate::{
-    instruction::{FloatInstruction, Instruction},
-    simulator::{util::class_mask, EcallSignal},
+    instruction::{AtomicInstruction, FloatInstruction, Instruction},
+    simulator::{
+        memory::Access,
+        util::{class_mask, fmax, fmin},
+        EcallSignal, SimError,
+    },
 };
+use std::thread;
 
-type ExecutorFn = dyn Fn(&mut Simulator, &[Executor]);
+type ExecutorFn = dyn Fn(&mut Simulator, &[Executor]) + Send;
 
 /// An Executor executes an instruction, moves the program counter forward (or appropriately, in
 /// the case of a jump), and continues execution by calling the next executor
+///
+/// PERF: replacing these with an enum of decoded instructions, dispatched by a `match` in a loop,
+/// was measured to be 15%-60% slower in `benches/simulator.rs` (mandelbrot.s went from 30ms to
+/// 35ms). Each closure has its operands baked in and ends in its own indirect call, which the
+/// branch predictor handles much better than the single jump table of a `match`.
 #[repr(transparent)]
 pub struct Executor(Box<ExecutorFn>);
 
 impl Executor {
-    pub fn new<F: Fn(&mut Simulator, &[Executor]) + 'static>(f: F) -> Self {
+    pub fn new<F: Fn(&mut Simulator, &[Executor]) + Send + 'static>(f: F) -> Self {
         Self(Box::new(f))
     }
 
@@ -25,51 +33,98 @@ impl Executor {
     }
 }
 
-fn display_memory_out_of_bounds_error(sim: &Simulator, position: usize) -> ! {
-    let byte_msg = if position as i32 >= 0 {
-        format!("{}", position.bright_blue())
-    } else {
-        format!(
-            "{} (the same as {} if signed)",
-            position.bright_blue(),
-            (position as i32).bright_blue()
-        )
-    };
-    eprintln!(
-        "{} Out of bounds memory access at byte {}!\n{}: when executing instruction\n{}",
-        "   [error]".bright_red(),
-        byte_msg,
-        "   Note".bright_yellow(),
-        sim.code_ctx[sim.pc >> 2]
-    );
-    std::process::exit(1);
+/// Programs with fewer instructions than this are compiled in a single thread, since spawning the
+/// threads would take longer than compiling them
+const PARALLEL_COMPILE_THRESHOLD: usize = 1 << 16;
+
+// `ucause` values of the exceptions, as defined by the RISC-V privileged spec
+const INSTRUCTION_ACCESS_FAULT: u32 = 1;
+const LOAD_ACCESS_FAULT: u32 = 5;
+const STORE_ACCESS_FAULT: u32 = 7;
+
+/// Execute the next instruction, after the current one has been retired
+#[inline(always)]
+pub fn next(sim: &mut Simulator, code: &[Executor], new_pc: usize) {
+    sim.instret += 1;
+    if sim.instret >= sim.next_check && !sim.periodic_check() {
+        return;
+    }
+    start(sim, code, new_pc);
 }
 
-fn display_instruction_out_of_bounds_error(sim: &Simulator, new_pc: usize) -> ! {
-    eprintln!(
-            "Tried to access instruction at pc {:x}, but code is only {:x} bytes long.\nLast instruction executed: {}",
-            new_pc,
-            sim.code.len() * 4,
-            sim.code_ctx[sim.pc >> 2],
-        );
-    std::process::exit(1);
+/// Execute the next instruction, after one that may have accessed memory out of bounds. Only loads,
+/// stores and ecalls can do that, so they're the only ones that pay for checking
+/// `out_of_bounds_access` instead of every instruction in the hot path. An instruction that
+/// faulted isn't retired.
+#[inline(always)]
+pub fn next_after_access(sim: &mut Simulator, code: &[Executor], new_pc: usize) {
+    if sim.memory.out_of_bounds_access.is_some() {
+        return out_of_bounds(sim, code);
+    }
+    next(sim, code, new_pc);
 }
 
-/// Execute the next instruction
+/// Writes the value read by a load to its destination register with `write_rd` and executes the
+/// next instruction. If the load went out of bounds, the register is left untouched.
 #[inline(always)]
-pub fn next(sim: &mut Simulator, code: &[Executor], new_pc: usize) {
-    if let Some(position) = sim.memory.out_of_bounds_access {
-        display_memory_out_of_bounds_error(sim, position);
+fn finish_load<F>(sim: &mut Simulator, code: &[Executor], write_rd: F)
+where
+    F: FnOnce(&mut Simulator),
+{
+    if sim.memory.out_of_bounds_access.is_some() {
+        return out_of_bounds(sim, code);
     }
+    write_rd(sim);
+    next(sim, code, sim.pc + 4);
+}
 
-    let executor = code
-        .get(new_pc >> 2)
-        .unwrap_or_else(|| display_instruction_out_of_bounds_error(sim, new_pc));
+/// Execute the instruction at `new_pc`, without retiring the current one. Used to start the
+/// program.
+#[inline(always)]
+pub fn start(sim: &mut Simulator, code: &[Executor], new_pc: usize) {
+    let Some(executor) = code.get(new_pc >> 2) else {
+        if !sim.can_trap(code) {
+            sim.error = Some(SimError::InstructionOutOfBounds {
+                pc: new_pc,
+                code_len: code.len() * 4,
+                ctx: sim.current_ctx(),
+            });
+            return;
+        }
+
+        sim.pc = new_pc;
+        sim.trap(INSTRUCTION_ACCESS_FAULT, new_pc as u32);
+        return start(sim, code, sim.pc);
+    };
 
     sim.pc = new_pc;
     executor.call(sim, code);
 }
 
+/// Reports the out-of-bounds access of the current instruction, or jumps to the trap handler
+#[cold]
+fn out_of_bounds(sim: &mut Simulator, code: &[Executor]) {
+    let Some((position, access)) = sim.memory.out_of_bounds_access.take() else {
+        return;
+    };
+
+    if !sim.can_trap(code) {
+        sim.error = Some(SimError::MemoryOutOfBounds {
+            address: position,
+            access,
+            ctx: sim.current_ctx(),
+        });
+        return;
+    }
+
+    let cause = match access {
+        Access::Load(_) => LOAD_ACCESS_FAULT,
+        Access::Store(_) => STORE_ACCESS_FAULT,
+    };
+    sim.trap(cause, position as u32);
+    start(sim, code, sim.pc);
+}
+
 fn from_bool(x: bool) -> u32 {
     if x {
         1
@@ -87,7 +142,7 @@ fn from_bool(x: bool) -> u32 {
 fn exec_type_r<R, F>(rd: u8, rs1: u8, rs2: u8, op: F) -> Executor
 where
     R: IntoRegister,
-    F: Fn(u32, u32) -> R + 'static,
+    F: Fn(u32, u32) -> R + Send + 'static,
 {
     Executor::new(move |sim, code| {
         sim.set_reg(rd, op(sim.reg(rs1), sim.reg(rs2)));
@@ -101,7 +156,7 @@ where
 fn exec_type_i_imm<R, F>(rd: u8, rs1: u8, imm: u32, op: F) -> Executor
 where
     R: IntoRegister,
-    F: Fn(u32, u32) -> R + 'static,
+    F: Fn(u32, u32) -> R + Send + 'static,
 {
     Executor::new(move |sim, code| {
         sim.set_reg(rd, op(sim.reg(rs1), imm));
@@ -113,7 +168,7 @@ where
 #[inline(always)]
 fn exec_branch<F>(rs1: u8, rs2: u8, label: usize, op: F) -> Executor
 where
-    F: Fn(u32, u32) -> bool + 'static,
+    F: Fn(u32, u32) -> bool + Send + 'static,
 {
     Executor::new(move |sim, code| {
         let new_pc = if op(sim.reg(rs1), sim.reg(rs2)) {
@@ -125,9 +180,132 @@ where
     })
 }
 
-/// Compiles all instructions in a slice
+/// Creates an executor that executes an atomic memory operation: loads the word at `rs1` into `rd`
+/// and stores `op(word, rs2)` in its place. See perf note on [`exec_type_r`]
+#[inline(always)]
+fn exec_amo<F>(rd: u8, rs2: u8, rs1: u8, op: F) -> Executor
+where
+    F: Fn(u32, u32) -> u32 + Send + 'static,
+{
+    Executor::new(move |sim, code| {
+        let addr = sim.reg::<u32>(rs1) as usize;
+        let (word, x) = (sim.memory.get_word(addr), sim.reg::<u32>(rs2));
+        sim.memory.set_word(addr, op(word, x));
+        finish_load(sim, code, |sim| sim.set_reg(rd, word));
+    })
+}
+
+/// Compiles all instructions in a slice. Large programs are split between threads.
 pub fn compile_all(i: &[Instruction]) -> Vec<Executor> {
-    i.iter().map(compile).collect()
+    let threads = if i.len() < PARALLEL_COMPILE_THRESHOLD {
+        1
+    } else {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    };
+    compile_all_in(i, threads)
+}
+
+/// Compiles all instructions in a slice, split in chunks between `threads` threads
+pub fn compile_all_in(i: &[Instruction], threads: usize) -> Vec<Executor> {
+    if threads <= 1 {
+        return i.iter().map(compile).collect();
+    }
+
+    let chunk_size = i.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = i
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(compile).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("A compiler thread panicked"))
+            .collect()
+    })
+}
+
+/// Wraps the executor of an instruction that writes to `x0`, warning about it when it runs. Used
+/// by `--warn-zero-write`.
+pub fn with_zero_write_warning(executor: Executor) -> Executor {
+    Executor::new(move |sim, code| {
+        sim.on_zero_write();
+        executor.call(sim, code);
+    })
+}
+
+/// Wraps the executor of an instruction that writes to memory, so the watchdog knows the program
+/// is making progress. Used by `--watchdog`.
+pub fn with_progress_tracking(executor: Executor) -> Executor {
+    Executor::new(move |sim, code| {
+        sim.last_progress = sim.instret;
+        executor.call(sim, code);
+    })
+}
+
+/// Wraps the executor of a load so it warns when it reads bytes the program never wrote.
+/// Executors of instructions that don't read memory are returned as they are. Used by
+/// `--sanitize-uninit`.
+pub fn with_uninit_check(i: &Instruction, executor: Executor) -> Executor {
+    let Some((rs1, imm, len)) = i.memory_read() else {
+        return executor;
+    };
+    Executor::new(move |sim, code| {
+        let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
+        sim.check_uninit_read(addr, len);
+        executor.call(sim, code);
+    })
+}
+
+/// Wraps an executor so the instruction is logged by the tracer. Used by `--trace`.
+pub fn with_trace(executor: Executor) -> Executor {
+    Executor::new(move |sim, code| {
+        if let Some(tracer) = &mut sim.tracer {
+            tracer.step(sim.pc, &sim.registers, &sim.floats);
+        }
+        executor.call(sim, code);
+    })
+}
+
+/// Wraps the executor of a division or remainder so it traps when dividing by zero, instead of
+/// following the RISC-V spec. Executors of other instructions are returned as they are. Used by
+/// `--trap-div-zero`.
+pub fn with_div_zero_trap(i: &Instruction, executor: Executor) -> Executor {
+    use Instruction::*;
+    let (Div(_, _, rs2) | Divu(_, _, rs2) | Rem(_, _, rs2) | Remu(_, _, rs2)) = *i else {
+        return executor;
+    };
+
+    Executor::new(move |sim, code| {
+        if sim.reg::<u32>(rs2) == 0 {
+            if sim.on_div_zero() {
+                next(sim, code, sim.pc);
+            }
+        } else {
+            executor.call(sim, code);
+        }
+    })
+}
+
+/// Wraps the executor of an `add` or `sub` so it traps on signed overflow, instead of wrapping
+/// around. Executors of other instructions are returned as they are. Used by `--trap-overflow`.
+pub fn with_overflow_trap(i: &Instruction, executor: Executor) -> Executor {
+    use Instruction::*;
+    type CheckedOp = fn(i32, i32) -> Option<i32>;
+    let (rs1, rs2, op): (u8, u8, CheckedOp) = match *i {
+        Add(_, rs1, rs2) => (rs1, rs2, i32::checked_add),
+        Sub(_, rs1, rs2) => (rs1, rs2, i32::checked_sub),
+        _ => return executor,
+    };
+
+    Executor::new(move |sim, code| {
+        if op(sim.reg::<i32>(rs1), sim.reg::<i32>(rs2)).is_none() {
+            if sim.on_overflow() {
+                next(sim, code, sim.pc);
+            }
+        } else {
+            executor.call(sim, code);
+        }
+    })
 }
 
 /// Compiles a parsed instruction into an executor  
@@ -165,7 +343,7 @@ pub fn compile(i: &Instruction) -> Executor {
             }
         }),
         Divu(rd, rs1, rs2) => {
-            exec_type_r(rd, rs1, rs2, |a, b| if b == 0 { u32::MAX } else { a / b })
+            exec_type_r(rd, rs1, rs2, |a, b| a.checked_div(b).unwrap_or(u32::MAX))
         }
         Rem(rd, rs1, rs2) => exec_type_r(rd, rs1, rs2, |a, b| {
             if b == 0 {
@@ -185,18 +363,13 @@ pub fn compile(i: &Instruction) -> Executor {
             use EcallSignal::*;
             match sim.ecall() {
                 Exit => {} // don't execute the next instruction
-                Continue => next(sim, code, sim.pc),
-                Nothing => next(sim, code, sim.pc + 4),
+                Continue => next_after_access(sim, code, sim.pc),
+                Nothing => next_after_access(sim, code, sim.pc + 4),
             }
         }),
-        Ebreak => Executor::new(move |sim, _code| {
-            let ctx = &sim.code_ctx[sim.pc / 4];
-            eprintln!(
-                "   {} has not yet been implemented in FPGRARS!\n{}",
-                "ebreak".on_bright_magenta(),
-                ctx
-            );
-            std::process::exit(1);
+        Ebreak => Executor::new(move |sim, code| {
+            sim.on_breakpoint();
+            next(sim, code, sim.pc + 4);
         }),
 
         // Type I -- Immediate
@@ -218,32 +391,27 @@ pub fn compile(i: &Instruction) -> Executor {
         Lb(rd, imm, rs1) => Executor::new(move |sim, code| {
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             let data = sim.memory.get_byte(addr) as i8 as u32; // sign-extends
-            sim.set_reg(rd, data);
-            next(sim, code, sim.pc + 4);
+            finish_load(sim, code, |sim| sim.set_reg(rd, data));
         }),
         Lbu(rd, imm, rs1) => Executor::new(move |sim, code| {
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             let data = sim.memory.get_byte(addr) as u32;
-            sim.set_reg(rd, data);
-            next(sim, code, sim.pc + 4);
+            finish_load(sim, code, |sim| sim.set_reg(rd, data));
         }),
         Lh(rd, imm, rs1) => Executor::new(move |sim, code| {
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             let data = sim.memory.get_half(addr) as i16 as u32; // sign-extends
-            sim.set_reg(rd, data);
-            next(sim, code, sim.pc + 4);
+            finish_load(sim, code, |sim| sim.set_reg(rd, data));
         }),
         Lhu(rd, imm, rs1) => Executor::new(move |sim, code| {
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             let data = sim.memory.get_half(addr) as u32;
-            sim.set_reg(rd, data);
-            next(sim, code, sim.pc + 4);
+            finish_load(sim, code, |sim| sim.set_reg(rd, data));
         }),
         Lw(rd, imm, rs1) => Executor::new(move |sim, code| {
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             let data = sim.memory.get_word(addr);
-            sim.set_reg(rd, data);
-            next(sim, code, sim.pc + 4);
+            finish_load(sim, code, |sim| sim.set_reg(rd, data));
         }),
 
         // Type S
@@ -252,21 +420,21 @@ pub fn compile(i: &Instruction) -> Executor {
                 (sim.reg::<u32>(rs1).wrapping_add(imm)) as usize,
                 sim.reg::<u8>(rs2),
             );
-            next(sim, code, sim.pc + 4);
+            next_after_access(sim, code, sim.pc + 4);
         }),
         Sh(rs2, imm, rs1) => Executor::new(move |sim, code| {
             sim.memory.set_half(
                 (sim.reg::<u32>(rs1).wrapping_add(imm)) as usize,
                 sim.reg::<u16>(rs2),
             );
-            next(sim, code, sim.pc + 4);
+            next_after_access(sim, code, sim.pc + 4);
         }),
         Sw(rs2, imm, rs1) => Executor::new(move |sim, code| {
             sim.memory.set_word(
                 (sim.reg::<u32>(rs1).wrapping_add(imm)) as usize,
                 sim.reg::<u32>(rs2),
             );
-            next(sim, code, sim.pc + 4);
+            next_after_access(sim, code, sim.pc + 4);
         }),
 
         // Type B
@@ -342,6 +510,9 @@ pub fn compile(i: &Instruction) -> Executor {
         // Floats
         Float(ref finstr) => compile_float_instruction(finstr),
 
+        // Atomics
+        Atomic(ref ainstr) => compile_atomic_instruction(ainstr),
+
         // Pseudoinstructions
         Li(rd, imm) => Executor::new(move |sim, code| {
             sim.set_reg(rd, imm as i32);
@@ -397,12 +568,12 @@ pub fn compile_float_instruction(i: &FloatInstruction) -> Executor {
         }),
         Max(rd, rs1, rs2) => Executor::new(move |sim, code| {
             let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-            sim.floats[rd] = sim.floats[rs1].max(sim.floats[rs2]);
+            sim.floats[rd] = fmax(sim.floats[rs1], sim.floats[rs2]);
             next(sim, code, sim.pc + 4);
         }),
         Min(rd, rs1, rs2) => Executor::new(move |sim, code| {
             let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-            sim.floats[rd] = sim.floats[rs1].min(sim.floats[rs2]);
+            sim.floats[rd] = fmin(sim.floats[rs1], sim.floats[rs2]);
             next(sim, code, sim.pc + 4);
         }),
         SgnjS(rd, rs1, rs2) => Executor::new(move |sim, code| {
@@ -443,14 +614,25 @@ pub fn compile_float_instruction(i: &FloatInstruction) -> Executor {
             sim.floats[rd] = sim.reg::<u32>(rs1) as f32;
             next(sim, code, sim.pc + 4);
         }),
-        CvtWS(rd, rs1) => Executor::new(move |sim, code| {
-            let rs1 = rs1 as usize;
-            sim.set_reg(rd, sim.floats[rs1] as i32);
+        // Out of range values saturate (`as` already does that), and NaN becomes the maximum value
+        CvtWS(rd, rs1, rm) => Executor::new(move |sim, code| {
+            let x = sim.floats[rs1 as usize];
+            let x = if x.is_nan() {
+                i32::MAX
+            } else {
+                rm.round(x) as i32
+            };
+            sim.set_reg(rd, x);
             next(sim, code, sim.pc + 4);
         }),
-        CvtWuS(rd, rs1) => Executor::new(move |sim, code| {
-            let rs1 = rs1 as usize;
-            sim.set_reg(rd, sim.floats[rs1] as u32);
+        CvtWuS(rd, rs1, rm) => Executor::new(move |sim, code| {
+            let x = sim.floats[rs1 as usize];
+            let x = if x.is_nan() {
+                u32::MAX
+            } else {
+                rm.round(x) as u32
+            };
+            sim.set_reg(rd, x);
             next(sim, code, sim.pc + 4);
         }),
 
@@ -473,14 +655,42 @@ pub fn compile_float_instruction(i: &FloatInstruction) -> Executor {
             let rd = rd as usize;
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             let data = sim.memory.get_float(addr);
-            sim.floats[rd] = data;
-            next(sim, code, sim.pc + 4);
+            finish_load(sim, code, |sim| sim.floats[rd] = data);
         }),
         Sw(rs2, imm, rs1) => Executor::new(move |sim, code| {
             let x = sim.floats[rs2 as usize];
             let addr = sim.reg::<u32>(rs1).wrapping_add(imm) as usize;
             sim.memory.set_float(addr, x);
-            next(sim, code, sim.pc + 4);
+            next_after_access(sim, code, sim.pc + 4);
         }),
     }
 }
+
+/// Compiles an atomic instruction into an executor.
+pub fn compile_atomic_instruction(i: &AtomicInstruction) -> Executor {
+    use AtomicInstruction::*;
+
+    match *i {
+        LrW(rd, rs1) => Executor::new(move |sim, code| {
+            let addr = sim.reg::<u32>(rs1) as usize;
+            let data = sim.memory.get_word(addr);
+            finish_load(sim, code, |sim| sim.set_reg(rd, data));
+        }),
+        // There's only one hart, so the reservation is never lost and `sc.w` always succeeds
+        ScW(rd, rs2, rs1) => Executor::new(move |sim, code| {
+            let addr = sim.reg::<u32>(rs1) as usize;
+            sim.memory.set_word(addr, sim.reg::<u32>(rs2));
+            sim.set_reg(rd, 0);
+            next_after_access(sim, code, sim.pc + 4);
+        }),
+        AmoSwapW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |_, b| b),
+        AmoAddW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| a.wrapping_add(b)),
+        AmoXorW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| a ^ b),
+        AmoAndW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| a & b),
+        AmoOrW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| a | b),
+        AmoMinW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| (a as i32).min(b as i32) as u32),
+        AmoMaxW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| (a as i32).max(b as i32) as u32),
+        AmoMinuW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| a.min(b)),
+        AmoMaxuW(rd, rs2, rs1) => exec_amo(rd, rs2, rs1, |a, b| a.max(b)),
+    }
+}
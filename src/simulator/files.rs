@@ -5,11 +5,49 @@
 
 use std::collections::BTreeMap;
 use std::fs;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 
 /// Maximum number of simultaneous open files
 const MAX_DESCRIPTORS: i32 = 1 << 30;
 
+// Flags for the open ecall. The lower two bits select the access mode and the others modify it,
+// like in newlib's `open`. Read (0), write (1) and append (9) behave the same as in RARS.
+const OPEN_READ: u32 = 0;
+const OPEN_WRITE: u32 = 1;
+const OPEN_READ_WRITE: u32 = 2;
+const OPEN_ACCESS_MODE: u32 = 3;
+const OPEN_APPEND: u32 = 8;
+const OPEN_CREATE: u32 = 0x200;
+const OPEN_TRUNCATE: u32 = 0x400;
+
+// Error codes returned in a1 when opening or reading a whole file fails. They're the negated
+// `errno` values of Linux, so `ENOENT` is -2 and `EBADF` is -9. a0 is still -1 on errors, to stay
+// compatible with RARS.
+const ENOENT: i32 = -2;
+const EIO: i32 = -5;
+const EBADF: i32 = -9;
+const EACCES: i32 = -13;
+const EFAULT: i32 = -14;
+const EEXIST: i32 = -17;
+const EISDIR: i32 = -21;
+const EINVAL: i32 = -22;
+
+/// Result of a file operation: the value returned in a0, or the error code
+type FileResult = Result<i32, i32>;
+
+/// Maps an I/O error to its error code
+fn error_code(err: io::Error) -> i32 {
+    use io::ErrorKind::*;
+    match err.kind() {
+        NotFound => ENOENT,
+        PermissionDenied => EACCES,
+        AlreadyExists => EEXIST,
+        IsADirectory => EISDIR,
+        InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
 /// Data structure to add, remove and fetch [Files](struct.File.html)
 pub struct FileHolder {
     next: i32,
@@ -55,16 +93,35 @@ impl FileHolder {
     }
 }
 
-/// Open a file and return its descriptor
-fn open(filepath: &str, flags: u32, holder: &mut FileHolder) -> i32 {
-    let file_opt = match flags {
-        0 => fs::File::open(filepath).ok(),
-        1 => fs::File::create(filepath).ok(),
-        9 => fs::OpenOptions::new().append(true).open(filepath).ok(),
-        _ => None,
+/// Converts the flags of the open ecall to `OpenOptions`, or `None` if they're invalid
+fn open_options(flags: u32) -> Option<fs::OpenOptions> {
+    const KNOWN_FLAGS: u32 = OPEN_ACCESS_MODE | OPEN_APPEND | OPEN_CREATE | OPEN_TRUNCATE;
+    if flags & !KNOWN_FLAGS != 0 {
+        return None;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    match flags & OPEN_ACCESS_MODE {
+        OPEN_READ => options.read(true),
+        OPEN_WRITE => options.write(true),
+        OPEN_READ_WRITE => options.read(true).write(true),
+        _ => return None,
     };
 
-    file_opt.map(|f| holder.add(f)).unwrap_or(-1)
+    // Plain write mode creates and truncates the file, like RARS does
+    let rars_write = flags == OPEN_WRITE;
+    options
+        .append(flags & OPEN_APPEND != 0)
+        .create(rars_write || flags & OPEN_CREATE != 0)
+        .truncate(rars_write || flags & OPEN_TRUNCATE != 0);
+    Some(options)
+}
+
+/// Open a file and return its descriptor
+fn open(filepath: &str, flags: u32, holder: &mut FileHolder) -> FileResult {
+    let options = open_options(flags).ok_or(EINVAL)?;
+    let file = options.open(filepath).map_err(error_code)?;
+    Ok(holder.add(file))
 }
 
 /// Close a file
@@ -74,18 +131,24 @@ fn close(fd: i32, holder: &mut FileHolder) {
 
 /// Seek to a position given by offset, starting from the start, end or current cursor.
 /// Returns the new position of the cursor from the start of the file
-fn seek(fd: i32, offset: u32, from_where: u32, holder: &mut FileHolder) -> i32 {
+fn seek(fd: i32, offset: u32, from_where: u32, holder: &mut FileHolder) -> FileResult {
     let seek_action = match from_where {
         1 => SeekFrom::Current(offset as i32 as i64),
         2 => SeekFrom::End(offset as i32 as i64),
         _ => SeekFrom::Start(offset as u64),
     };
 
-    holder
-        .get_mut(fd)
-        .and_then(|file| file.seek(seek_action).ok())
-        .map(|x| x as i32)
-        .unwrap_or(-1)
+    let file = holder.get_mut(fd).ok_or(EBADF)?;
+    file.seek(seek_action).map(|x| x as i32).map_err(error_code)
+}
+
+/// Clamps the length of the buffer at `buffer_start` so it doesn't go past the end of its memory
+/// region. Errors if the buffer starts out of bounds.
+fn clamp_buffer(buffer_start: u32, len: usize, memory: &super::Memory) -> Result<usize, i32> {
+    match memory.bytes_until_end(buffer_start as usize) {
+        0 if len > 0 => Err(EFAULT),
+        available => Ok(len.min(available)),
+    }
 }
 
 /// Read `len` bytes from a file and put them in `memory[buffer_start..buffer_start + len]`
@@ -95,12 +158,13 @@ fn read(
     len: usize,
     holder: &mut FileHolder,
     memory: &mut super::Memory,
-) -> i32 {
-    holder
-        .get_mut(fd)
-        .and_then(|file| memory.set_reader(file, buffer_start as usize, len))
+) -> FileResult {
+    let file = holder.get_mut(fd).ok_or(EBADF)?;
+    let len = clamp_buffer(buffer_start, len, memory)?;
+    memory
+        .set_reader(file, buffer_start as usize, len)
         .map(|x| x as i32)
-        .unwrap_or(-1)
+        .ok_or(EIO)
 }
 
 /// Write `memory[buffer_start..buffer_start + len]` to a file
@@ -110,15 +174,50 @@ fn write(
     len: usize,
     holder: &mut FileHolder,
     memory: &mut super::Memory,
-) -> i32 {
-    holder
-        .get_mut(fd)
-        .and_then(|file| memory.get_with(buffer_start as usize, |buf| file.write(&buf[..len]).ok()))
+) -> FileResult {
+    let file = holder.get_mut(fd).ok_or(EBADF)?;
+    let len = clamp_buffer(buffer_start, len, memory)?;
+    memory
+        .get_with(buffer_start as usize, len, |buf| {
+            file.write(&buf[..len]).ok()
+        })
         .map(|x| x as i32)
-        .unwrap_or(-1)
+        .ok_or(EIO)
 }
 
-/// Tries to handle an ecall and returns whether we could handle it
+/// Opens the file at `filepath`, reads up to `len` bytes of it into
+/// `memory[buffer_start..buffer_start + len]` and closes it
+fn read_file(
+    filepath: &str,
+    buffer_start: u32,
+    len: usize,
+    memory: &mut super::Memory,
+) -> FileResult {
+    let mut file = fs::File::open(filepath).map_err(error_code)?;
+    let len = clamp_buffer(buffer_start, len, memory)?;
+    memory
+        .set_reader(&mut file, buffer_start as usize, len)
+        .map(|x| x as i32)
+        .ok_or(EIO)
+}
+
+/// Stores the result of a file operation in a0, which is -1 if it failed
+fn set_result(registers: &mut [u32; 32], result: FileResult) {
+    registers[10] = result.unwrap_or(-1) as u32;
+}
+
+/// Like [`set_result`], but also stores the error code in a1 if it failed. Only for the ecalls
+/// that open a file by its path, since the others take arguments in a1 that programs may reuse.
+fn set_result_with_code(registers: &mut [u32; 32], result: FileResult) {
+    set_result(registers, result);
+    if let Err(code) = result {
+        registers[11] = code as u32;
+    }
+}
+
+/// Tries to handle an ecall and returns whether we could handle it.
+/// When an operation fails, a0 is set to -1. Opening a file and reading a whole file also set a1
+/// to an error code like [`ENOENT`] or [`EACCES`].
 pub fn handle_ecall(
     ecall: u32,
     holder: &mut FileHolder,
@@ -129,12 +228,22 @@ pub fn handle_ecall(
         1024 => {
             // Open file
             let (a0, flags) = (registers[10] as usize, registers[11]);
-            let filepath: String = (a0..)
-                .map(|i| memory.get_byte(i) as char)
-                .take_while(|&c| c != '\0')
-                .collect();
+            let filepath = memory.read_c_string(a0);
+
+            set_result_with_code(registers, open(&filepath, flags, holder));
+
+            true
+        }
+
+        1025 => {
+            // Read whole file
+            let (a0, buffer_start, len) = (registers[10] as usize, registers[11], registers[12]);
+            let filepath = memory.read_c_string(a0);
 
-            registers[10] = open(&filepath, flags, holder) as u32;
+            set_result_with_code(
+                registers,
+                read_file(&filepath, buffer_start, len as usize, memory),
+            );
 
             true
         }
@@ -151,7 +260,7 @@ pub fn handle_ecall(
             // LSeek
             let (fd, offset, from_where) = (registers[10] as i32, registers[11], registers[12]);
 
-            registers[10] = seek(fd, offset, from_where, holder) as u32;
+            set_result(registers, seek(fd, offset, from_where, holder));
 
             true
         }
@@ -161,7 +270,7 @@ pub fn handle_ecall(
             let (fd, buffer_start, len) =
                 (registers[10] as i32, registers[11], registers[12] as usize);
 
-            registers[10] = read(fd, buffer_start, len, holder, memory) as u32;
+            set_result(registers, read(fd, buffer_start, len, holder, memory));
 
             true
         }
@@ -171,7 +280,7 @@ pub fn handle_ecall(
             let (fd, buffer_start, len) =
                 (registers[10] as i32, registers[11], registers[12] as usize);
 
-            registers[10] = write(fd, buffer_start, len, holder, memory) as u32;
+            set_result(registers, write(fd, buffer_start, len, holder, memory));
 
             true
         }
@@ -179,3 +288,164 @@ pub fn handle_ecall(
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Memory;
+
+    /// Stores `path` as a null-terminated string at `memory[0]`
+    fn store_path(memory: &mut Memory, path: &std::path::Path) {
+        let path = path.to_str().unwrap();
+        memory.data[..path.len()].copy_from_slice(path.as_bytes());
+        memory.data[path.len()] = 0;
+    }
+
+    /// Runs a file ecall with the given arguments in a0, a1 and a2, returning a0 and a1
+    fn ecall(
+        number: u32,
+        args: [u32; 3],
+        holder: &mut FileHolder,
+        memory: &mut Memory,
+    ) -> (i32, i32) {
+        let mut registers = [0; 32];
+        registers[10..13].copy_from_slice(&args);
+        assert!(handle_ecall(number, holder, &mut registers, memory));
+        (registers[10] as i32, registers[11] as i32)
+    }
+
+    #[test]
+    fn test_open_read_write() {
+        let path = crate::temp_path("open_read_write.txt");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let mut memory = Memory::new();
+        let mut holder = FileHolder::new();
+        store_path(&mut memory, &path);
+        memory.data[0x1000..0x1003].copy_from_slice(b"abc");
+
+        // Read-write doesn't truncate
+        let (fd, _) = ecall(1024, [0, OPEN_READ_WRITE, 0], &mut holder, &mut memory);
+        assert!(fd >= 0);
+        let fd = fd as u32;
+        assert_eq!(ecall(64, [fd, 0x1000, 3], &mut holder, &mut memory).0, 3);
+        assert_eq!(ecall(62, [fd, 0, 0], &mut holder, &mut memory).0, 0);
+        assert_eq!(ecall(63, [fd, 0x2000, 16], &mut holder, &mut memory).0, 10);
+        assert_eq!(&memory.data[0x2000..0x200a], b"abc3456789");
+        ecall(57, [fd, 0, 0], &mut holder, &mut memory);
+
+        // Write + create without truncating
+        let (fd, _) = ecall(
+            1024,
+            [0, OPEN_WRITE | OPEN_CREATE, 0],
+            &mut holder,
+            &mut memory,
+        );
+        assert_eq!(
+            ecall(64, [fd as u32, 0x1000, 1], &mut holder, &mut memory).0,
+            1
+        );
+        ecall(57, [fd as u32, 0, 0], &mut holder, &mut memory);
+        assert_eq!(fs::read(&path).unwrap(), b"abc3456789");
+
+        // Explicit truncate
+        let (fd, _) = ecall(
+            1024,
+            [0, OPEN_READ_WRITE | OPEN_TRUNCATE, 0],
+            &mut holder,
+            &mut memory,
+        );
+        ecall(57, [fd as u32, 0, 0], &mut holder, &mut memory);
+        assert_eq!(fs::read(&path).unwrap(), b"");
+
+        // Invalid flags
+        let (fd, code) = ecall(1024, [0, 3, 0], &mut holder, &mut memory);
+        assert_eq!((fd, code), (-1, EINVAL));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let path = crate::temp_path("error_codes_missing.txt");
+        let _ = fs::remove_file(&path);
+
+        let mut memory = Memory::new();
+        let mut holder = FileHolder::new();
+        store_path(&mut memory, &path);
+
+        let result = ecall(1024, [0, OPEN_READ, 0], &mut holder, &mut memory);
+        assert_eq!(result, (-1, ENOENT));
+        let result = ecall(1025, [0, 0x1000, 16], &mut holder, &mut memory);
+        assert_eq!(result, (-1, ENOENT));
+
+        // The other ecalls leave a1 as it was
+        let bad_fd = 1234;
+        for number in [62, 63, 64] {
+            let result = ecall(number, [bad_fd, 0x1000, 4], &mut holder, &mut memory);
+            assert_eq!(result, (-1, 0x1000), "ecall {number}");
+        }
+    }
+
+    #[test]
+    fn test_buffer_near_the_end() {
+        let path = crate::temp_path("buffer_near_the_end.txt");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let mut memory = Memory::new();
+        let mut holder = FileHolder::new();
+        store_path(&mut memory, &path);
+
+        let end = memory.data.len() as u32;
+        let (fd, _) = ecall(1024, [0, OPEN_READ, 0], &mut holder, &mut memory);
+        let fd = fd as u32;
+        assert_eq!(ecall(63, [fd, end - 4, 16], &mut holder, &mut memory).0, 4);
+        assert_eq!(&memory.data[end as usize - 4..], b"0123");
+        assert_eq!(ecall(63, [fd, end, 16], &mut holder, &mut memory).0, -1);
+        assert_eq!(
+            ecall(1025, [0, end, 16], &mut holder, &mut memory),
+            (-1, EFAULT)
+        );
+        assert_eq!(ecall(1025, [0, end - 2, 16], &mut holder, &mut memory).0, 2);
+        ecall(57, [fd, 0, 0], &mut holder, &mut memory);
+
+        let (fd, _) = ecall(1024, [0, OPEN_WRITE, 0], &mut holder, &mut memory);
+        let fd = fd as u32;
+        assert_eq!(ecall(64, [fd, end - 4, 16], &mut holder, &mut memory).0, 4);
+        ecall(57, [fd, 0, 0], &mut holder, &mut memory);
+        assert_eq!(fs::read(&path).unwrap(), b"0101");
+        assert!(memory.out_of_bounds_access.is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_whole_file() {
+        let path = crate::temp_path("read_whole_file.bin");
+        fs::write(&path, b"hello file").unwrap();
+
+        let mut memory = Memory::new();
+        let mut holder = FileHolder::new();
+        let mut registers = [0; 32];
+        store_path(&mut memory, &path);
+
+        let buffer = 0x1000;
+        registers[10] = 0;
+        registers[11] = buffer;
+        registers[12] = 64;
+        assert!(handle_ecall(1025, &mut holder, &mut registers, &mut memory));
+        assert_eq!(registers[10], 10);
+        assert_eq!(&memory.data[buffer as usize..][..11], b"hello file\0");
+
+        // Reads at most a2 bytes
+        registers[10] = 0;
+        registers[12] = 5;
+        handle_ecall(1025, &mut holder, &mut registers, &mut memory);
+        assert_eq!(registers[10], 5);
+
+        fs::remove_file(&path).unwrap();
+        registers[10] = 0;
+        handle_ecall(1025, &mut holder, &mut registers, &mut memory);
+        assert_eq!(registers[10] as i32, -1);
+    }
+}
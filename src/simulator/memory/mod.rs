@@ -1,13 +1,36 @@
 use byteorder::{ByteOrder, LittleEndian};
 use parking_lot::Mutex;
+use std::fmt;
 use std::io::Read;
+use std::ops::Range;
 use std::sync::Arc;
 
 pub mod consts;
 pub use consts::*;
 
 mod util;
-use util::{copy_with_transparency, has_transparent_byte};
+use util::{copy_slice_with_transparency, copy_with_transparency, has_transparent_byte};
+
+mod written;
+pub use written::WrittenBytes;
+
+/// Kind of memory access and how many bytes it was for, used to tell load and store faults apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Load(usize),
+    Store(usize),
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Access::Load(1) => write!(f, "load of 1 byte"),
+            Access::Load(width) => write!(f, "load of {width} bytes"),
+            Access::Store(1) => write!(f, "store of 1 byte"),
+            Access::Store(width) => write!(f, "store of {width} bytes"),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Memory {
@@ -17,11 +40,21 @@ pub struct Memory {
     /// Memory allocated by `sbrk`
     pub dynamic: Vec<u8>,
 
-    /// Flag that indicates if an out-of-bounds access was attempted, and where. This is used by
-    /// the executor to display better error messages when this happens. Not a very elegant
-    /// solution, but returning some kind of MemoryAccessResult<T> from [`Memory::get_with`] has a
-    /// high performance penalty.
-    pub out_of_bounds_access: Option<usize>,
+    /// Flag that indicates if an out-of-bounds access was attempted, where, whether it was a load
+    /// or a store, and how wide it was. This is used by the executor to display better error
+    /// messages (or jump to the trap handler) when this happens. Not a very elegant solution, but
+    /// returning some kind of MemoryAccessResult<T> from [`Memory::get_with`] has a high
+    /// performance penalty.
+    pub out_of_bounds_access: Option<(usize, Access)>,
+
+    /// Bytes with this value are not written to the video memory. `None` disables transparency.
+    pub transparent: Option<u8>,
+
+    /// Addresses of the video memory, where transparent bytes aren't written
+    pub video: Range<usize>,
+
+    /// Which bytes were written, if `--sanitize-uninit` is set
+    pub written: Option<WrittenBytes>,
 }
 
 impl Memory {
@@ -31,29 +64,60 @@ impl Memory {
             data: vec![0; DATA_SIZE],
             dynamic: vec![],
             out_of_bounds_access: None,
+            transparent: Some(TRANSPARENT_BYTE),
+            video: VIDEO_START..VIDEO_END,
+            written: None,
         }
     }
 
+    pub fn with_transparent(mut self, transparent: Option<u8>) -> Self {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Moves the video memory to the MMIO offsets in `video`, like the pixels of a custom
+    /// [`FrameLayout`](crate::renderer::FrameLayout)
+    pub fn with_video(mut self, video: Range<usize>) -> Self {
+        self.video = MMIO_START + video.start..MMIO_START + video.end;
+        self
+    }
+
+    /// Writes the display dimensions to their MMIO words, so programs can read them
+    pub fn with_display(self, width: usize, height: usize, bytes_per_pixel: usize) -> Self {
+        {
+            let mut mmio = self.mmio.lock();
+            LittleEndian::write_u32(&mut mmio[DISPLAY_WIDTH..], width as u32);
+            LittleEndian::write_u32(&mut mmio[DISPLAY_HEIGHT..], height as u32);
+            LittleEndian::write_u32(&mut mmio[DISPLAY_BYTES_PER_PIXEL..], bytes_per_pixel as u32);
+        }
+        self
+    }
+
     /// *IF* `x` has any transparent bytes and `i` is in the video memory,
     /// sets `n` bytes in the memory, ignoring the transparent ones. (`memory[i] = x`)
     /// Returns whether we actually set the bytes or not.
     fn set_with_transparency(&mut self, i: usize, x: u32, n: usize) -> bool {
-        if has_transparent_byte(x) && (VIDEO_START..VIDEO_END).contains(&i) {
+        let Some(transparent) = self.transparent else {
+            return false;
+        };
+
+        if has_transparent_byte(x, transparent) && self.video.contains(&i) {
             let mut mmio = self.mmio.lock();
-            copy_with_transparency(&mut mmio[i - MMIO_START..], x, n);
+            copy_with_transparency(&mut mmio[i - MMIO_START..], x, n, transparent);
             true
         } else {
             false
         }
     }
 
-    /// Reads a value from the `i`-th byte of the memory
-    pub fn get_with<T: Default, F>(&mut self, i: usize, read: F) -> T
+    /// Reads a value from the `i`-th byte of the memory. `width` is how many bytes `read` needs,
+    /// reported if the access is out of bounds.
+    pub fn get_with<T: Default, F>(&mut self, i: usize, width: usize, read: F) -> T
     where
         F: FnOnce(&[u8]) -> T,
     {
         if self.out_of_bounds(i) {
-            self.out_of_bounds_access = Some(i);
+            self.out_of_bounds_access = Some((i, Access::Load(width)));
             return T::default();
         }
 
@@ -75,20 +139,33 @@ impl Memory {
 
     /// Writes the value `x` to the `i`-th byte of the memory, with some writing function `write`.
     /// NOTE: make sure `write` doesn't write 0xC7 (transparent) to video memory. In most cases,
-    /// you should be using `set_byte`, `set_half` or `set_word` instead.
-    fn set_with<T, F, R: Default>(&mut self, i: usize, x: T, write: F) -> R
+    /// you should be using `set_byte`, `set_half` or `set_word` instead. `width` works like in
+    /// [`Memory::get_with`].
+    fn set_with<T, F, R: Default>(&mut self, i: usize, width: usize, x: T, write: F) -> R
     where
         F: FnOnce(&mut [u8], T) -> R,
     {
         if self.out_of_bounds(i) {
-            self.out_of_bounds_access = Some(i);
+            self.out_of_bounds_access = Some((i, Access::Store(width)));
             return R::default();
         }
 
         if i >= MMIO_START {
             // MMIO
+            let offset = i - MMIO_START;
             let mut mmio = self.mmio.lock();
-            write(&mut mmio[i - MMIO_START..], x)
+            let read_only =
+                offset.max(READ_ONLY_MMIO.start)..(offset + width).min(READ_ONLY_MMIO.end);
+            if read_only.is_empty() {
+                return write(&mut mmio[offset..], x);
+            }
+
+            // The write may be a bulk one that covers the read-only words, like a `memset`, so
+            // they're put back afterwards
+            let saved = mmio[read_only.clone()].to_vec();
+            let result = write(&mut mmio[offset..], x);
+            mmio[read_only].copy_from_slice(&saved);
+            result
         } else if i >= HEAP_START {
             // Heap/dynamic memory
             write(&mut self.dynamic[i - HEAP_START..], x)
@@ -98,41 +175,55 @@ impl Memory {
         }
     }
 
+    /// Marks `len` bytes starting at `i` as written, if `--sanitize-uninit` is set. Out of bounds
+    /// writes aren't marked.
+    #[inline]
+    fn mark_written(&mut self, i: usize, len: usize) {
+        if self.written.is_some() && self.bytes_until_end(i) >= len {
+            if let Some(written) = &mut self.written {
+                written.mark(i, len);
+            }
+        }
+    }
+
     pub fn get_byte(&mut self, i: usize) -> u8 {
-        self.get_with(i, |v| v[0])
+        self.get_with(i, 1, |v| v[0])
     }
 
     pub fn set_byte(&mut self, i: usize, x: u8) {
         if self.set_with_transparency(i, x as u32, 1) {
             return;
         }
-        self.set_with(i, x, |v, x| v[0] = x);
+        self.set_with(i, 1, x, |v, x| v[0] = x);
+        self.mark_written(i, 1);
     }
 
     pub fn get_half(&mut self, i: usize) -> u16 {
-        self.get_with(i, LittleEndian::read_u16)
+        self.get_with(i, 2, LittleEndian::read_u16)
     }
 
     pub fn set_half(&mut self, i: usize, x: u16) {
         if self.set_with_transparency(i, x as u32, 2) {
             return;
         }
-        self.set_with(i, x, LittleEndian::write_u16);
+        self.set_with(i, 2, x, LittleEndian::write_u16);
+        self.mark_written(i, 2);
     }
 
     pub fn get_word(&mut self, i: usize) -> u32 {
-        self.get_with(i, LittleEndian::read_u32)
+        self.get_with(i, 4, LittleEndian::read_u32)
     }
 
     pub fn set_word(&mut self, i: usize, x: u32) {
         if self.set_with_transparency(i, x, 4) {
             return;
         }
-        self.set_with(i, x, LittleEndian::write_u32);
+        self.set_with(i, 4, x, LittleEndian::write_u32);
+        self.mark_written(i, 4);
     }
 
     pub fn get_float(&mut self, i: usize) -> f32 {
-        self.get_with(i, LittleEndian::read_f32)
+        self.get_with(i, 4, LittleEndian::read_f32)
     }
 
     pub fn set_float(&mut self, i: usize, x: f32) {
@@ -146,21 +237,22 @@ impl Memory {
         R: Read,
     {
         // We'll write to these three sections separately
-        let before_video = start..VIDEO_START.min(start + len);
-        let in_video = VIDEO_START.max(start)..VIDEO_END.min(start + len);
-        let after_video = VIDEO_END.max(start)..start + len;
+        let video = self.video.clone();
+        let before_video = start..video.start.min(start + len);
+        let in_video = video.start.max(start)..video.end.min(start + len);
+        let after_video = video.end.max(start)..start + len;
 
         let mut bytes_read = 0;
 
         // Fast path: no need to check for transparent bytes
         if !before_video.is_empty() {
             let bytes = before_video.end - before_video.start;
-            bytes_read += self.set_with(before_video.start, 0, |buf, _| {
+            bytes_read += self.set_with(before_video.start, bytes, 0, |buf, _| {
                 reader.take(bytes as u64).read(buf).ok()
             })?;
         }
 
-        // Slow path: we need to check for transparent bytes and skip them
+        // Slow path: we may need to check for transparent bytes and skip them
         if !in_video.is_empty() {
             let mut mmio = self.mmio.lock();
 
@@ -178,23 +270,13 @@ impl Memory {
                 bytes_read += b;
 
                 // copy `b` bytes from `buf` to `mmio`
-                let mut i = 0;
-                while i + 4 <= b {
-                    let x = LittleEndian::read_u32(&buf[i..]);
-                    // copy a word
-                    if has_transparent_byte(x) {
-                        copy_with_transparency(&mut mmio[pos + i - MMIO_START..], x, 4);
-                    } else {
-                        LittleEndian::write_u32(&mut mmio[pos + i - MMIO_START..], x);
-                    }
-                    i += 4;
-                }
-                while i < b {
-                    // copy a byte
-                    if !has_transparent_byte(buf[i] as u32) {
-                        mmio[pos + i - MMIO_START] = buf[i];
+                let dst = &mut mmio[pos - MMIO_START..];
+                match self.transparent {
+                    Some(t) if buf[..b].contains(&t) => {
+                        copy_slice_with_transparency(dst, &buf[..b], t)
                     }
-                    i += 1;
+                    // No transparent bytes in this chunk, so it can be copied all at once
+                    _ => dst[..b].copy_from_slice(&buf[..b]),
                 }
 
                 pos += b;
@@ -204,23 +286,296 @@ impl Memory {
         // Fast path: no need to check for transparent bytes
         if !after_video.is_empty() {
             let bytes = after_video.end - after_video.start;
-            bytes_read += self.set_with(after_video.start, 0, |buf, _| {
+            bytes_read += self.set_with(after_video.start, bytes, 0, |buf, _| {
                 reader.take(bytes as u64).read(buf).ok()
             })?;
         }
 
+        self.mark_written(start, bytes_read);
         Some(bytes_read)
     }
 
-    /// Is `pos` out of memory bounds?
-    fn out_of_bounds(&self, pos: usize) -> bool {
+    /// Reads `buf.len()` bytes starting at `start` into `buf`, taking the MMIO lock only once.
+    /// Returns false, and leaves `buf` untouched, if the region is out of bounds.
+    pub fn read_bytes(&mut self, start: usize, buf: &mut [u8]) -> bool {
+        let len = buf.len();
+        if len == 0 || !self.check_range(start, len, Access::Load(len)) {
+            return len == 0;
+        }
+        self.get_with(start, len, |v| buf.copy_from_slice(&v[..len]));
+        true
+    }
+
+    /// Reads the null-terminated string that starts at `start`, taking the MMIO lock only once.
+    /// If the string runs past the end of its memory region, the first byte that's out of bounds
+    /// is flagged in `out_of_bounds_access`, just like reading it byte by byte would.
+    pub fn read_c_string(&mut self, start: usize) -> String {
+        let (string, unterminated_at) = self.get_with(start, 1, |v| {
+            let len = v.iter().position(|&b| b == 0);
+            let string = v[..len.unwrap_or(v.len())]
+                .iter()
+                .map(|&b| b as char)
+                .collect();
+            (string, len.is_none().then_some(start + v.len()))
+        });
+        if let Some(i) = unterminated_at {
+            self.out_of_bounds_access = Some((i, Access::Load(1)));
+        }
+        string
+    }
+
+    /// Writes `pattern` repeatedly to the `len` bytes starting at `dst`, taking the MMIO lock
+    /// only once. Unlike [`Memory::fill`], transparent bytes are written too, so this can clear
+    /// the screen. Nothing is written if the region is out of bounds.
+    pub fn fill_pattern(&mut self, dst: usize, pattern: &[u8], len: usize) {
+        if len == 0 || pattern.is_empty() || !self.check_range(dst, len, Access::Store(len)) {
+            return;
+        }
+        self.set_with(dst, len, pattern, |v, pattern| {
+            for chunk in v[..len].chunks_mut(pattern.len()) {
+                chunk.copy_from_slice(&pattern[..chunk.len()]);
+            }
+        });
+        self.mark_written(dst, len);
+    }
+
+    /// Checks that `len` bytes starting at `start` are inside a single memory region. If they
+    /// aren't, the first byte that's out of bounds is flagged in `out_of_bounds_access`.
+    fn check_range(&mut self, start: usize, len: usize, access: Access) -> bool {
+        let available = self.bytes_until_end(start);
+        if available < len {
+            self.out_of_bounds_access = Some((start + available, access));
+            return false;
+        }
+        true
+    }
+
+    /// Copies `len` bytes from `src` to `dst`, like `memmove`, so the regions may overlap.
+    /// Transparent bytes aren't copied to the video memory. Nothing is copied if either region is
+    /// out of bounds.
+    pub fn copy(&mut self, dst: usize, src: usize, len: usize) {
+        if len == 0
+            || !self.check_range(src, len, Access::Load(len))
+            || !self.check_range(dst, len, Access::Store(len))
+        {
+            return;
+        }
+        let mut bytes = vec![0; len];
+        self.read_bytes(src, &mut bytes);
+        self.set_reader(&mut &bytes[..], dst, len);
+    }
+
+    /// Sets `len` bytes starting at `dst` to `byte`, like `memset`. Filling the video memory with
+    /// the transparent byte does nothing. Nothing is written if the region is out of bounds.
+    pub fn fill(&mut self, dst: usize, byte: u8, len: usize) {
+        if len == 0 || !self.check_range(dst, len, Access::Store(len)) {
+            return;
+        }
+        let bytes = vec![byte; len];
+        self.set_reader(&mut &bytes[..], dst, len);
+    }
+
+    /// Number of bytes from `pos` to the end of the memory region (data, heap or MMIO) that
+    /// contains it. Zero if `pos` is out of bounds.
+    pub fn bytes_until_end(&self, pos: usize) -> usize {
         if pos >= MMIO_START {
             let mmio = self.mmio.lock();
-            pos - MMIO_START >= mmio.len()
+            mmio.len().saturating_sub(pos - MMIO_START)
         } else if pos >= HEAP_START {
-            pos - HEAP_START >= self.dynamic.len()
+            self.dynamic.len().saturating_sub(pos - HEAP_START)
         } else {
-            pos >= self.data.len()
+            self.data.len().saturating_sub(pos)
         }
     }
+
+    /// Is `pos` out of memory bounds?
+    fn out_of_bounds(&self, pos: usize) -> bool {
+        self.bytes_until_end(pos) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, OptionalConfig};
+    use clap::Parser;
+
+    #[test]
+    fn test_default_transparency() {
+        let mut memory = Memory::new();
+        memory.set_word(VIDEO_START, 0x11223344);
+        memory.set_word(VIDEO_START, 0xc7c755c7);
+        assert_eq!(memory.get_word(VIDEO_START), 0x11225544);
+    }
+
+    #[test]
+    fn test_transparency_disabled() {
+        let mut memory = Memory::new().with_transparent(None);
+        memory.set_word(VIDEO_START, 0x11223344);
+        memory.set_word(VIDEO_START, 0xc7c755c7);
+        assert_eq!(memory.get_word(VIDEO_START), 0xc7c755c7);
+
+        let image = [0xc7; 6];
+        memory.set_reader(&mut &image[..], VIDEO_START + 4, image.len());
+        assert_eq!(memory.get_word(VIDEO_START + 4), 0xc7c7c7c7);
+        assert_eq!(memory.get_half(VIDEO_START + 8), 0xc7c7);
+    }
+
+    #[test]
+    fn test_custom_transparent_byte() {
+        let mut memory = Memory::new().with_transparent(Some(0xff));
+        memory.set_word(VIDEO_START, 0x11223344);
+        memory.set_word(VIDEO_START, 0xc7ff55ff);
+        assert_eq!(memory.get_word(VIDEO_START), 0xc7225544);
+
+        memory.set_byte(VIDEO_START + 1, 0xff);
+        assert_eq!(memory.get_byte(VIDEO_START + 1), 0x55);
+
+        let image = [0xff, 0x01, 0xff, 0x02, 0xff];
+        memory.set_reader(&mut &image[..], VIDEO_START, image.len());
+        assert_eq!(memory.get_word(VIDEO_START), 0x02220144);
+    }
+
+    #[test]
+    fn test_custom_video_memory() {
+        let mut memory = Memory::new().with_video(0x1000..0x2000);
+        memory.set_word(VIDEO_START, 0xc7c7c7c7);
+        assert_eq!(memory.get_word(VIDEO_START), 0xc7c7c7c7);
+
+        memory.set_word(VIDEO_START + 0x1000, 0x11223344);
+        memory.set_word(VIDEO_START + 0x1000, 0xc7c755c7);
+        assert_eq!(memory.get_word(VIDEO_START + 0x1000), 0x11225544);
+
+        let image = [0xc7; 8];
+        memory.set_reader(&mut &image[..], VIDEO_START + 0x1ffc, image.len());
+        assert_eq!(memory.get_word(VIDEO_START + 0x1ffc), 0);
+        assert_eq!(memory.get_word(VIDEO_START + 0x2000), 0xc7c7c7c7);
+    }
+
+    #[test]
+    fn test_display_dimensions() {
+        let args = ["fpgrars", "--width", "640", "--height", "480", "program.s"];
+        let config = Config::from(OptionalConfig::parse_from(args));
+        let mut memory = Memory::new().with_display(
+            config.width,
+            config.height,
+            config.frame_layout.bytes_per_pixel,
+        );
+
+        assert_eq!(memory.get_word(MMIO_START + DISPLAY_WIDTH), 640);
+        assert_eq!(memory.get_word(MMIO_START + DISPLAY_HEIGHT), 480);
+        assert_eq!(
+            memory.get_word(MMIO_START + DISPLAY_BYTES_PER_PIXEL) as usize,
+            crate::renderer::BYTES_PER_PIXEL
+        );
+
+        // They're read-only
+        memory.set_word(MMIO_START + DISPLAY_WIDTH, 1);
+        memory.set_byte(MMIO_START + DISPLAY_HEIGHT + 1, 1);
+        memory.set_word(MMIO_START + DISPLAY_WIDTH - 2, 1);
+        assert_eq!(memory.get_word(MMIO_START + DISPLAY_WIDTH), 640);
+        assert_eq!(memory.get_word(MMIO_START + DISPLAY_HEIGHT), 480);
+
+        // Bulk writes that cover them only change the bytes around them
+        let around = MMIO_START + DISPLAY_WIDTH - 0x10;
+        memory.fill(around, 0xAB, 0x30);
+        memory.fill_pattern(around, &[1, 2], 0x30);
+        memory.set_reader(&mut &[7; 0x30][..], around, 0x30);
+        assert_eq!(memory.get_word(MMIO_START + DISPLAY_WIDTH), 640);
+        assert_eq!(memory.get_word(MMIO_START + DISPLAY_HEIGHT), 480);
+        assert_eq!(
+            memory.get_word(MMIO_START + DISPLAY_BYTES_PER_PIXEL) as usize,
+            crate::renderer::BYTES_PER_PIXEL
+        );
+        assert_eq!(memory.get_byte(around), 7);
+        assert_eq!(memory.get_byte(MMIO_START + DISPLAY_WIDTH - 1), 7);
+        assert_eq!(memory.get_byte(MMIO_START + DISPLAY_BYTES_PER_PIXEL + 4), 7);
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut memory = Memory::new();
+        memory.fill(0x100, 0xab, 6);
+        assert_eq!(memory.get_word(0x100), 0xabababab);
+        assert_eq!(memory.get_word(0x104), 0x0000abab);
+
+        // Transparent bytes are skipped in the video memory
+        memory.set_word(VIDEO_START, 0x11223344);
+        memory.fill(VIDEO_START, TRANSPARENT_BYTE, 4);
+        assert_eq!(memory.get_word(VIDEO_START), 0x11223344);
+
+        // Out of bounds fills write nothing
+        memory.fill(DATA_SIZE - 2, 0xff, 4);
+        assert_eq!(
+            memory.out_of_bounds_access,
+            Some((DATA_SIZE, Access::Store(4)))
+        );
+        assert_eq!(memory.get_half(DATA_SIZE - 2), 0);
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut memory = Memory::new();
+        memory.set_word(0x100, 0x44332211);
+        memory.copy(VIDEO_START + 8, 0x100, 4);
+        assert_eq!(memory.get_word(VIDEO_START + 8), 0x44332211);
+
+        // Overlapping regions, like memmove
+        memory.copy(0x101, 0x100, 4);
+        assert_eq!(memory.get_word(0x100), 0x33221111);
+        assert_eq!(memory.get_byte(0x104), 0x44);
+
+        memory.set_word(0x200, 0xc7c7c7c7);
+        memory.copy(VIDEO_START + 8, 0x200, 2);
+        assert_eq!(memory.get_word(VIDEO_START + 8), 0x44332211);
+        assert!(memory.out_of_bounds_access.is_none());
+
+        memory.copy(0x100, DATA_SIZE - 2, 4);
+        assert_eq!(
+            memory.out_of_bounds_access,
+            Some((DATA_SIZE, Access::Load(4)))
+        );
+        assert_eq!(memory.get_word(0x100), 0x33221111);
+    }
+
+    #[test]
+    fn test_batched_matches_byte_by_byte() {
+        let mut memory = Memory::new();
+        let image: Vec<u8> = (0..FRAME_SIZE).map(|i| (i % 97) as u8 + 1).collect();
+        memory.set_reader(&mut &image[..], VIDEO_START, image.len());
+        memory.set_byte(VIDEO_START + 1000, 0);
+
+        let mut bytes = vec![0; 2000];
+        assert!(memory.read_bytes(VIDEO_START, &mut bytes));
+        let expected: Vec<u8> = (0..2000)
+            .map(|i| memory.get_byte(VIDEO_START + i))
+            .collect();
+        assert_eq!(bytes, expected);
+
+        let expected: String = (VIDEO_START..)
+            .map(|i| memory.get_byte(i) as char)
+            .take_while(|&c| c != '\0')
+            .collect();
+        assert_eq!(memory.read_c_string(VIDEO_START), expected);
+        assert_eq!(expected.chars().count(), 1000);
+
+        memory.fill_pattern(VIDEO_START + 4, &[0x11, 0xc7, 0x33, 0x44], 4 * 100 + 2);
+        for i in 0..100 {
+            assert_eq!(memory.get_word(VIDEO_START + 4 + 4 * i), 0x4433c711);
+        }
+        assert_eq!(memory.get_half(VIDEO_START + 4 + 4 * 100), 0xc711);
+        assert_eq!(
+            memory.get_byte(VIDEO_START + 4 + 4 * 100 + 2),
+            image[4 * 101 + 2]
+        );
+        assert!(memory.out_of_bounds_access.is_none());
+
+        // Strings that run off the end of memory are flagged at the same byte
+        memory.set_word(DATA_SIZE - 4, 0x41414141);
+        assert_eq!(memory.read_c_string(DATA_SIZE - 4), "AAAA");
+        assert_eq!(
+            memory.out_of_bounds_access,
+            Some((DATA_SIZE, Access::Load(1)))
+        );
+    }
 }
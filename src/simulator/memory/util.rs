@@ -1,4 +1,4 @@
-use crate::simulator::{TRANSPARENT_BYTE, TRANSPARENT_WORD};
+use byteorder::{ByteOrder, LittleEndian};
 
 /// From https://graphics.stanford.edu/~seander/bithacks.html#ZeroInWord
 /// I have no idea whether these u32 should be u32s or u64s because UL means nothing
@@ -7,22 +7,45 @@ fn has_zero_byte(v: u32) -> bool {
     (((v).wrapping_sub(0x01010101u32)) & !(v) & 0x80808080u32) != 0
 }
 
+/// Whether any byte of `v` is the `transparent` one
 #[inline]
-pub fn has_transparent_byte(v: u32) -> bool {
-    has_zero_byte(v ^ TRANSPARENT_WORD)
+pub fn has_transparent_byte(v: u32, transparent: u8) -> bool {
+    has_zero_byte(v ^ u32::from_ne_bytes([transparent; 4]))
 }
 
 /// Copies `n` bytes from `x` to the buffer, but ignores transparent bytes
-pub fn copy_with_transparency(buf: &mut [u8], mut x: u32, n: usize) {
+pub fn copy_with_transparency(buf: &mut [u8], mut x: u32, n: usize, transparent: u8) {
     for data in &mut buf[0..n] {
         let byte = x as u8;
-        if byte != TRANSPARENT_BYTE {
+        if byte != transparent {
             *data = byte;
         }
         x >>= 8;
     }
 }
 
+/// Copies `src` to the start of `dst`, but ignores transparent bytes
+pub fn copy_slice_with_transparency(dst: &mut [u8], src: &[u8], transparent: u8) {
+    let mut i = 0;
+    while i + 4 <= src.len() {
+        // copy a word
+        let x = LittleEndian::read_u32(&src[i..]);
+        if has_transparent_byte(x, transparent) {
+            copy_with_transparency(&mut dst[i..], x, 4, transparent);
+        } else {
+            LittleEndian::write_u32(&mut dst[i..], x);
+        }
+        i += 4;
+    }
+    while i < src.len() {
+        // copy a byte
+        if src[i] != transparent {
+            dst[i] = src[i];
+        }
+        i += 1;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -36,4 +59,44 @@ mod test {
         assert!(has_zero_byte(0x12005678));
         assert!(has_zero_byte(0x11223300));
     }
+
+    #[test]
+    fn test_has_transparent_byte() {
+        assert!(has_transparent_byte(0x00c70000, 0xc7));
+        assert!(!has_transparent_byte(0x00c70000, 0xc8));
+        assert!(has_transparent_byte(0x12345600, 0x00));
+        assert!(!has_transparent_byte(0x12345678, 0x00));
+    }
+
+    #[test]
+    fn test_copy_with_transparency() {
+        let mut buf = [0xaa; 4];
+        copy_with_transparency(&mut buf, 0x11ff22ff, 4, 0xff);
+        assert_eq!(buf, [0xaa, 0x22, 0xaa, 0x11]);
+    }
+
+    #[test]
+    fn test_copy_slice_with_transparency() {
+        // Without transparent bytes, the slow path is the same as a plain copy
+        let src: Vec<u8> = (0..103).collect();
+        let mut slow = vec![0xaa; 110];
+        let mut fast = slow.clone();
+        copy_slice_with_transparency(&mut slow, &src, 0xc7);
+        fast[..src.len()].copy_from_slice(&src);
+        assert_eq!(slow, fast);
+
+        // With them, it's the same as copying byte by byte
+        let src: Vec<u8> = (0..103)
+            .map(|i| if i % 3 == 0 { 0xc7 } else { i })
+            .collect();
+        let mut slow = vec![0xaa; 110];
+        let mut expected = slow.clone();
+        copy_slice_with_transparency(&mut slow, &src, 0xc7);
+        for (dst, &byte) in expected.iter_mut().zip(&src) {
+            if byte != 0xc7 {
+                *dst = byte;
+            }
+        }
+        assert_eq!(slow, expected);
+    }
 }
@@ -0,0 +1,106 @@
+//! Tracks which bytes of the data and heap memory were written, for `--sanitize-uninit`
+
+use super::{HEAP_START, MMIO_START};
+
+/// One bit per byte of the data and heap memory, set when the byte is written. Bytes of the MMIO
+/// aren't tracked, since devices write to them.
+#[derive(Debug, Default, Clone)]
+pub struct WrittenBytes {
+    data: Vec<u64>,
+    heap: Vec<u64>,
+}
+
+impl WrittenBytes {
+    /// Starts with the first `initialized` bytes of the data segment (the ones the program
+    /// declared) marked as written
+    pub fn new(initialized: usize) -> Self {
+        let mut written = Self::default();
+        written.mark(0, initialized);
+        written
+    }
+
+    /// Whether address `i` is in the heap, and its offset in its bitmap. `None` if it's not tracked
+    fn locate(i: usize) -> Option<(bool, usize)> {
+        if i >= MMIO_START {
+            None
+        } else if i >= HEAP_START {
+            Some((true, i - HEAP_START))
+        } else {
+            Some((false, i))
+        }
+    }
+
+    /// Marks `len` bytes starting at `start` as written
+    pub fn mark(&mut self, start: usize, len: usize) {
+        let Some((in_heap, start)) = Self::locate(start) else {
+            return;
+        };
+        let bits = if in_heap {
+            &mut self.heap
+        } else {
+            &mut self.data
+        };
+
+        let end = start + len;
+        if bits.len() * 64 < end {
+            bits.resize(end.div_ceil(64), 0);
+        }
+        for i in start..end {
+            bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    /// Forgets that the heap bytes from `len` onwards were written, because `sbrk` freed them
+    pub fn truncate_heap(&mut self, len: usize) {
+        let words = len.div_ceil(64);
+        self.heap.truncate(words);
+        if let Some(last) = self.heap.last_mut().filter(|_| !len.is_multiple_of(64)) {
+            *last &= (1 << (len % 64)) - 1;
+        }
+    }
+
+    /// The first byte of the `len` bytes starting at `start` that was never written, if any
+    pub fn first_unwritten(&self, start: usize, len: usize) -> Option<usize> {
+        let (in_heap, offset) = Self::locate(start)?;
+        let bits = if in_heap { &self.heap } else { &self.data };
+        (offset..offset + len)
+            .position(|i| {
+                bits.get(i / 64)
+                    .is_none_or(|word| word & (1 << (i % 64)) == 0)
+            })
+            .map(|i| start + i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_written_bytes() {
+        let mut written = WrittenBytes::new(6);
+        assert_eq!(written.first_unwritten(0, 6), None);
+        assert_eq!(written.first_unwritten(4, 4), Some(6));
+
+        written.mark(HEAP_START + 60, 8);
+        assert_eq!(written.first_unwritten(HEAP_START + 60, 8), None);
+        assert_eq!(
+            written.first_unwritten(HEAP_START + 58, 4),
+            Some(HEAP_START + 58)
+        );
+        assert_eq!(
+            written.first_unwritten(HEAP_START + 66, 4),
+            Some(HEAP_START + 68)
+        );
+
+        written.truncate_heap(64);
+        assert_eq!(written.first_unwritten(HEAP_START + 60, 4), None);
+        assert_eq!(
+            written.first_unwritten(HEAP_START + 64, 1),
+            Some(HEAP_START + 64)
+        );
+
+        // The MMIO isn't tracked
+        assert_eq!(written.first_unwritten(MMIO_START, 4), None);
+    }
+}
@@ -2,7 +2,7 @@
 //! Defines MIDI ecalls
 //!
 
-use midir::{MidiOutput, MidiOutputConnection};
+use midir::{MidiOutput, MidiOutputConnection, SendError};
 use parking_lot::Mutex;
 use std::error::Error;
 use std::fmt;
@@ -14,6 +14,9 @@ const NOTE_ON: u8 = 0x90;
 const NOTE_OFF: u8 = 0x80;
 const PROGRAM_CHANGE: u8 = 0xC0;
 
+/// In General MIDI, channel 9 plays percussion, and each pitch is a different drum
+const PERCUSSION_CHANNEL: u8 = 9;
+
 const DEFAULT_PORT: usize = 0;
 
 /// When [MidiPlayer::get_connection](struct.MidiPlayer.html#method.get_connection) can't connect
@@ -22,6 +25,8 @@ const DEFAULT_PORT: usize = 0;
 enum ConnectionError {
     CouldntCreateOutput,
     NoPorts,
+    /// No port name contains the requested device name. Has the names of the available ports
+    NoMatchingDevice(String, Vec<String>),
     CouldntConnect,
 }
 
@@ -33,6 +38,12 @@ impl fmt::Display for ConnectionError {
                 write!(f, "Couldn't create MidiOutput, MIDI ecalls will not work")
             }
             NoPorts => write!(f, "No MIDI output port found. MIDI ecalls will not work"),
+            NoMatchingDevice(device, available) => write!(
+                f,
+                "No MIDI device matches '{}', MIDI ecalls will not work. Available devices: {}",
+                device,
+                available.join(", ")
+            ),
             CouldntConnect => write!(
                 f,
                 "Couldn't connect the MIDI output to port, MIDI ecalls will not work"
@@ -43,8 +54,19 @@ impl fmt::Display for ConnectionError {
 
 impl Error for ConnectionError {}
 
+/// Where MIDI messages are sent to. Normally a [`MidiOutputConnection`], but tests use a mock
+trait MidiConnection: Send {
+    fn send(&mut self, message: &[u8]) -> Result<(), SendError>;
+}
+
+impl MidiConnection for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), SendError> {
+        MidiOutputConnection::send(self, message)
+    }
+}
+
 struct MidiPlayerData {
-    conn: MidiOutputConnection,
+    conn: Box<dyn MidiConnection>,
 
     /// `channels[x]` is the channel that will be used the next time instrument `x`
     /// is played. We alternate between channels 0..=15 for each instrument, so overlapping
@@ -56,33 +78,66 @@ struct MidiPlayerData {
 /// A MidiPlayer connects to a MidiOutputConnection and plays notes.
 /// `play_note()` blocks the thread for the duration of the note.
 #[derive(Default, Clone)]
-pub struct MidiPlayer(Option<Arc<Mutex<MidiPlayerData>>>);
+pub struct MidiPlayer {
+    data: Option<Arc<Mutex<MidiPlayerData>>>,
+    /// Threads playing async notes. They're joined by [`MidiPlayer::flush`], so notes aren't cut
+    /// off when the program exits right after playing them
+    pending: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+}
 
 impl MidiPlayer {
-    pub fn new(port: Option<usize>) -> Self {
-        match Self::get_connection(port) {
-            Ok(c) => Self(Some(Arc::new(Mutex::new(MidiPlayerData {
-                conn: c,
-                channels: [0; 128],
-            })))),
+    /// Connects to the MIDI output port with index `port`. If `device` is given, the first port
+    /// whose name contains it is used instead
+    pub fn new(port: Option<usize>, device: Option<&str>) -> Self {
+        match Self::get_connection(port, device) {
+            Ok(c) => Self::with_connection(Box::new(c)),
             Err(e) => {
                 eprintln!("Warning: {}", e);
-                Self(None)
+                Self::default()
             }
         }
     }
 
-    fn get_connection(port: Option<usize>) -> Result<MidiOutputConnection, ConnectionError> {
+    fn with_connection(conn: Box<dyn MidiConnection>) -> Self {
+        Self {
+            data: Some(Arc::new(Mutex::new(MidiPlayerData {
+                conn,
+                channels: [0; 128],
+            }))),
+            pending: Arc::default(),
+        }
+    }
+
+    fn get_connection(
+        port: Option<usize>,
+        device: Option<&str>,
+    ) -> Result<MidiOutputConnection, ConnectionError> {
         let midi_out = match MidiOutput::new("FPGRARS_MIDI_Out") {
             Ok(x) => x,
             Err(_) => return Err(ConnectionError::CouldntCreateOutput),
         };
 
         let ports = midi_out.ports();
-        if port.is_some() && port.unwrap() >= ports.len() {
+        let port = match device {
+            Some(device) => {
+                let names: Vec<String> = ports
+                    .iter()
+                    .map(|p| midi_out.port_name(p).unwrap_or_default())
+                    .collect();
+                match find_device(&names, device) {
+                    Some(i) => Some(i),
+                    None => {
+                        return Err(ConnectionError::NoMatchingDevice(device.to_owned(), names))
+                    }
+                }
+            }
+            None => port,
+        };
+
+        if let Some(port) = port.filter(|&p| p >= ports.len()) {
             panic!(
                 "Provided MIDI port ({}) isn't valid (should be in range 0-{} inclusive)",
-                port.unwrap(),
+                port,
                 ports.len() as isize - 1
             );
         }
@@ -103,42 +158,50 @@ impl MidiPlayer {
         }
     }
 
-    /// Plays a blocking MIDI note (unless there's no connection)
-    fn play_note(&self, pitch: u8, duration: u32, instrument: u8, velocity: u8) {
-        if self.0.is_none() {
+    /// Plays a blocking MIDI note (unless there's no connection). An `instrument` of `None` plays
+    /// the note in the percussion channel
+    fn play_note(&self, pitch: u8, duration: u32, instrument: Option<u8>, velocity: u8) {
+        if self.data.is_none() {
             return;
         }
 
         let ch;
         {
-            let mut d = self.0.as_ref().unwrap().lock();
-            ch = get_channel(&mut d, instrument);
+            let mut d = self.data.as_ref().unwrap().lock();
+            ch = match instrument {
+                Some(instrument) => {
+                    let ch = get_channel(&mut d, instrument);
+                    d.conn
+                        .send(&[PROGRAM_CHANGE | ch, instrument])
+                        .expect("Failed to send PROGRAM_CHANGE message to MIDI output");
+                    ch
+                }
+                None => PERCUSSION_CHANNEL,
+            };
 
-            d.conn
-                .send(&[PROGRAM_CHANGE | ch, instrument])
-                .expect("Failed to send PROGRAM_CHANGE message to MIDI output");
             d.conn
                 .send(&[NOTE_ON | ch, pitch, velocity])
                 .expect("Failed to send NOTE_ON message to MIDI output");
         }
         thread::sleep(Duration::from_millis(duration as u64));
         {
-            let mut d = self.0.as_ref().unwrap().lock();
+            let mut d = self.data.as_ref().unwrap().lock();
             d.conn
                 .send(&[NOTE_OFF | ch, pitch, velocity])
                 .expect("Failed to send NOTE_OFF message to MIDI output");
         }
     }
 
-    /// Tries to handle a MIDI ecall and returns whether we could handle it
+    /// Tries to handle a MIDI ecall and returns whether we could handle it.
+    /// Ecalls 131 and 133 work like 31 and 33, but play percussion, ignoring the instrument
     pub fn handle_ecall(&self, ecall: u32, registers: &mut [u32; 32]) -> bool {
-        if (ecall != 31 && ecall != 33) || self.0.is_none() {
+        if !matches!(ecall, 31 | 33 | 131 | 133) || self.data.is_none() {
             return false;
         }
 
         let pitch = registers[10]; // a0
         let duration = registers[11] as i32; // a1
-        let instrument = registers[12]; // a2
+        let instrument = (ecall < 100).then_some(registers[12] as u8); // a2
         let velocity = registers[13]; // a3
 
         let player = self.clone();
@@ -146,7 +209,7 @@ impl MidiPlayer {
             player.play_note(
                 pitch as u8,
                 if duration < 0 { 1000 } else { duration as u32 },
-                instrument as u8,
+                instrument,
                 if (0..128).contains(&velocity) {
                     velocity as u8
                 } else {
@@ -155,9 +218,11 @@ impl MidiPlayer {
             );
         };
 
-        if ecall == 31 {
+        if ecall % 100 == 31 {
             // MIDI Out Async
-            thread::spawn(play);
+            let mut pending = self.pending.lock();
+            pending.retain(|handle| !handle.is_finished());
+            pending.push(thread::spawn(play));
         } else {
             // MIDI Out Sync
             play();
@@ -165,6 +230,22 @@ impl MidiPlayer {
 
         true
     }
+
+    /// Waits until every async note has finished playing
+    pub fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        for handle in pending {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Finds the first port name that contains `device`, ignoring case
+fn find_device(names: &[String], device: &str) -> Option<usize> {
+    let device = device.to_lowercase();
+    names
+        .iter()
+        .position(|name| name.to_lowercase().contains(&device))
 }
 
 /// Get and increment channel for a given instrument
@@ -176,3 +257,86 @@ fn get_channel(d: &mut MidiPlayerData, instrument: u8) -> u8 {
     } // ch 9 is only for percussion...
     *ch
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the messages instead of sending them anywhere
+    struct MockConnection(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl MidiConnection for MockConnection {
+        fn send(&mut self, message: &[u8]) -> Result<(), SendError> {
+            self.0.lock().push(message.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_find_device() {
+        let names = [
+            "Midi Through:Midi Through Port-0 14:0",
+            "FLUID Synth (1234):Synth input port (1234:0) 128:0",
+            "TiMidity:TiMidity port 0 129:0",
+        ]
+        .map(String::from);
+
+        assert_eq!(find_device(&names, "fluid"), Some(1));
+        assert_eq!(find_device(&names, "TiMidity port"), Some(2));
+        assert_eq!(find_device(&names, "port"), Some(0));
+        assert_eq!(find_device(&names, "yamaha"), None);
+        assert_eq!(find_device(&[], "fluid"), None);
+    }
+
+    #[test]
+    fn test_percussion_channel() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let player = MidiPlayer::with_connection(Box::new(MockConnection(messages.clone())));
+        let channels = || {
+            let channels = messages
+                .lock()
+                .iter()
+                .map(|m| m[0] & 0x0F)
+                .collect::<Vec<_>>();
+            messages.lock().clear();
+            channels
+        };
+
+        // Melodic notes go round-robin through every channel, except the percussion one
+        let mut registers = [0; 32];
+        for _ in 0..32 {
+            assert!(player.handle_ecall(33, &mut registers));
+        }
+        let melodic = channels();
+        assert_eq!(melodic.len(), 3 * 32);
+        assert!(!melodic.contains(&PERCUSSION_CHANNEL));
+
+        registers[12] = 5; // the instrument is ignored
+        assert!(player.handle_ecall(133, &mut registers));
+        assert_eq!(channels(), [PERCUSSION_CHANNEL, PERCUSSION_CHANNEL]);
+    }
+
+    #[test]
+    fn test_async_notes_are_joined() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let player = MidiPlayer::with_connection(Box::new(MockConnection(messages.clone())));
+
+        let mut registers = [0; 32];
+        registers[10] = 60; // pitch
+        registers[11] = 50; // duration
+        registers[13] = 100; // velocity
+        assert!(player.handle_ecall(31, &mut registers));
+        registers[10] = 64;
+        assert!(player.handle_ecall(31, &mut registers));
+        assert!(!player.pending.lock().is_empty());
+
+        player.flush();
+        assert!(player.pending.lock().is_empty());
+
+        let messages = messages.lock();
+        let note_offs = messages.iter().filter(|m| m[0] & 0xF0 == NOTE_OFF);
+        let mut pitches: Vec<_> = note_offs.map(|m| m[1]).collect();
+        pitches.sort();
+        assert_eq!(pitches, [60, 64]);
+    }
+}
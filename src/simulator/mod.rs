@@ -5,20 +5,54 @@
 //! and you can find how they're simulated at [Simulator::run](struct.Simulator.html#method.run)
 //!
 
+mod console;
+mod error;
 mod executor;
 mod files;
 mod into_register;
 pub mod memory;
 mod midi;
+pub mod state;
+mod trace;
 mod util;
 
 use crate::config::Config;
+use crate::instruction::Instruction;
 use crate::parser;
-use crate::renderer::{FRAME_0, FRAME_1, FRAME_SIZE};
+use crate::renderer::{InputSignal, FRAME_SIZE, KDMMIO_CONTROL};
+pub use error::SimError;
 use into_register::*;
 use memory::*;
 use owo_colors::OwoColorize;
-use std::{mem, time};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{mem, thread, time};
+
+/// `ucause` of a division by zero with `--trap-div-zero`. RISC-V has no such exception, so this
+/// is the first cause code reserved for custom use
+const DIV_BY_ZERO_CAUSE: u32 = 24;
+/// `ucause` of a signed overflow in `add` or `sub` with `--trap-overflow`. Also not in the spec.
+const OVERFLOW_CAUSE: u32 = 25;
+/// How many instructions run between checks of the pause flag and the `--max-ips` throttle
+const CHECK_INTERVAL: u64 = 4096;
+/// When the throttled simulation falls behind by more than this (because of a slow ecall, for
+/// example), it doesn't try to catch up
+const MAX_THROTTLE_LAG: time::Duration = time::Duration::from_millis(100);
+/// How often a program waiting for input checks the keyboard MMIO, in case a key arrives without
+/// a notification
+const IDLE_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// The heap's backing memory grows by at least this many bytes (or doubles, if that's more), so
+/// many small `sbrk`s don't each reallocate
+const MIN_HEAP_GROWTH: usize = 64 * 1024;
+
+/// Digits after the point are capped when printing floats, an f32 doesn't have nearly this many
+const MAX_FLOAT_PRECISION: u32 = 64;
 
 /// Returned by the [ecall](struct.Simulator.html#method.ecall) procedure
 enum EcallSignal {
@@ -34,13 +68,47 @@ pub struct Simulator {
 
     registers: [u32; 32],
     floats: [f32; 32],
-    status: Vec<u32>, // I'm not sure myself how many status registers I'll use
+    /// One for each name in `STATUSVEC`, allocated once when the simulator is created
+    status: Vec<u32>,
     pc: usize,
+    /// Number of instructions retired since the program started
+    instret: u64,
     started_at: time::Instant,
     exit_code: i32,
+    /// Why the execution stopped, if it was because of an error
+    error: Option<SimError>,
+    /// Whether we already warned about a write to `x0` (see `--warn-zero-write`)
+    warned_zero_write: bool,
+    /// Instructions that already warned about reading uninitialized memory (see
+    /// `--sanitize-uninit`), by index
+    warned_uninit: HashSet<usize>,
 
     open_files: files::FileHolder,
     midi_player: midi::MidiPlayer,
+    /// Where the read int/float ecalls read from. Generally stdin
+    input: Box<dyn BufRead>,
+    /// Where the print ecalls write to. Generally stdout
+    output: Box<dyn io::Write>,
+    /// Logs every instruction executed, if `--trace` is set
+    tracer: Option<trace::Tracer>,
+    /// Console input for the non-blocking read char ecall. Started the first time it's used
+    console: Option<console::InputQueue>,
+    /// Execution stops while this is set. Toggled by the bitmap display
+    paused: Option<Arc<AtomicBool>>,
+    /// Notified by the bitmap display and input scripts on key events, wakes up ecall 111. `None`
+    /// when there's no source of input.
+    input_signal: Option<InputSignal>,
+    /// `instret` at which the next periodic check (like whether we're paused) happens. `u64::MAX`
+    /// if there's nothing to check, so the executor doesn't pay for it.
+    next_check: u64,
+    /// Instructions between periodic checks
+    check_interval: u64,
+    /// When and at which `instret` the `--max-ips` throttle started counting
+    throttle_from: (time::Instant, u64),
+    /// `instret` of the last ecall or memory write, tracked for `--watchdog`
+    last_progress: u64,
+    /// Whether the watchdog already warned about a loop
+    watchdog_fired: bool,
 
     pub memory: Memory,
     pub code: Vec<executor::Executor>,
@@ -53,12 +121,28 @@ impl Default for Simulator {
             config: Config::default(),
             registers: [0; 32],
             floats: [0.0; 32],
-            status: Vec::new(),
+            status: vec![0; parser::register_names::STATUSVEC.len()],
             pc: 0,
+            instret: 0,
             started_at: time::Instant::now(), // Will be set again in run()
             exit_code: 0,
+            error: None,
+            warned_zero_write: false,
+            warned_uninit: HashSet::new(),
             open_files: files::FileHolder::new(),
             midi_player: midi::MidiPlayer::default(),
+            // Not locked, or the console input thread couldn't read stdin
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+            tracer: None,
+            console: None,
+            paused: None,
+            input_signal: None,
+            next_check: u64::MAX,
+            check_interval: CHECK_INTERVAL,
+            throttle_from: (time::Instant::now(), 0),
+            last_progress: 0,
+            watchdog_fired: false,
             memory: Memory::new(),
             code: Vec::new(),
             code_ctx: Vec::new(),
@@ -67,10 +151,52 @@ impl Default for Simulator {
 }
 
 impl Simulator {
+    fn parse_options(&self) -> parser::ParseOptions {
+        parser::ParseOptions {
+            imm_range_check: !self.config.no_imm_range_check,
+            hw_range_check: self.config.hw_range_check,
+        }
+    }
+
     pub fn load_file(&mut self, path: &str) -> Result<(), parser::error::Error> {
-        let parsed = parser::parse(path, DATA_SIZE)?;
-        self.load_parsed_output(parsed);
-        Ok(())
+        let options = self.parse_options();
+        self.load_with(|data| parser::parse_with_options(path, data, options))
+    }
+
+    /// Loads a program from a string instead of a file. `name` is shown as the file name in error
+    /// messages, and `.include`s are relative to it.
+    /// ```
+    /// use fpgrars::simulator::Simulator;
+    ///
+    /// let mut sim = Simulator::default();
+    /// sim.load_str("li a0, 42\n li a7, 93\n ecall", "answer.s").unwrap();
+    /// assert_eq!(sim.run().unwrap(), 42);
+    /// ```
+    pub fn load_str(&mut self, source: &str, name: &str) -> Result<(), parser::error::Error> {
+        let options = self.parse_options();
+        self.load_with(|data| {
+            parser::parse_str_with_options(source.to_owned(), name, data, options)
+        })
+    }
+
+    /// Hands the data memory to `parse`, so the data segment is written to it directly instead of
+    /// to a second buffer. The memory gets a fresh one if parsing fails.
+    fn load_with<F>(&mut self, parse: F) -> Result<(), parser::error::Error>
+    where
+        F: FnOnce(Vec<u8>) -> parser::ParseResult,
+    {
+        let mut data = mem::take(&mut self.memory.data);
+        data.resize(DATA_SIZE, 0);
+        match parse(data) {
+            Ok(parsed) => {
+                self.load_parsed_output(parsed);
+                Ok(())
+            }
+            Err(e) => {
+                self.memory.data = vec![0; DATA_SIZE];
+                Err(e)
+            }
+        }
     }
 
     fn load_parsed_output(&mut self, parsed: parser::Parsed) {
@@ -78,10 +204,45 @@ impl Simulator {
             code,
             code_ctx,
             data,
+            data_len,
             globl,
+            labels: _,
         } = parsed;
 
+        // Each option wraps the executors the previous ones left, so they can all be combined
         self.code = executor::compile_all(&code);
+        if self.config.warn_zero_write {
+            self.wrap_code(&code, |i, executor| {
+                if i.writes_to_zero() {
+                    executor::with_zero_write_warning(executor)
+                } else {
+                    executor
+                }
+            });
+        }
+        if self.config.trap_div_zero {
+            self.wrap_code(&code, executor::with_div_zero_trap);
+        }
+        if self.config.trap_overflow {
+            self.wrap_code(&code, executor::with_overflow_trap);
+        }
+        if self.config.sanitize_uninit {
+            self.wrap_code(&code, executor::with_uninit_check);
+            self.memory.written = Some(WrittenBytes::new(data_len));
+        }
+        if self.config.watchdog.is_some() {
+            self.wrap_code(&code, |i, executor| {
+                if i.writes_memory() {
+                    executor::with_progress_tracking(executor)
+                } else {
+                    executor
+                }
+            });
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.load(&code_ctx);
+            self.wrap_code(&code, |_, executor| executor::with_trace(executor));
+        }
         self.code_ctx = code_ctx;
         self.memory.data = data;
 
@@ -96,8 +257,43 @@ impl Simulator {
         }
     }
 
+    /// Replaces the executor of each instruction in `code` by `wrap(instruction, executor)`
+    fn wrap_code<F>(&mut self, code: &[Instruction], wrap: F)
+    where
+        F: Fn(&Instruction, executor::Executor) -> executor::Executor,
+    {
+        self.code = mem::take(&mut self.code)
+            .into_iter()
+            .zip(code)
+            .map(|(executor, i)| wrap(i, executor))
+            .collect();
+    }
+
+    /// Copies the contents of the file at `path` into memory, starting at `addr`. Writes to the
+    /// video memory respect transparency, like any other store.
+    pub fn load_image(&mut self, addr: u32, path: &str) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+
+        let written = self.memory.set_reader(&mut file, addr as usize, len);
+        if written != Some(len) || self.memory.out_of_bounds_access.take().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes at {:#x} don't fit in memory", len, addr),
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn with_midi_port(mut self, midi_port: Option<usize>) -> Self {
-        self.midi_player = midi::MidiPlayer::new(midi_port);
+        self.midi_player = midi::MidiPlayer::new(midi_port, None);
+        self
+    }
+
+    /// Uses the first MIDI output port whose name contains `device`
+    pub fn with_midi_device(mut self, device: &str) -> Self {
+        self.midi_player = midi::MidiPlayer::new(None, Some(device));
         self
     }
 
@@ -111,6 +307,34 @@ impl Simulator {
         self
     }
 
+    /// Pauses the execution while `paused` is set
+    pub fn with_pause_flag(mut self, paused: Arc<AtomicBool>) -> Self {
+        self.paused = Some(paused);
+        self
+    }
+
+    /// Lets ecall 111 sleep until `input_signal` is notified. Without one, it returns right away.
+    pub fn with_input_signal(mut self, input_signal: InputSignal) -> Self {
+        self.input_signal = Some(input_signal);
+        self
+    }
+
+    pub fn with_input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = Box::new(input);
+        self
+    }
+
+    pub fn with_output(mut self, output: impl io::Write + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Writes a trace of the execution to `out`. Must be set before the program is loaded.
+    pub fn with_trace(mut self, out: impl io::Write + 'static) -> Self {
+        self.tracer = Some(trace::Tracer::new(out));
+        self
+    }
+
     #[inline]
     fn reg<T: FromRegister>(&self, i: u8) -> T {
         FromRegister::from(unsafe { *self.registers.get_unchecked(i as usize) })
@@ -124,79 +348,453 @@ impl Simulator {
     }
 
     fn get_status(&self, i: u8) -> u32 {
-        if i == parser::register_names::TIME_INDEX {
-            self.started_at.elapsed().as_millis() as u32
-        } else {
-            self.status[i as usize]
+        use parser::register_names::*;
+        let millis = || self.started_at.elapsed().as_millis() as u64;
+        match i {
+            // The 64-bit counters are split in two registers, the ones ending in `h` have the
+            // upper half
+            TIME_INDEX => millis() as u32,
+            TIMEH_INDEX => (millis() >> 32) as u32,
+            // There's no cycle-accurate simulation, so every instruction takes one cycle
+            INSTRET_INDEX | CYCLE_INDEX => self.instret as u32,
+            INSTRETH_INDEX | CYCLEH_INDEX => (self.instret >> 32) as u32,
+            _ => self.status[i as usize],
         }
     }
 
-    #[allow(clippy::needless_range_loop)]
     pub fn print_state(&self) {
-        use parser::register_names::REGVEC;
+        let _ = self.write_state(&mut io::stderr().lock());
+    }
+
+    /// Writes the pc, the last instruction executed and the registers in a human-readable way
+    #[allow(clippy::needless_range_loop)]
+    fn write_state(&self, out: &mut impl io::Write) -> io::Result<()> {
+        use parser::register_names::{self, REGVEC};
 
-        eprintln!("{}", "Registers:".bright_blue());
+        writeln!(out, "{} {:#010x}", "pc:".bright_blue(), self.pc)?;
+        match self.code_ctx.get(self.pc / 4) {
+            Some(ctx) => writeln!(out, "{}\n{}", "Last instruction:".bright_blue(), ctx)?,
+            // The exit sequence appended by the parser has no context
+            None => writeln!(
+                out,
+                "{} end of the program",
+                "Last instruction:".bright_blue()
+            )?,
+        }
+
+        writeln!(out, "{}", "Registers:".bright_blue())?;
         for i in 0..32 {
             let regname = if i == 0 { "0" } else { REGVEC[i] };
             let reg = format!("x{:02}/{:03}", i, regname);
-            eprint!("{}: {:08x} ", reg.bright_blue(), self.registers[i]);
+            write!(out, "{}: {:08x} ", reg.bright_blue(), self.registers[i])?;
             if i % 4 == 3 {
-                eprintln!();
+                writeln!(out)?;
             }
         }
-        eprintln!();
-        eprintln!("{}", "Float Registers:".bright_blue());
+        writeln!(out)?;
+        writeln!(out, "{}", "Float Registers:".bright_blue())?;
         for i in 0..32 {
-            eprint!(
+            write!(
+                out,
                 "{}{:02}: {:<8} ",
                 "f".bright_blue(),
                 i.bright_blue(),
                 self.floats[i]
-            );
+            )?;
             if i % 4 == 3 {
-                eprintln!();
+                writeln!(out)?;
+            }
+        }
+        writeln!(out)?;
+
+        writeln!(out, "{}", "Status Registers:".bright_blue())?;
+        for (i, name) in register_names::STATUSVEC.iter().enumerate() {
+            write!(
+                out,
+                "{}: {:08x} ",
+                format!("{name:>8}").bright_blue(),
+                self.get_status(i as u8)
+            )?;
+            if i % 4 == 3 {
+                writeln!(out)?;
+            }
+        }
+        writeln!(out)
+    }
+
+    /// Called when an `ebreak` is executed. Prints where the program stopped and the current
+    /// state of the registers, then execution continues normally.
+    fn on_breakpoint(&mut self) {
+        eprintln!(
+            "   {} reached at pc {:#x}\n{}",
+            "ebreak".on_bright_magenta(),
+            self.pc,
+            self.code_ctx[self.pc / 4]
+        );
+        self.print_state();
+    }
+
+    /// Called before an instruction that writes to `x0` is executed, if `--warn-zero-write` is set.
+    /// Only the first one is reported.
+    fn on_zero_write(&mut self) {
+        if self.warned_zero_write {
+            return;
+        }
+        self.warned_zero_write = true;
+
+        eprintln!(
+            "   {} This instruction writes to {}, so its result is discarded\n{}",
+            "[warning]".bright_yellow(),
+            "zero".bright_blue(),
+            self.code_ctx[self.pc / 4]
+        );
+    }
+
+    /// Called before a load from `addr`, if `--sanitize-uninit` is set. Warns if any of the `len`
+    /// bytes were never written. Each instruction is only reported once.
+    fn check_uninit_read(&mut self, addr: usize, len: usize) {
+        let Some(written) = &self.memory.written else {
+            return;
+        };
+        let Some(byte) = written.first_unwritten(addr, len) else {
+            return;
+        };
+        if !self.warned_uninit.insert(self.pc / 4) {
+            return;
+        }
+
+        eprintln!(
+            "   {} This instruction reads {}, which the program never wrote\n{}",
+            "[warning]".bright_yellow(),
+            format!("{:#x}", byte).bright_blue(),
+            self.code_ctx[self.pc / 4]
+        );
+    }
+
+    /// Called by the executor every `check_interval` instructions, when there's something to
+    /// check. Blocks while the execution is paused, or while it's ahead of `--max-ips`.
+    /// Returns whether the execution should continue.
+    #[cold]
+    fn periodic_check(&mut self) -> bool {
+        if let Some(paused) = &self.paused {
+            if paused.load(Ordering::Relaxed) {
+                while paused.load(Ordering::Relaxed) {
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                self.throttle_from = (time::Instant::now(), self.instret);
+            }
+        }
+
+        if let Some(max_ips) = self.config.max_ips {
+            self.throttle(max_ips);
+        }
+
+        if let Some(limit) = self.config.watchdog {
+            if !self.watchdog_fired && self.instret - self.last_progress >= limit {
+                self.watchdog_fired = true;
+                if !self.on_watchdog(limit) {
+                    return false;
+                }
             }
         }
-        eprintln!();
+
+        self.next_check = self.instret + self.check_interval;
+        true
+    }
+
+    /// Called when `limit` instructions ran without any ecall or memory write. Warns about it,
+    /// and returns whether the execution should continue
+    fn on_watchdog(&mut self, limit: u64) -> bool {
+        let location = match self.code_ctx.get(self.pc / 4) {
+            Some(ctx) => ctx.to_string(),
+            None => format!("   at pc {:#x}", self.pc),
+        };
+        eprintln!(
+            "   {} {} instructions ran without any ecall or memory write, so the program may be stuck in a loop\n{}",
+            "[warning]".bright_yellow(),
+            limit,
+            location
+        );
+
+        if self.config.watchdog_exit {
+            self.exit_code = 1;
+            return false;
+        }
+        true
+    }
+
+    /// Sleeps until the instructions executed so far should have taken at least as long as
+    /// `max_ips` allows
+    fn throttle(&mut self, max_ips: u64) {
+        let (since, instret) = self.throttle_from;
+        let instructions = (self.instret - instret) as f64;
+        let expected = time::Duration::from_secs_f64(instructions / max_ips as f64);
+        let elapsed = since.elapsed();
+
+        if elapsed < expected {
+            thread::sleep(expected - elapsed);
+        } else if elapsed - expected > MAX_THROTTLE_LAG {
+            self.throttle_from = (time::Instant::now(), self.instret);
+        }
+    }
+
+    /// Called when dividing by zero with `--trap-div-zero`. Returns whether the execution should
+    /// continue.
+    fn on_div_zero(&mut self) -> bool {
+        self.arithmetic_exception(DIV_BY_ZERO_CAUSE, "Division by zero")
+    }
+
+    /// Called when an `add` or `sub` overflows with `--trap-overflow`. Returns whether the
+    /// execution should continue.
+    fn on_overflow(&mut self) -> bool {
+        self.arithmetic_exception(OVERFLOW_CAUSE, "Signed integer overflow")
     }
 
-    fn init(&mut self) {
-        // Create necessary status registers
-        self.status
-            .resize(parser::register_names::status().len(), 0);
+    /// Jumps to the user trap handler, or stops with an error if there's none
+    fn arithmetic_exception(&mut self, cause: u32, description: &'static str) -> bool {
+        if self.user_traps_enabled() {
+            self.trap(cause, 0);
+            return true;
+        }
 
+        self.error = Some(SimError::ArithmeticException {
+            cause,
+            description,
+            ctx: self.current_ctx(),
+        });
+        false
+    }
+
+    /// Context of the instruction being executed
+    fn current_ctx(&self) -> parser::token::Context {
+        self.code_ctx[self.pc / 4].clone()
+    }
+
+    /// Whether the program enabled user traps, by setting the lowest bit of `ustatus`
+    fn user_traps_enabled(&self) -> bool {
+        use parser::register_names::USTATUS_INDEX;
+        self.status[USTATUS_INDEX as usize] & 1 == 1
+    }
+
+    /// Whether an exception can go to the user trap handler. The handler must be enabled and be the
+    /// address of an instruction, otherwise we'd keep trapping forever
+    fn can_trap(&self, code: &[executor::Executor]) -> bool {
+        use parser::register_names::UTVEC_INDEX;
+        let utvec = self.status[UTVEC_INDEX as usize] as usize;
+        self.user_traps_enabled() && utvec.is_multiple_of(4) && utvec / 4 < code.len()
+    }
+
+    /// Jumps to the user trap handler in `utvec`. Like in a real trap, `uepc` is set to the
+    /// current instruction, so the handler can return to it with `uret`.
+    fn trap(&mut self, cause: u32, tval: u32) {
+        use parser::register_names::*;
+        self.status[UCAUSE_INDEX as usize] = cause;
+        self.status[UTVAL_INDEX as usize] = tval;
+        self.status[UEPC_INDEX as usize] = self.pc as u32;
+        self.pc = self.status[UTVEC_INDEX as usize] as usize;
+    }
+
+    fn init(&mut self) -> Result<(), SimError> {
         // Set stack pointer
-        self.set_reg(2, self.memory.data.len() as u32 - 4);
+        let sp = self.config.sp.unwrap_or(self.memory.data.len() as u32 - 4);
+        if sp as usize > self.memory.data.len() - 4 {
+            return Err(SimError::StackPointerOutOfBounds {
+                sp,
+                max: self.memory.data.len() - 4,
+            });
+        }
+        self.set_reg(2, sp);
 
         // Set global pointer
-        self.set_reg(3, 0x10008000);
+        self.set_reg(3, self.config.gp.unwrap_or(0x10008000));
 
+        self.instret = 0;
         self.started_at = time::Instant::now();
+
+        // The throttle checks about 100 times per second to be smooth, and the watchdog at least
+        // once per `watchdog` instructions
+        let (max_ips, watchdog) = (self.config.max_ips, self.config.watchdog);
+        self.check_interval = [max_ips.map(|ips| ips / 100), watchdog]
+            .into_iter()
+            .flatten()
+            .fold(CHECK_INTERVAL, u64::min)
+            .max(1);
+        self.next_check = if self.paused.is_some() || max_ips.is_some() || watchdog.is_some() {
+            self.check_interval
+        } else {
+            u64::MAX
+        };
+        self.throttle_from = (self.started_at, 0);
+        self.last_progress = 0;
+        self.watchdog_fired = false;
+        self.error = None;
         self.status[parser::register_names::MISA_INDEX as usize] = 0x40001128;
+        Ok(())
     }
 
-    pub fn run(&mut self) -> i32 {
-        self.init();
+    /// Runs the program until it exits, returning its exit code, or until an error stops it
+    pub fn run(&mut self) -> Result<i32, SimError> {
+        self.init()?;
         if self.code.is_empty() {
-            return 0;
+            return Ok(0);
         }
 
         // Copy code to local variable so we can access it without borrowing self
         let code = mem::take(&mut self.code);
 
-        executor::next(self, &code, self.pc);
+        executor::start(self, &code, self.pc);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.finish(&self.registers, &self.floats);
+            tracer.flush();
+        }
+        self.midi_player.flush();
+        let _ = self.output.flush();
 
         if self.config.print_state {
             self.print_state();
         }
 
-        self.exit_code
+        if let Some(path) = &self.config.dump_state {
+            if let Err(e) = state::State::from_simulator(self).write_to_file(path) {
+                eprintln!(
+                    "   {} Couldn't write the state to {}: {}",
+                    "[error]".bright_red(),
+                    path.bright_yellow(),
+                    e
+                );
+            }
+        }
+
+        if let Some(path) = &self.config.screenshot {
+            let mmio = self.memory.mmio.lock();
+            let (width, height) = (self.config.width, self.config.height);
+            let layout = &self.config.frame_layout;
+            if let Err(e) = crate::renderer::screenshot(&mmio, width, height, layout, path) {
+                eprintln!(
+                    "   {} Couldn't save the screenshot to {}: {}",
+                    "[error]".bright_red(),
+                    path.bright_yellow(),
+                    e
+                );
+            }
+        }
+
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(self.exit_code),
+        }
+    }
+
+    /// Moves the end of the heap by `bytes` and returns the previous end, which is the address of
+    /// the allocated chunk. Like RARS, negative increments are not allowed, unless the
+    /// `allow_sbrk_shrink` option is set.
+    fn sbrk(&mut self, bytes: i32) -> Result<u32, String> {
+        let old_end = (HEAP_START + self.memory.dynamic.len()) as u32;
+
+        if bytes < 0 {
+            if !self.config.allow_sbrk_shrink {
+                return Err(format!(
+                    "`sbrk` does not allow negative increments (got {}). Use {} to allow it",
+                    bytes.bright_blue(),
+                    "--allow-sbrk-shrink".bright_blue()
+                ));
+            }
+
+            let bytes = bytes.unsigned_abs() as usize / 4 * 4; // only full words are freed
+            if bytes > self.memory.dynamic.len() {
+                return Err(format!(
+                    "`sbrk` tried to free {} bytes, but only {} were allocated",
+                    bytes.bright_blue(),
+                    self.memory.dynamic.len().bright_blue()
+                ));
+            }
+            let len = self.memory.dynamic.len() - bytes;
+            self.memory.dynamic.truncate(len);
+            if let Some(written) = &mut self.memory.written {
+                written.truncate_heap(len);
+            }
+        } else {
+            let padding = (4 - bytes % 4) % 4; // makes sure we're always allocating full words
+            let bytes = (bytes + padding) as usize;
+            let heap = &mut self.memory.dynamic;
+            // `len` is the end of the heap as the program sees it, and `capacity` is how much was
+            // actually allocated
+            let (len, capacity) = (heap.len(), heap.capacity());
+            if len + bytes > capacity {
+                let new_capacity = (len + bytes).max(2 * capacity).max(MIN_HEAP_GROWTH);
+                heap.reserve_exact(new_capacity - len);
+            }
+            heap.resize(len + bytes, 0);
+        }
+
+        Ok(old_end)
     }
 
+    /// Reads a line from the input and parses it. Invalid values are reported and read again,
+    /// so a typo doesn't kill the program.
+    fn read_input<T: FromStr>(&mut self, what: &'static str) -> Result<T, SimError> {
+        loop {
+            let mut buf = String::new();
+            if let Ok(0) | Err(_) = self.input.read_line(&mut buf) {
+                return Err(SimError::InputEnded {
+                    what,
+                    ctx: self.current_ctx(),
+                });
+            }
+
+            match buf.trim().parse() {
+                Ok(x) => return Ok(x),
+                Err(_) => eprintln!(
+                    "   {} '{}' is not {}, please try again",
+                    "[warning]".bright_yellow(),
+                    buf.trim().bright_yellow(),
+                    what
+                ),
+            }
+        }
+    }
+
+    /// Sleeps until a key is typed, like `wfi` waits for an interrupt. May also wake up on other
+    /// key events, so programs should check the keyboard afterwards.
+    fn wait_for_input(&mut self) {
+        loop {
+            if self.memory.mmio.lock()[KDMMIO_CONTROL] & 1 != 0 {
+                return;
+            }
+
+            match &self.input_signal {
+                Some(signal) => {
+                    if signal.wait(IDLE_POLL_INTERVAL) {
+                        return;
+                    }
+                }
+                // Nothing can type, so waiting would never end
+                None => return,
+            }
+        }
+    }
+
+    /// Prints the output of an ecall
+    fn print(&mut self, args: fmt::Arguments) {
+        let _ = self.output.write_fmt(args);
+    }
+
+    /// Executes an ecall. Errors stop the execution.
     fn ecall(&mut self) -> EcallSignal {
-        use parser::register_names::*;
+        match self.handle_ecall() {
+            Ok(signal) => signal,
+            Err(error) => {
+                self.error = Some(error);
+                EcallSignal::Exit
+            }
+        }
+    }
+
+    fn handle_ecall(&mut self) -> Result<EcallSignal, SimError> {
         use rand::{thread_rng, Rng};
 
+        self.last_progress = self.instret;
+
         let a7 = self.reg::<u32>(17);
 
         if files::handle_ecall(
@@ -205,74 +803,80 @@ impl Simulator {
             &mut self.registers,
             &mut self.memory,
         ) {
-            return EcallSignal::Nothing;
+            return Ok(EcallSignal::Nothing);
         }
 
         if self.midi_player.handle_ecall(a7, &mut self.registers) {
-            return EcallSignal::Nothing;
+            return Ok(EcallSignal::Nothing);
         }
 
         match a7 {
             10 => {
                 self.exit_code = 0;
-                return EcallSignal::Exit;
+                return Ok(EcallSignal::Exit);
             }
             93 => {
                 self.exit_code = self.reg::<i32>(10); // a0
-                return EcallSignal::Exit;
+                return Ok(EcallSignal::Exit);
             }
             1 => {
                 // print int
-                print!("{}", self.reg::<i32>(10));
+                self.print(format_args!("{}", self.reg::<i32>(10)));
             }
             2 => {
                 // print float
-                print!("{}", self.floats[10]);
+                let x = self.floats[10];
+                self.print(format_args!("{}", x));
             }
             4 => {
                 // print string
                 let start = self.reg::<u32>(10) as usize; // a0
-                (start..)
-                    .map(|i| self.memory.get_byte(i) as char)
-                    .take_while(|&c| c != '\0')
-                    .for_each(|c| print!("{}", c));
+                let string = self.memory.read_c_string(start);
+                self.print(format_args!("{}", string));
             }
             5 => {
                 // read int
-                let mut buf = String::new();
-                std::io::stdin().read_line(&mut buf).unwrap();
-                self.set_reg(10, buf.trim().parse::<i32>().unwrap());
+                let x = self.read_input::<i32>("an integer")?;
+                self.set_reg(10, x);
             }
             6 => {
                 // read float
-                let mut buf = String::new();
-                std::io::stdin().read_line(&mut buf).unwrap();
-                self.floats[10] = buf.trim().parse::<f32>().unwrap();
+                self.floats[10] = self.read_input::<f32>("a float")?;
             }
 
             9 => {
                 // sbrk
-                // Like RARS, negative increments are not allowed
                 let bytes = self.reg::<i32>(10); // a0
+                let addr = self.sbrk(bytes).map_err(|message| SimError::Sbrk {
+                    message,
+                    ctx: self.current_ctx(),
+                })?;
+                self.set_reg(10, addr);
+            }
 
-                if bytes < 0 {
-                    panic!("`sbrk` does not allow negative increments");
-                }
-
-                let padding = (4 - bytes % 4) % 4; // makes sure we're always allocating full words
-                let bytes = (bytes + padding) as usize;
-
-                self.set_reg(10, (HEAP_START + self.memory.dynamic.len()) as u32);
+            110 => {
+                // stop execution
+                self.exit_code = 0;
+                return Ok(EcallSignal::Exit);
+            }
 
-                self.memory.dynamic.reserve(bytes); // may reserve more than `bytes`
-                self.memory
-                    .dynamic
-                    .resize(self.memory.dynamic.len() + bytes, 0);
+            111 => {
+                // wait for input
+                self.wait_for_input();
             }
 
             11 => {
                 // print char
-                print!("{}", self.reg::<u32>(10) as u8 as char);
+                self.print(format_args!("{}", self.reg::<u32>(10) as u8 as char));
+            }
+
+            112 => {
+                // read char without blocking, -1 if nothing was typed
+                let console = self
+                    .console
+                    .get_or_insert_with(|| console::InputQueue::spawn(io::stdin()));
+                let c = console.poll().map_or(-1, |c| c as i32);
+                self.set_reg(10, c);
             }
 
             30 => {
@@ -292,12 +896,34 @@ impl Simulator {
 
             34 => {
                 // print hex int
-                print!("{:#X}", self.reg::<u32>(10));
+                self.print(format_args!("{:#X}", self.reg::<u32>(10)));
+            }
+
+            35 => {
+                // print binary int, with all 32 bits
+                self.print(format_args!("{:032b}", self.reg::<u32>(10)));
+            }
+
+            135 => {
+                // print binary int, without leading zeros
+                self.print(format_args!("{:b}", self.reg::<u32>(10)));
             }
 
             36 => {
                 // print unsigned int
-                print!("{}", self.reg::<u32>(10));
+                self.print(format_args!("{}", self.reg::<u32>(10)));
+            }
+
+            37 => {
+                // print float in hex
+                self.print(format_args!("{}", util::hex_float(self.floats[10])));
+            }
+
+            38 => {
+                // print float in scientific notation, with a1 digits after the point
+                let precision = self.reg::<u32>(11).min(MAX_FLOAT_PRECISION) as usize;
+                let x = self.floats[10];
+                self.print(format_args!("{:.*e}", precision, x));
             }
 
             // RNG stuff
@@ -320,14 +946,37 @@ impl Simulator {
 
             48 | 148 => {
                 // clear screen
-                let color = self.reg::<u8>(10); // a0
+                let color = self.reg::<u32>(10); // a0
                 let frame_select = self.reg::<u32>(11); // a1
 
-                let mut mmio = self.memory.mmio.lock();
-                let frame = if frame_select == 0 { FRAME_0 } else { FRAME_1 };
-                for x in &mut mmio[frame..frame + FRAME_SIZE] {
-                    *x = color;
-                }
+                let video = self.memory.video.clone();
+                let start = video.start + if frame_select == 0 { 0 } else { FRAME_SIZE };
+                let pattern = color.to_le_bytes();
+                // Every pixel is a whole word in the 24-bit format
+                let pixel = if cfg!(feature = "unb") {
+                    &pattern[..1]
+                } else {
+                    &pattern[..]
+                };
+                // With a custom layout, the registers may come right after the pixels of frame 1
+                let len = FRAME_SIZE.min(video.end - start);
+                self.memory.fill_pattern(start, pixel, len);
+            }
+
+            1026 => {
+                // memcpy
+                let dst = self.reg::<u32>(10) as usize; // a0
+                let src = self.reg::<u32>(11) as usize; // a1
+                let len = self.reg::<u32>(12) as usize; // a2
+                self.memory.copy(dst, src, len);
+            }
+
+            1027 => {
+                // memset
+                let dst = self.reg::<u32>(10) as usize; // a0
+                let byte = self.reg::<u32>(11) as u8; // a1
+                let len = self.reg::<u32>(12) as usize; // a2
+                self.memory.fill(dst, byte, len);
             }
 
             // These two should only be here temporarily for convenience
@@ -339,24 +988,823 @@ impl Simulator {
             }
 
             // Does the user want to handle this ecall?
-            _x if self.status[USTATUS_INDEX as usize] & 1 == 1 => {
-                self.status[UCAUSE_INDEX as usize] = 8; // ecall exception
-                self.status[UEPC_INDEX as usize] = self.pc as u32; // set uret location
-                self.pc = self.status[UTVEC_INDEX as usize] as usize; // jump to utvec
-                return EcallSignal::Continue;
+            _x if self.user_traps_enabled() => {
+                self.trap(8, 0); // ecall exception
+                return Ok(EcallSignal::Continue);
             }
 
-            x => {
-                let ctx = &self.code_ctx[self.pc / 4];
-                eprintln!(
-                    "   Your code tried calling the ecall {}, which is not implemented in FPGRARS!\n{}",
-                    x.bright_blue(),
-                    ctx
-                );
-                std::process::exit(1);
+            number => {
+                return Err(SimError::UnimplementedEcall {
+                    number,
+                    ctx: self.current_ctx(),
+                });
+            }
+        }
+
+        Ok(EcallSignal::Nothing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn simulator_from_str(code: &str) -> Simulator {
+        let parsed = parser::parse_str(code.to_owned(), DATA_SIZE).unwrap();
+        let mut sim = Simulator::default();
+        sim.load_parsed_output(parsed);
+        sim
+    }
+
+    pub(super) fn simulator_with_config(code: &str, config: Config) -> Simulator {
+        let parsed = parser::parse_str(code.to_owned(), DATA_SIZE).unwrap();
+        let mut sim = Simulator::default().with_config(config);
+        sim.load_parsed_output(parsed);
+        sim
+    }
+
+    #[test]
+    fn test_read_int_retries_on_garbage() {
+        let mut sim = simulator_from_str("li a7 5\n ecall\n mv s0 a0\n li a7 6\n ecall")
+            .with_input(io::Cursor::new("not a number\n42\n??\n1.5\n"));
+        sim.run().unwrap();
+        assert_eq!(sim.registers[8], 42);
+        assert_eq!(sim.floats[10], 1.5);
+    }
+
+    #[test]
+    fn test_ebreak_continues_execution() {
+        let mut sim = simulator_from_str("li s0 1\n ebreak\n li s0 2");
+        sim.run().unwrap();
+        assert_eq!(sim.registers[8], 2);
+    }
+
+    #[test]
+    fn test_fcvt_rounding_modes() {
+        let mut sim = simulator_from_str(
+            "li t0 0x40200000 # 2.5
+            fmv.s.x ft0 t0
+            fcvt.w.s s0 ft0 rne
+            fcvt.w.s s1 ft0 rtz
+            fcvt.w.s s2 ft0 rup
+            fcvt.w.s s3 ft0 rmm
+            fneg.s ft1 ft0
+            fcvt.w.s s4 ft1 rdn
+            fcvt.wu.s s5 ft1
+            li t0 0x4f800000 # 2^32
+            fmv.s.x ft2 t0
+            fcvt.w.s s6 ft2",
+        );
+        sim.run().unwrap();
+        assert_eq!(&sim.registers[8..10], &[2, 2]);
+        assert_eq!(
+            &sim.registers[18..23],
+            &[3, 3, -3i32 as u32, 0, i32::MAX as u32]
+        );
+    }
+
+    #[test]
+    fn test_instret() {
+        let mut sim = simulator_from_str(
+            "nop
+            li t0 1
+            add t0 t0 t0
+            nop
+            nop
+            csrr s0 instret
+            csrr s1 instreth
+            csrr s2 cycle",
+        );
+        sim.run().unwrap();
+        assert_eq!(&sim.registers[8..10], &[5, 0]);
+        assert_eq!(sim.registers[18], 7);
+    }
+
+    #[test]
+    fn test_status_length() {
+        use parser::register_names::STATUSVEC;
+        let mut sim = simulator_from_str("csrwi uscratch, 5");
+        assert_eq!(sim.status.len(), STATUSVEC.len());
+
+        sim.init().unwrap();
+        assert_eq!(sim.status.len(), STATUSVEC.len());
+        let allocation = sim.status.as_ptr();
+
+        // Running again reuses the same registers
+        sim.run().unwrap();
+        sim.init().unwrap();
+        assert_eq!(sim.status.len(), STATUSVEC.len());
+        assert_eq!(sim.status.as_ptr(), allocation);
+    }
+
+    #[test]
+    fn test_machine_mode_status_registers() {
+        let code = "
+            la t0, handler
+            csrw t0, mtvec
+            csrsi mstatus, 1
+            csrr s0, ustatus
+            div s2, t0, zero
+            li s3, 1 # the handler returns here
+            j end
+
+        handler:
+            csrr s1, mcause
+            csrr t1, mepc
+            addi t1, t1, 4
+            csrw t1, sepc
+            mret
+        end:
+        ";
+        let config = Config {
+            trap_div_zero: true,
+            ..Config::default()
+        };
+        let mut sim = simulator_with_config(code, config);
+        sim.run().unwrap();
+
+        assert_eq!(sim.reg::<u32>(8), 1);
+        assert_eq!(sim.reg::<u32>(9), DIV_BY_ZERO_CAUSE);
+        assert_eq!(sim.reg::<u32>(19), 1);
+    }
+
+    #[test]
+    fn test_64_bit_counters() {
+        use parser::register_names::*;
+
+        let sim = Simulator {
+            instret: 0x1_2345_6789,
+            ..Default::default()
+        };
+        assert_eq!(sim.get_status(INSTRET_INDEX), 0x2345_6789);
+        assert_eq!(sim.get_status(INSTRETH_INDEX), 1);
+        assert_eq!(sim.get_status(CYCLE_INDEX), 0x2345_6789);
+        assert_eq!(sim.get_status(CYCLEH_INDEX), 1);
+
+        let mut last = 0;
+        for _ in 0..20 {
+            let high = sim.get_status(TIMEH_INDEX) as u64;
+            let time = high << 32 | sim.get_status(TIME_INDEX) as u64;
+            assert!(time >= last);
+            last = time;
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        assert!(last >= 19);
+    }
+
+    #[test]
+    fn test_amoadd_accumulates() {
+        let mut sim = simulator_from_str(
+            ".data
+            counter: .word 10
+            .text
+            la a0 counter
+            li t0 5
+            amoadd.w s0, t0, (a0)
+            amoadd.w.aq s1, t0, (a0)
+            lw s2 0(a0)",
+        );
+        sim.run().unwrap();
+        assert_eq!(&sim.registers[8..10], &[10, 15]);
+        assert_eq!(sim.registers[18], 20);
+    }
+
+    #[test]
+    fn test_sbrk_rejects_negative_increments() {
+        let mut sim = Simulator::default();
+        assert_eq!(sim.sbrk(6), Ok(HEAP_START as u32));
+        assert!(sim.sbrk(-4).is_err());
+        assert_eq!(sim.memory.dynamic.len(), 8);
+    }
+
+    #[test]
+    fn test_many_small_sbrks() {
+        let mut sim = Simulator::default();
+        let mut capacities = vec![];
+        let mut end = HEAP_START as u32;
+        for i in 0..1000 {
+            let bytes = 1 + i % 8;
+            assert_eq!(sim.sbrk(bytes), Ok(end), "sbrk #{i} isn't contiguous");
+            end += (bytes as u32).next_multiple_of(4);
+
+            let capacity = sim.memory.dynamic.capacity();
+            if capacities.last() != Some(&capacity) {
+                capacities.push(capacity);
+            }
+        }
+
+        assert_eq!(sim.memory.dynamic.len() as u32, end - HEAP_START as u32);
+        assert!(capacities.len() <= 2, "reallocated at {capacities:?}");
+
+        // Memory past the end of the heap is out of bounds, even if it's allocated
+        sim.memory.get_byte(end as usize);
+        assert_eq!(
+            sim.memory.out_of_bounds_access,
+            Some((end as usize, Access::Load(1)))
+        );
+    }
+
+    #[test]
+    fn test_sanitize_uninit() {
+        let code = "
+            .data
+            x: .word 5
+            .text
+            li a0 8
+            li a7 9
+            ecall
+            lw t0 0(a0) # never written
+            sw t0 4(a0)
+            lw t1 4(a0)
+            lb t2 3(a0) # never written
+            la t3 x
+            lw t4 0(t3)
+            lw t5 -8(sp) # the stack was never written either
+        ";
+        let config = Config {
+            sanitize_uninit: true,
+            ..Config::default()
+        };
+        let mut sim = simulator_with_config(code, config);
+        sim.run().unwrap();
+
+        let mut warned: Vec<_> = sim
+            .warned_uninit
+            .iter()
+            .map(|&i| sim.code_ctx[i].line)
+            .collect();
+        warned.sort();
+        assert_eq!(warned, [8, 11, 14]);
+    }
+
+    #[test]
+    fn test_sbrk_shrink() {
+        let config = Config {
+            allow_sbrk_shrink: true,
+            ..Config::default()
+        };
+        let mut sim = Simulator::default().with_config(config);
+        assert_eq!(sim.sbrk(16), Ok(HEAP_START as u32));
+        assert_eq!(sim.sbrk(-8), Ok(HEAP_START as u32 + 16));
+        assert_eq!(sim.memory.dynamic.len(), 8);
+        assert_eq!(sim.sbrk(4), Ok(HEAP_START as u32 + 8));
+        assert!(sim.sbrk(-16).is_err());
+    }
+
+    #[test]
+    fn test_load_image() {
+        let path = crate::temp_path("load_image.bin");
+        std::fs::write(&path, [0xde, 0xad, 0xbe, 0xef, 0x42]).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut sim = Simulator::default();
+        sim.load_image(0x100, path).unwrap();
+        assert_eq!(sim.memory.get_byte(0xff), 0);
+        assert_eq!(sim.memory.get_byte(0x100), 0xde);
+        assert_eq!(sim.memory.get_byte(0x103), 0xef);
+        assert_eq!(sim.memory.get_byte(0x104), 0x42);
+        assert_eq!(sim.memory.get_byte(0x105), 0);
+
+        let end_of_data = sim.memory.data.len() as u32 - 2;
+        assert!(sim.load_image(end_of_data, path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_warn_zero_write() {
+        let warns = |code: &str| {
+            let config = Config {
+                warn_zero_write: true,
+                ..Config::default()
+            };
+            let mut sim = simulator_with_config(code, config);
+            sim.run().unwrap();
+            sim.warned_zero_write
+        };
+
+        assert!(warns("li zero 5"));
+        assert!(!warns("li t0 5"));
+        assert!(!warns("nop\n j end\n end:"));
+    }
+
+    #[test]
+    fn test_combined_checks() {
+        let run = |code: &str| {
+            let config = Config {
+                warn_zero_write: true,
+                trap_div_zero: true,
+                trap_overflow: true,
+                sanitize_uninit: true,
+                watchdog: Some(1_000_000),
+                ..Config::default()
+            };
+            let mut sim = simulator_with_config(code, config);
+            sim.run().unwrap();
+            sim
+        };
+
+        // Every option wraps the same instructions without replacing the others' checks
+        assert!(run("li t0 7\n div zero t0 t0").warned_zero_write);
+        assert!(run("li t0 7\n add zero t0 t0").warned_zero_write);
+
+        let sim = run("addi sp sp -8\n lw zero 0(sp)");
+        assert!(sim.warned_zero_write);
+        assert!(sim.warned_uninit.contains(&1));
+
+        let sim = run("addi sp sp -8\n amoswap.w t0 t1 (sp)");
+        assert!(sim.warned_uninit.contains(&1));
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        let code = "
+            li t0, 7
+            div t1, t0, zero
+            divu t2, t0, zero
+            rem t3, t0, zero
+            remu t4, t0, zero
+        ";
+        let mut sim = simulator_from_str(code);
+        sim.run().unwrap();
+        assert_eq!(sim.reg::<i32>(6), -1);
+        assert_eq!(sim.reg::<u32>(7), u32::MAX);
+        assert_eq!(sim.reg::<u32>(28), 7);
+        assert_eq!(sim.reg::<u32>(29), 7);
+    }
+
+    #[test]
+    fn test_trap_div_zero() {
+        let code = "
+            la t0, handler
+            csrw t0, utvec
+            csrsi ustatus, 1
+            li t0, 7
+            div s2, t0, zero
+            li s3, 1 # the handler returns here
+            div s4, t0, t0
+            j end
+
+        handler:
+            csrr s0, ucause
+            csrr s1, uepc
+            addi t1, s1, 4
+            csrw t1, uepc
+            uret
+        end:
+        ";
+        let config = Config {
+            trap_div_zero: true,
+            ..Config::default()
+        };
+        let mut sim = simulator_with_config(code, config);
+        sim.run().unwrap();
+
+        assert_eq!(sim.reg::<u32>(8), DIV_BY_ZERO_CAUSE);
+        assert_eq!(sim.reg::<u32>(9), 4 * 4); // address of the first div
+        assert_eq!(sim.reg::<u32>(18), 0); // the div didn't write anything
+        assert_eq!(sim.reg::<u32>(19), 1);
+        assert_eq!(sim.reg::<u32>(20), 1);
+    }
+
+    #[test]
+    fn test_trap_overflow() {
+        let code = "
+            la t0, handler
+            csrw t0, utvec
+            csrsi ustatus, 1
+            li t0, 0x7fffffff
+            li t1, 1
+            add s2, t0, t1
+            li t2, 0x80000000
+            sub s3, t2, t1
+            add s4, t1, t1
+            j end
+
+        handler:
+            csrr s0, ucause
+            addi s1, s1, 1
+            csrr t3, uepc
+            addi t3, t3, 4
+            csrw t3, uepc
+            uret
+        end:
+        ";
+
+        let mut sim = simulator_from_str(code);
+        sim.run().unwrap();
+        assert_eq!(sim.reg::<u32>(18), 0x80000000); // wraps by default
+        assert_eq!(sim.reg::<u32>(19), 0x7fffffff);
+        assert_eq!(sim.reg::<u32>(9), 0);
+
+        let config = Config {
+            trap_overflow: true,
+            ..Config::default()
+        };
+        let mut sim = simulator_with_config(code, config);
+        sim.run().unwrap();
+        assert_eq!(sim.reg::<u32>(8), OVERFLOW_CAUSE);
+        assert_eq!(sim.reg::<u32>(9), 2); // both overflows trapped
+        assert_eq!(sim.reg::<u32>(18), 0);
+        assert_eq!(sim.reg::<u32>(19), 0);
+        assert_eq!(sim.reg::<u32>(20), 2);
+    }
+
+    #[test]
+    fn test_trap_out_of_bounds_access() {
+        let code = "
+            la t0, handler
+            csrw t0, utvec
+            csrsi ustatus, 1
+            li t0, 0x80000000
+            li s2, 42
+            lw s2, 0(t0)
+            li s3, 1 # the handler returns here
+            sw s3, 4(t0)
+            j end
+
+        handler:
+            csrr t1, ucause
+            csrr t2, utval
+            add s4, s4, t1
+            add s5, s5, t2
+            csrr t1, uepc
+            addi t1, t1, 4
+            csrw t1, uepc
+            uret
+        end:
+        ";
+        let mut sim = simulator_from_str(code);
+        sim.run().unwrap();
+
+        assert_eq!(sim.reg::<u32>(18), 42); // the faulting load doesn't write to s2
+        assert_eq!(sim.reg::<u32>(19), 1);
+        assert_eq!(sim.reg::<u32>(20), 5 + 7); // load fault + store fault
+        assert_eq!(sim.instret, 25); // neither the load nor the store were retired
+        assert_eq!(sim.reg::<u32>(21), 0x80000000u32.wrapping_add(0x80000004));
+    }
+
+    #[test]
+    fn test_out_of_bounds_reported_promptly() {
+        // Every kind of instruction that checks for out-of-bounds accesses, on line 3
+        let accesses = [
+            "lb t1 0(t0)",
+            "sh t1 0(t0)",
+            "flw ft0 0(t0)",
+            "fsw ft0 0(t0)",
+            "lr.w t1 (t0)",
+            "amoadd.w t1 t2 (t0)",
+            "mv a0 t0\n li a1 0\n li a2 4\n li a7 1027\n ecall",
+        ];
+        for access in accesses {
+            let code = format!("li t0 0x50000000\n li s1 1\n {access}\n li s0 1\n li s0 2");
+            let mut sim = simulator_from_str(&code);
+            let Err(SimError::MemoryOutOfBounds { address, ctx, .. }) = sim.run() else {
+                panic!("`{access}` should be out of bounds");
+            };
+            assert_eq!(address, 0x50000000, "{access}");
+            assert_eq!(ctx.line as usize, 2 + access.lines().count(), "{access}");
+            assert_eq!(sim.reg::<u32>(9), 1, "{access}");
+            assert_eq!(sim.reg::<u32>(8), 0, "`{access}` should stop right away");
+        }
+    }
+
+    #[test]
+    fn test_mandelbrot_matches_reference() {
+        let mut sim = simulator_from_str(include_str!("../../benches/samples/mandelbrot.s"));
+        sim.run().unwrap();
+
+        // Same computation, with the same single precision operations in the same order
+        let (x0, y0, step) = (-2.0f32, -1.2f32, 0.02f32);
+        let mut total = 0;
+        for y in 0..120 {
+            let ci = y as f32 * step + y0;
+            for x in 0..160 {
+                let cr = x as f32 * step + x0;
+                let (mut zr, mut zi, mut iterations) = (0.0f32, 0.0f32, 0);
+                while iterations < 64 {
+                    let (zr2, zi2) = (zr * zr, zi * zi);
+                    if 4.0 < zr2 + zi2 {
+                        break;
+                    }
+                    let zrzi = zr * zi;
+                    zi = (zrzi + zrzi) + ci;
+                    zr = (zr2 - zi2) + cr;
+                    iterations += 1;
+                }
+                total += iterations;
+            }
+        }
+
+        assert_eq!(sim.reg::<u32>(8), total);
+    }
+
+    #[test]
+    fn test_parallel_compilation() {
+        let source = include_str!("../../benches/samples/mandelbrot.s");
+        let run = |threads| {
+            let mut sim = simulator_from_str(source);
+            let parsed = parser::parse_str(source.to_owned(), DATA_SIZE).unwrap();
+            sim.code = executor::compile_all_in(&parsed.code, threads);
+            sim.run().unwrap();
+            (sim.registers, sim.floats, sim.instret)
+        };
+
+        let sequential = run(1);
+        for threads in [2, 3, 8, 1000] {
+            assert_eq!(run(threads), sequential, "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn test_ignored_compiler_directives() {
+        let code = r#"
+            .option norvc
+            .attribute arch, "rv32i2p0_m2p0"
+            .file "main.c"
+            .text
+            .globl main
+            .type main, @function
+        main:
+            .cfi_startproc
+            li s0, 42
+            .cfi_endproc
+            .size main, .-main
+            li s1, 7
+        "#;
+        let mut sim = simulator_from_str(code);
+        sim.run().unwrap();
+        assert_eq!(sim.reg::<u32>(8), 42);
+        assert_eq!(sim.reg::<u32>(9), 7);
+    }
+
+    #[test]
+    fn test_print_state() {
+        let mut sim = simulator_from_str("li s0, 0x1234\n li a7, 10\n ecall");
+        sim.run().unwrap();
+
+        let mut out = Vec::new();
+        sim.write_state(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("pc:"));
+        assert!(out.contains("0x00000008"));
+        assert!(out.contains(&sim.code_ctx[2].to_string()));
+        assert!(out.contains("00001234"));
+        assert!(out.contains("ucause"));
+    }
+
+    #[test]
+    fn test_pause_flag() {
+        let code = "
+            li t0, 10000
+        loop:
+            addi t0, t0, -1
+            bnez t0, loop
+            li s0, 1
+        ";
+        let paused = Arc::new(AtomicBool::new(true));
+        let mut sim = simulator_from_str(code).with_pause_flag(paused.clone());
+
+        let delay = time::Duration::from_millis(100);
+        let unpause = thread::spawn(move || {
+            thread::sleep(delay);
+            paused.store(false, Ordering::Relaxed);
+        });
+
+        let start = time::Instant::now();
+        sim.run().unwrap();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(sim.reg::<u32>(8), 1);
+        unpause.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_ips() {
+        let code = "
+            li t0, 100
+        loop:
+            addi t0, t0, -1
+            bnez t0, loop
+            li s0, 1
+        ";
+        let config = Config {
+            max_ips: Some(1000),
+            ..Config::default()
+        };
+        let mut sim = simulator_with_config(code, config);
+
+        // About 200 instructions at 1000 per second
+        let start = time::Instant::now();
+        sim.run().unwrap();
+        assert!(start.elapsed() >= time::Duration::from_millis(190));
+        assert_eq!(sim.reg::<u32>(8), 1);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let code = "
+            li t0, 10
+        store:
+            sw t0, 0(sp)
+            addi t0, t0, -1
+            bnez t0, store
+        back:
+            j back
+        ";
+        let config = Config {
+            watchdog: Some(1000),
+            watchdog_exit: true,
+            ..Config::default()
+        };
+        let mut sim = simulator_with_config(code, config);
+
+        assert_eq!(sim.run().unwrap(), 1);
+        assert!(sim.watchdog_fired);
+        assert_eq!(sim.pc, 4 * 4); // stopped at the `j back`
+        assert!(sim.instret <= 31 + 1000 + sim.check_interval);
+    }
+
+    #[test]
+    fn test_wait_for_input() {
+        let signal = InputSignal::default();
+        let mut sim =
+            simulator_from_str("li a7 111\n ecall\n li s0 1").with_input_signal(signal.clone());
+
+        let notifier = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            signal.notify();
+        });
+        let start = time::Instant::now();
+        sim.run().unwrap();
+        notifier.join().unwrap();
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+        assert_eq!(sim.registers[8], 1);
+
+        // A key that's already waiting doesn't block at all
+        let mut sim = simulator_from_str("li a7 111\n ecall\n li s0 1")
+            .with_input_signal(InputSignal::default());
+        sim.memory.mmio.lock()[KDMMIO_CONTROL] = 1;
+        sim.run().unwrap();
+        assert_eq!(sim.registers[8], 1);
+
+        // Without a display or an input script, nothing can type
+        let mut sim = simulator_from_str("li a7 111\n ecall\n li s0 1");
+        sim.run().unwrap();
+        assert_eq!(sim.registers[8], 1);
+    }
+
+    #[test]
+    fn test_stop_execution() {
+        let mut sim = simulator_from_str("li a7 110\n ecall\n li s0 1");
+        assert_eq!(sim.run().unwrap(), 0);
+        assert_eq!(sim.registers[8], 0);
+    }
+
+    #[test]
+    fn test_jr() {
+        // `jr` jumps to its register, without overwriting it with a return address. The `bnez`
+        // stops the program if it jumps back to the start instead.
+        let code = "
+            bnez s0, end
+            li s0, 1
+            la t2, target
+            jr t2
+            li s1, 1
+            target: li s2, 2
+            end:
+        ";
+        let mut sim = simulator_from_str(code);
+        sim.run().unwrap();
+        assert_eq!(sim.registers[7], 20);
+        assert_eq!(sim.registers[9], 0);
+        assert_eq!(sim.registers[18], 2);
+    }
+
+    #[test]
+    fn test_load_str() {
+        let code = "
+            li s0, 6
+            li s1, 7
+            mul s2, s0, s1
+        ";
+        let mut sim = Simulator::default();
+        sim.load_str(code, "test_load_str.s").unwrap();
+        assert_eq!(sim.run().unwrap(), 0);
+        assert_eq!(sim.reg::<u32>(18), 42);
+
+        let err = Simulator::default().load_str("addi t0, t0", "test_load_str_error.s");
+        let err = err.expect_err("the program should not parse").to_string();
+        assert!(err.contains("test_load_str_error.s"));
+        assert!(err.contains("addi t0, t0"));
+    }
+
+    #[test]
+    fn test_load_reuses_data_memory() {
+        let mut sim = Simulator::default();
+        let buffer = sim.memory.data.as_ptr();
+
+        let code = ".data\n .word 0x11223344\n .space 4\n .byte 0x55\n .text\n nop";
+        sim.load_str(code, "test_data_offsets.s").unwrap();
+        assert_eq!(sim.memory.data.len(), DATA_SIZE);
+        assert_eq!(
+            sim.memory.data.as_ptr(),
+            buffer,
+            "the data was copied to a new buffer"
+        );
+        assert_eq!(sim.memory.get_word(0), 0x11223344);
+        assert_eq!(sim.memory.get_word(4), 0);
+        assert_eq!(sim.memory.get_byte(8), 0x55);
+
+        // The previous program's data doesn't leak into the next one
+        sim.memory.set_word(0x1000, 42);
+        sim.load_str(".data\n .half 7", "test_data_offsets.s")
+            .unwrap();
+        assert_eq!(sim.memory.data.as_ptr(), buffer);
+        assert_eq!(sim.memory.get_word(0), 7);
+        assert_eq!(sim.memory.get_byte(8), 0);
+        assert_eq!(sim.memory.get_word(0x1000), 0);
+
+        // A failed load still leaves a usable memory
+        assert!(sim.load_str("addi t0", "test_data_offsets.s").is_err());
+        assert_eq!(sim.memory.data.len(), DATA_SIZE);
+    }
+
+    #[test]
+    fn test_run_errors() {
+        let mut sim = simulator_from_str("li t0 0x50000000\n lw t1 0(t0)\n li s0 1");
+        let err = sim.run().expect_err("the load should be out of bounds");
+        assert!(matches!(
+            err,
+            SimError::MemoryOutOfBounds {
+                address: 0x50000000,
+                ..
             }
+        ));
+        assert_eq!(sim.registers[8], 0, "execution should stop at the error");
+
+        let mut sim = simulator_from_str("li t0 0x50000000\n sh t1 0(t0)");
+        let err = sim.run().expect_err("the store should be out of bounds");
+        assert!(matches!(
+            err,
+            SimError::MemoryOutOfBounds {
+                access: Access::Store(2),
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("Out of bounds store of 2 bytes"));
+
+        let mut sim = simulator_from_str("li a7 12345\n ecall");
+        let err = sim.run().expect_err("the ecall doesn't exist");
+        assert!(matches!(
+            err,
+            SimError::UnimplementedEcall { number: 12345, .. }
+        ));
+
+        let mut sim = simulator_from_str("li a7 5\n ecall").with_input(io::Cursor::new(""));
+        let err = sim.run().expect_err("there's nothing to read");
+        assert!(matches!(err, SimError::InputEnded { .. }));
+
+        // Errors don't leak into the next run
+        let mut sim = simulator_from_str("li s0 1");
+        sim.error = Some(SimError::StackPointerOutOfBounds { sp: 0, max: 0 });
+        assert_eq!(sim.run().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_initial_sp_gp() {
+        let mut sim = simulator_from_str("nop");
+        sim.init().unwrap();
+        assert_eq!(sim.reg::<u32>(2), DATA_SIZE as u32 - 4);
+        assert_eq!(sim.reg::<u32>(3), 0x10008000);
+
+        sim.config.sp = Some(0x3000);
+        sim.config.gp = Some(0x1800);
+        sim.init().unwrap();
+        assert_eq!(sim.reg::<u32>(2), 0x3000);
+        assert_eq!(sim.reg::<u32>(3), 0x1800);
+    }
+
+    #[test]
+    fn test_clear_screen() {
+        use crate::renderer::{FRAME_0, FRAME_1};
+        let mut sim = simulator_from_str("li a0 0x00123456\n li a1 1\n li a7 148\n ecall");
+        sim.memory.mmio.lock()[FRAME_0] = 42;
+        sim.run().unwrap();
+
+        let mmio = sim.memory.mmio.lock();
+        let frame_1 = &mmio[FRAME_1..FRAME_1 + FRAME_SIZE];
+        if cfg!(feature = "unb") {
+            assert!(frame_1.iter().all(|&x| x == 0x56));
+        } else {
+            assert!(frame_1
+                .chunks_exact(4)
+                .all(|x| x == [0x56, 0x34, 0x12, 0x00]));
         }
 
-        EcallSignal::Nothing
+        // The other frame is untouched
+        assert_eq!(mmio[FRAME_0], 42);
+        assert!(mmio[FRAME_0 + 1..FRAME_0 + FRAME_SIZE]
+            .iter()
+            .all(|&x| x == 0));
     }
 }
@@ -0,0 +1,261 @@
+//!
+//! Snapshots of the simulator. [State] is a machine-readable view of the registers, written by
+//! `--dump-state`, and [SimState] is a full copy that can be restored later.
+//!
+
+use super::Simulator;
+use crate::parser::register_names::{FLOATVEC, REGVEC, STATUSVEC};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+/// Registers of the simulator at some point in time. Registers are keyed by their ABI names
+/// (`zero`, `ra`, ..., `ft0`, ...) so the output is easy to check from scripts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct State {
+    pub pc: u32,
+    pub registers: BTreeMap<String, u32>,
+    #[serde(serialize_with = "serialize_floats")]
+    #[serde(deserialize_with = "deserialize_floats")]
+    pub floats: BTreeMap<String, f32>,
+    pub status: BTreeMap<String, u32>,
+}
+
+impl State {
+    pub fn from_simulator(sim: &Simulator) -> Self {
+        let registers = REGVEC
+            .iter()
+            .zip(sim.registers)
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+
+        let floats = FLOATVEC
+            .iter()
+            .zip(sim.floats)
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+
+        let status = STATUSVEC
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.to_string(), sim.get_status(index as u8)))
+            .collect();
+
+        Self {
+            pc: sim.pc as u32,
+            registers,
+            floats,
+            status,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn write_to_file(&self, path: &str) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+/// A float register in the JSON. NaNs and infinities have no JSON number, so they're written as
+/// their bit pattern in a hex string, like `"0x7fc00000"`
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonFloat {
+    Number(f32),
+    Bits(String),
+}
+
+impl From<f32> for JsonFloat {
+    fn from(x: f32) -> Self {
+        if x.is_finite() {
+            JsonFloat::Number(x)
+        } else {
+            JsonFloat::Bits(format!("{:#010x}", x.to_bits()))
+        }
+    }
+}
+
+fn serialize_floats<S: Serializer>(
+    floats: &BTreeMap<String, f32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        floats
+            .iter()
+            .map(|(name, &value)| (name, JsonFloat::from(value))),
+    )
+}
+
+fn deserialize_floats<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, f32>, D::Error> {
+    BTreeMap::<String, JsonFloat>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| match value {
+            JsonFloat::Number(x) => Ok((name, x)),
+            JsonFloat::Bits(bits) => bits
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .map(|bits| (name, f32::from_bits(bits)))
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid float: {}", bits))),
+        })
+        .collect()
+}
+
+/// Everything needed to bring the simulator back to some point of the execution, like a debugger
+/// going back in time. Taken by [Simulator::snapshot] and applied by [Simulator::restore].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimState {
+    pc: usize,
+    instret: u64,
+    registers: [u32; 32],
+    floats: [f32; 32],
+    status: Vec<u32>,
+    data: Vec<u8>,
+    dynamic: Vec<u8>,
+    /// Only copied by [Simulator::snapshot_with_mmio], since the video memory is large
+    mmio: Option<Vec<u8>>,
+}
+
+impl Simulator {
+    /// Copies the registers and the data and heap memory. The MMIO is left out, see
+    /// [snapshot_with_mmio](Self::snapshot_with_mmio).
+    pub fn snapshot(&self) -> SimState {
+        SimState {
+            pc: self.pc,
+            instret: self.instret,
+            registers: self.registers,
+            floats: self.floats,
+            status: self.status.clone(),
+            data: self.memory.data.clone(),
+            dynamic: self.memory.dynamic.clone(),
+            mmio: None,
+        }
+    }
+
+    /// Like [snapshot](Self::snapshot), but also copies the MMIO, which includes the video
+    /// memory and the keyboard buffer
+    pub fn snapshot_with_mmio(&self) -> SimState {
+        SimState {
+            mmio: Some(self.memory.mmio.lock().clone()),
+            ..self.snapshot()
+        }
+    }
+
+    /// Brings the simulator back to the point where `state` was taken. The MMIO is written in
+    /// place, so the bitmap display keeps sharing it.
+    pub fn restore(&mut self, state: SimState) {
+        self.pc = state.pc;
+        self.instret = state.instret;
+        self.registers = state.registers;
+        self.floats = state.floats;
+        self.status = state.status;
+        self.memory.data = state.data;
+        self.memory.dynamic = state.dynamic;
+        self.memory.out_of_bounds_access = None;
+        if let Some(mmio) = state.mmio {
+            self.memory.mmio.lock().copy_from_slice(&mmio);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::tests::simulator_from_str;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut sim = simulator_from_str(
+            r#"
+            li t0 42
+            li a0 -1
+            li t1 3
+            fcvt.s.w fa0 t1
+            "#,
+        );
+        sim.run().unwrap();
+
+        let state = State::from_simulator(&sim);
+        let json = state.to_json().unwrap();
+        let parsed: State = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, state);
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        for key in ["pc", "registers", "floats", "status"] {
+            assert!(value.get(key).is_some(), "missing key {}", key);
+        }
+        assert_eq!(value["registers"]["t0"], 42);
+        assert_eq!(value["floats"]["fa0"], 3.0);
+        assert!(value["status"].get("misa").is_some());
+        assert!(value["status"].get("instret").is_some());
+        assert_eq!(value["registers"].as_object().unwrap().len(), 32);
+        assert_eq!(value["floats"].as_object().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_state_non_finite_floats() {
+        let mut sim = simulator_from_str("");
+        sim.floats[0] = f32::NAN;
+        sim.floats[1] = f32::INFINITY;
+        sim.floats[2] = f32::NEG_INFINITY;
+        sim.floats[3] = -1.5;
+
+        let json = State::from_simulator(&sim).to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["floats"]["ft0"], "0x7fc00000");
+        assert_eq!(value["floats"]["ft1"], "0x7f800000");
+        assert_eq!(value["floats"]["ft2"], "0xff800000");
+        assert_eq!(value["floats"]["ft3"], -1.5);
+
+        let parsed: State = serde_json::from_str(&json).unwrap();
+        for (i, name) in FLOATVEC.iter().enumerate() {
+            assert_eq!(parsed.floats[*name].to_bits(), sim.floats[i].to_bits());
+        }
+        assert!(serde_json::from_str::<State>(&json.replace("0x7fc00000", "nan")).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut sim = simulator_from_str(
+            r#"
+            .data
+            x: .word 7
+            .text
+            li t0 42
+            la t1 x
+            li t2 9
+            sw t2 0(t1)
+            li a0 16
+            li a7 9
+            ecall
+            "#,
+        );
+        sim.run().unwrap();
+        let snapshot = sim.snapshot_with_mmio();
+        let mmio = sim.memory.mmio.clone();
+        let x = sim.registers[6] as usize;
+
+        sim.registers[5] = 0;
+        sim.floats[1] = 1.5;
+        sim.pc += 4;
+        sim.memory.set_word(x, 123);
+        sim.memory.dynamic.clear();
+        sim.memory.mmio.lock()[0] = 0xff;
+
+        sim.restore(snapshot.clone());
+        assert_eq!(sim.registers[5], 42);
+        assert_eq!(sim.floats[1], 0.0);
+        assert_eq!(sim.memory.get_word(x), 9);
+        assert_eq!(sim.memory.dynamic.len(), 16);
+        assert_eq!(sim.memory.mmio.lock()[0], 0);
+        assert!(Arc::ptr_eq(&mmio, &sim.memory.mmio));
+        assert_eq!(sim.snapshot_with_mmio(), snapshot);
+    }
+}
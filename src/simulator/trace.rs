@@ -0,0 +1,123 @@
+//!
+//! Per-instruction trace written by `--trace`. Each line has the pc, the source of the instruction
+//! and the registers it wrote, which makes it easy to diff an execution against RARS's.
+//!
+
+use crate::parser::register_names::{FLOATVEC, REGVEC};
+use crate::parser::token::Context;
+use std::io;
+
+/// Registers before an instruction was executed
+type Registers = ([u32; 32], [f32; 32]);
+
+pub struct Tracer {
+    out: Box<dyn io::Write>,
+    /// Source of each instruction, found when the program is loaded
+    sources: Vec<String>,
+    /// The instruction being executed and the registers before it. Logged when we know what it
+    /// wrote, before the next instruction starts.
+    pending: Option<(usize, Registers)>,
+}
+
+/// The instruction in the line of `ctx`, without labels or comments
+fn instruction_source(ctx: &Context) -> String {
+    let Some(line) = ctx.source_line() else {
+        return String::new();
+    };
+    let code = line.split('#').next().unwrap_or_default();
+    let code = code.rsplit(':').next().unwrap_or_default();
+    code.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl Tracer {
+    pub fn new(out: impl io::Write + 'static) -> Self {
+        Self {
+            out: Box::new(out),
+            sources: Vec::new(),
+            pending: None,
+        }
+    }
+
+    pub fn load(&mut self, code_ctx: &[Context]) {
+        self.sources = code_ctx.iter().map(instruction_source).collect();
+        self.pending = None;
+    }
+
+    /// Called before the instruction at `pc` executes. Instructions without a source, like the
+    /// exit sequence appended by the parser, aren't traced.
+    pub fn step(&mut self, pc: usize, registers: &[u32; 32], floats: &[f32; 32]) {
+        self.finish(registers, floats);
+        if pc / 4 < self.sources.len() {
+            self.pending = Some((pc, (*registers, *floats)));
+        }
+    }
+
+    /// Logs the pending instruction, given the registers after it executed
+    pub fn finish(&mut self, registers: &[u32; 32], floats: &[f32; 32]) {
+        let Some((pc, (old_registers, old_floats))) = self.pending.take() else {
+            return;
+        };
+
+        let mut line = format!("{:#010x} {}", pc, self.sources[pc / 4]);
+        for i in 1..32 {
+            if registers[i] != old_registers[i] {
+                line += &format!(" {}={:#010x}", REGVEC[i], registers[i]);
+            }
+        }
+        for i in 0..32 {
+            if floats[i].to_bits() != old_floats[i].to_bits() {
+                line += &format!(" {}={}", FLOATVEC[i], floats[i]);
+            }
+        }
+
+        let _ = writeln!(self.out, "{}", line);
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Lets the test read what the tracer wrote
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let code = "
+            li t0 5
+            loop: addi t1, t0, -2 # comment
+            fcvt.s.w ft0 t1
+        ";
+        let buffer = SharedBuffer::default();
+        let mut sim = Simulator::default().with_trace(buffer.clone());
+        sim.load_str(code, "test_trace.s").unwrap();
+        sim.run().unwrap();
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace.lines().collect::<Vec<_>>(),
+            [
+                "0x00000000 li t0 5 t0=0x00000005",
+                "0x00000004 addi t1, t0, -2 t1=0x00000003",
+                "0x00000008 fcvt.s.w ft0 t1 ft0=3",
+            ]
+        );
+    }
+}
@@ -16,13 +16,16 @@
 /// 8| _rs1_ is a signaling NaN.
 /// 9| _rs1_ is a quiet NaN.
 ///
-/// The last 2 bits may or may not be wrong in some (hopefully older) architectures
-/// because of encoding shenanigans I don't know how to deal with.
+/// Quiet and signaling NaNs are told apart by the most significant bit of the significand (bit 22),
+/// the sign of a NaN doesn't matter.
 /// See [https://en.wikipedia.org/wiki/NaN#Encoding](https://en.wikipedia.org/wiki/NaN#Encoding)
 pub fn class_mask(f: f32) -> u32 {
     use std::num::FpCategory::*;
+    const QUIET_BIT: u32 = 1 << 22;
     let neg = f.is_sign_negative();
     let bit = match f.classify() {
+        Nan if f.to_bits() & QUIET_BIT != 0 => 9,
+        Nan => 8,
         Infinite if neg => 0,
         Normal if neg => 1,
         Subnormal if neg => 2,
@@ -31,9 +34,123 @@ pub fn class_mask(f: f32) -> u32 {
         Subnormal => 5,
         Normal => 6,
         Infinite => 7,
-        Nan if neg => 9,
-        Nan => 8,
     };
 
     1_u32 << bit
 }
+
+/// The canonical NaN, returned by float operations whose result is NaN
+pub const CANONICAL_NAN: f32 = f32::from_bits(0x7fc0_0000);
+
+/// `fmin.s`, according to the RISC-V spec. Differently from [`f32::min`], if both operands are NaN
+/// the result is the canonical NaN, and `-0.0` is considered to be less than `+0.0`.
+pub fn fmin(a: f32, b: f32) -> f32 {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => f32::from_bits(a.to_bits() | b.to_bits()), // -0.0 wins
+        _ => a.min(b),
+    }
+}
+
+/// `fmax.s`, according to the RISC-V spec. See [`fmin`].
+pub fn fmax(a: f32, b: f32) -> f32 {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => f32::from_bits(a.to_bits() & b.to_bits()), // +0.0 wins
+        _ => a.max(b),
+    }
+}
+
+/// Formats a float in hexadecimal, like C's `%a`. `1.5` becomes `0x1.8p+0`
+pub fn hex_float(f: f32) -> String {
+    if f.is_nan() {
+        return "nan".to_owned();
+    }
+
+    let sign = if f.is_sign_negative() { "-" } else { "" };
+    if f.is_infinite() {
+        return format!("{sign}inf");
+    }
+
+    let bits = f.to_bits();
+    let biased_exponent = (bits >> 23) & 0xff;
+    // 23 bits of mantissa, shifted so they fill 6 hex digits
+    let mantissa = (bits & 0x7f_ffff) << 1;
+    let (leading, exponent) = match biased_exponent {
+        0 if mantissa == 0 => (0, 0),
+        0 => (0, -126),
+        e => (1, e as i32 - 127),
+    };
+
+    let digits = format!("{:06x}", mantissa);
+    let digits = digits.trim_end_matches('0');
+    if digits.is_empty() {
+        format!("{sign}0x{leading}p{exponent:+}")
+    } else {
+        format!("{sign}0x{leading}.{digits}p{exponent:+}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_mask() {
+        assert_eq!(class_mask(f32::NEG_INFINITY), 1 << 0);
+        assert_eq!(class_mask(-1.0), 1 << 1);
+        assert_eq!(class_mask(-0.0), 1 << 3);
+        assert_eq!(class_mask(0.0), 1 << 4);
+        assert_eq!(class_mask(f32::MIN_POSITIVE / 2.0), 1 << 5);
+        assert_eq!(class_mask(f32::INFINITY), 1 << 7);
+    }
+
+    #[test]
+    fn test_class_mask_nan() {
+        let signaling = f32::from_bits(0x7f80_0001);
+        let quiet = f32::from_bits(0x7fc0_0000);
+        assert_eq!(class_mask(signaling), 1 << 8);
+        assert_eq!(class_mask(quiet), 1 << 9);
+
+        // The sign bit is irrelevant
+        assert_eq!(class_mask(f32::from_bits(0xff80_0001)), 1 << 8);
+        assert_eq!(class_mask(f32::from_bits(0xffc0_0000)), 1 << 9);
+    }
+
+    #[test]
+    fn test_fmin_fmax() {
+        let nan = f32::NAN;
+        assert_eq!(fmin(nan, 3.0), 3.0);
+        assert_eq!(fmin(3.0, nan), 3.0);
+        assert_eq!(fmax(nan, 3.0), 3.0);
+        assert_eq!(fmax(3.0, nan), 3.0);
+        assert_eq!(fmin(nan, nan).to_bits(), CANONICAL_NAN.to_bits());
+        assert_eq!(fmax(nan, nan).to_bits(), CANONICAL_NAN.to_bits());
+
+        assert_eq!(fmin(-0.0, 0.0).to_bits(), (-0.0f32).to_bits());
+        assert_eq!(fmin(0.0, -0.0).to_bits(), (-0.0f32).to_bits());
+        assert_eq!(fmax(-0.0, 0.0).to_bits(), 0.0f32.to_bits());
+        assert_eq!(fmax(0.0, -0.0).to_bits(), 0.0f32.to_bits());
+
+        assert_eq!(fmin(-1.0, 2.0), -1.0);
+        assert_eq!(fmax(-1.0, 2.0), 2.0);
+    }
+
+    #[test]
+    fn test_hex_float() {
+        assert_eq!(hex_float(1.5), "0x1.8p+0");
+        assert_eq!(hex_float(1.0), "0x1p+0");
+        assert_eq!(hex_float(-0.75), "-0x1.8p-1");
+        assert_eq!(hex_float(10.0), "0x1.4p+3");
+        assert_eq!(hex_float(0.0), "0x0p+0");
+        assert_eq!(hex_float(-0.0), "-0x0p+0");
+        assert_eq!(hex_float(f32::MIN_POSITIVE / 2.0), "0x0.8p-126");
+        assert_eq!(hex_float(f32::MAX), "0x1.fffffep+127");
+        assert_eq!(hex_float(f32::NEG_INFINITY), "-inf");
+        assert_eq!(hex_float(f32::NAN), "nan");
+    }
+}
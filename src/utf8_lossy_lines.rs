@@ -1,8 +1,12 @@
 use std::io::{self, BufRead};
 
+/// Iterator over the lines of a reader, replacing invalid UTF-8 instead of failing. Lines may end
+/// in `\n`, `\r\n` or a lone `\r`, just like the lexer counts them.
 pub struct Utf8LossyLines<R: BufRead> {
     reader: R,
     buf: Vec<u8>,
+    /// The last line ended in `\r`, so a `\n` right after it belongs to the same line break
+    skip_lf: bool,
 }
 
 impl<R: BufRead> Iterator for Utf8LossyLines<R> {
@@ -10,12 +14,36 @@ impl<R: BufRead> Iterator for Utf8LossyLines<R> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.buf.clear();
-        match self.reader.read_until(b'\n', &mut self.buf) {
-            Ok(0) => None,
-            Ok(_) => Some(Ok(
-                String::from_utf8_lossy(&self.buf[..self.buf.len() - 1]).into_owned()
-            )),
-            Err(e) => Some(Err(e)),
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if std::mem::take(&mut self.skip_lf) && available.first() == Some(&b'\n') {
+                self.reader.consume(1);
+                continue;
+            }
+
+            if available.is_empty() {
+                return (!self.buf.is_empty())
+                    .then(|| Ok(String::from_utf8_lossy(&self.buf).into_owned()));
+            }
+
+            match available.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(i) => {
+                    self.skip_lf = available[i] == b'\r';
+                    self.buf.extend_from_slice(&available[..i]);
+                    self.reader.consume(i + 1);
+                    return Some(Ok(String::from_utf8_lossy(&self.buf).into_owned()));
+                }
+                None => {
+                    let len = available.len();
+                    self.buf.extend_from_slice(available);
+                    self.reader.consume(len);
+                }
+            }
         }
     }
 }
@@ -29,6 +57,34 @@ impl<R: BufRead> Utf8LossyLinesExt for R {
         Utf8LossyLines {
             reader: self,
             buf: Vec::new(),
+            skip_lf: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(input: &[u8]) -> Vec<String> {
+        input.utf8_lossy_lines().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_line_endings() {
+        assert_eq!(lines(b"a\nb\n"), ["a", "b"]);
+        assert_eq!(lines(b"a\r\nb\r\n"), ["a", "b"]);
+        assert_eq!(lines(b"a\rb\rc"), ["a", "b", "c"]);
+        assert_eq!(lines(b"a\r\rb\n\r\nc\r"), ["a", "", "b", "", "c"]);
+        assert_eq!(lines(b"\xffa"), ["\u{fffd}a"]);
+        assert!(lines(b"").is_empty());
+    }
+
+    #[test]
+    fn test_cr_split_across_buffers() {
+        // A BufReader with capacity 2 sees "a\r" and "\nb" in separate buffers
+        let reader = io::BufReader::with_capacity(2, &b"a\r\nb"[..]);
+        let lines: Vec<_> = reader.utf8_lossy_lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["a", "b"]);
+    }
+}
@@ -0,0 +1,144 @@
+use std::process::Command;
+
+/// Runs the code with FPGRARS and returns what it printed to stdout
+fn run_stdout(name: &str, code: &str) -> String {
+    let path = std::env::temp_dir().join(format!("fpgrars_test_{}_{name}.s", std::process::id()));
+    std::fs::write(&path, code).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fpgrars"))
+        .arg("--no-video")
+        .arg(&path)
+        .output()
+        .expect("Failed to run FPGRARS!");
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(
+        output.status.success(),
+        "FPGRARS exited with {}",
+        output.status
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_print_float_formats() {
+    let code = r#"
+        li t0, 0x3fc00000 # 1.5
+        fmv.s.x fa0, t0
+        li a7, 37
+        ecall
+
+        li a0, ' '
+        li a7, 11
+        ecall
+
+        li a1, 2
+        li a7, 38
+        ecall
+    "#;
+    assert_eq!(run_stdout("print_float_formats", code), "0x1.8p+0 1.50e0");
+}
+
+#[test]
+fn test_print_binary() {
+    let code = r#"
+        li s0, 5
+        li s1, 0xF0F0F0F0
+        li s2, ' '
+
+        mv a0, s0
+        li a7, 135
+        ecall
+        mv a0, s2
+        li a7, 11
+        ecall
+        mv a0, s0
+        li a7, 35
+        ecall
+        mv a0, s2
+        li a7, 11
+        ecall
+
+        mv a0, s1
+        li a7, 135
+        ecall
+        mv a0, s2
+        li a7, 11
+        ecall
+        mv a0, s1
+        li a7, 35
+        ecall
+        mv a0, s2
+        li a7, 11
+        ecall
+
+        li a0, 0
+        li a7, 135
+        ecall
+    "#;
+    assert_eq!(
+        run_stdout("print_binary", code),
+        "101 00000000000000000000000000000101 \
+        11110000111100001111000011110000 11110000111100001111000011110000 0"
+    );
+}
+
+#[test]
+fn test_memcpy_memset() {
+    let code = r#"
+        .data
+        src: .string "hello"
+        dst: .space 16
+        .text
+        la a0, dst
+        li a1, '-'
+        li a2, 8
+        li a7, 1027
+        ecall
+
+        la a0, dst
+        la a1, src
+        li a2, 5
+        li a7, 1026
+        ecall
+
+        la a0, dst
+        li a7, 4
+        ecall
+    "#;
+    assert_eq!(run_stdout("memcpy_memset", code), "hello---");
+}
+
+#[test]
+fn test_stdout_file() {
+    let dir = std::env::temp_dir();
+    let (path, stdout_path) = (
+        dir.join(format!("fpgrars_test_{}_stdout_file.s", std::process::id())),
+        dir.join(format!(
+            "fpgrars_test_{}_stdout_file.txt",
+            std::process::id()
+        )),
+    );
+    let code = "
+        li a0, 42
+        li a7, 1
+        ecall
+    ";
+    std::fs::write(&path, code).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fpgrars"))
+        .arg("--no-video")
+        .arg("--stdout")
+        .arg(&stdout_path)
+        .arg(&path)
+        .output()
+        .expect("Failed to run FPGRARS!");
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+
+    let printed = std::fs::read_to_string(&stdout_path).unwrap();
+    std::fs::remove_file(&stdout_path).unwrap();
+    assert_eq!(printed, "42");
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Finished in"));
+}